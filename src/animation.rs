@@ -0,0 +1,75 @@
+use crate::vector3::*;
+
+/// Things that can be blended between keyframes.
+pub trait Lerp: Copy {
+    fn lerp(a: Self, b: Self, t: f64) -> Self;
+}
+
+impl Lerp for f64 {
+    fn lerp(a: f64, b: f64, t: f64) -> f64 {
+        a + t * (b - a)
+    }
+}
+
+impl Lerp for Vector3 {
+    fn lerp(a: Vector3, b: Vector3, t: f64) -> Vector3 {
+        (1.0 - t) * a + t * b
+    }
+}
+
+impl<A: Lerp, B: Lerp> Lerp for (A, B) {
+    fn lerp(a: (A, B), b: (A, B), t: f64) -> (A, B) {
+        (A::lerp(a.0, b.0, t), B::lerp(a.1, b.1, t))
+    }
+}
+
+/// A value keyframed over time, in frames. It's linearly interpolated between keys, and holds
+/// still before the first one and after the last.
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    /// (frame, value), sorted by frame
+    keys: Vec<(f64, T)>,
+}
+
+impl<T: Lerp> Track<T> {
+    /// Keys as (frame, value), in any order. There has to be at least one.
+    pub fn new(keys: Vec<(f64, T)>) -> Track<T> {
+        assert!(!keys.is_empty(), "a track needs a key");
+        let mut keys = keys;
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Track { keys }
+    }
+
+    pub fn at(&self, frame: f64) -> T {
+        let keys = &self.keys;
+        let i = keys.partition_point(|(f, _)| *f <= frame);
+        if i == 0 {
+            return keys[0].1;
+        }
+        if i == keys.len() {
+            return keys[i - 1].1;
+        }
+        let ((f0, a), (f1, b)) = (keys[i - 1], keys[i]);
+        T::lerp(a, b, (frame - f0) / (f1 - f0))
+    }
+}
+
+/// The keyframes of one object. Whatever has no track stays as the scene has it.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectTracks {
+    /// Where the object is, and how much it's scaled up. For a sphere, that's its center and
+    /// radius.
+    pub placement: Option<Track<(Vector3, f64)>>,
+    /// In linear sRGB, like all the colors scenes are made with
+    pub base_col: Option<Track<Vector3>>,
+    pub roughness: Option<Track<f64>>,
+}
+
+/// How a scene changes from frame to frame.
+#[derive(Debug, Clone, Default)]
+pub struct Animation {
+    /// Where the camera is, and the direction it looks in
+    pub camera: Option<Track<(Vector3, Vector3)>>,
+    /// Tracks of the animated objects, by object index
+    pub objects: Vec<(usize, ObjectTracks)>,
+}
@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::film::Film;
+use crate::vector3::*;
+
+/// Arbitrary output variables: render passes besides the beauty image, for compositing.
+/// They're written as float images, without any exposure or tonemapping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    /// Distance along the camera ray to the first hit
+    Depth,
+    /// World space normal at the first hit, facing the camera
+    Normal,
+    Albedo,
+    MaterialId,
+    ObjectId,
+    /// Light that reached the camera after one bounce
+    Direct,
+    /// Light that took two or more bounces
+    Indirect,
+    /// Lights, emissive surfaces and background seen by the camera
+    Emission,
+    /// Samples taken in each pixel
+    Samples,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 9] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::MaterialId,
+        Aov::ObjectId,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Emission,
+        Aov::Samples,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::MaterialId => "material",
+            Aov::ObjectId => "object",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+            Aov::Samples => "samples",
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Aov> {
+        Aov::ALL.into_iter().find(|aov| aov.name() == name)
+    }
+
+    /// Single channel passes get written as grayscale.
+    fn is_scalar(&self) -> bool {
+        matches!(
+            self,
+            Aov::Depth | Aov::MaterialId | Aov::ObjectId | Aov::Samples
+        )
+    }
+
+    /// Value of the pass at pixel (i, j). Scalars are in x. IDs are -1 where there is no object.
+    fn value(&self, film: &Film, i: usize, j: usize) -> Vector3 {
+        let aux = film.features(i, j);
+        let f = aux.features;
+        let id = |id: Option<usize>| id.map_or(-1.0, |id| id as f64);
+        let scalar = |v: f64| Vector3::new(v, v, v);
+        match self {
+            Aov::Depth => scalar(f.depth),
+            Aov::Normal => f.normal,
+            Aov::Albedo => f.albedo,
+            Aov::MaterialId => scalar(id(f.material)),
+            Aov::ObjectId => scalar(id(f.object)),
+            Aov::Direct => film.light(i, j).direct,
+            Aov::Indirect => film.light(i, j).indirect,
+            Aov::Emission => film.light(i, j).emission,
+            Aov::Samples => scalar(aux.samples),
+        }
+    }
+
+    /// Writes the pass as a .pfm, the floating point relative of .ppm.
+    pub fn write(&self, film: &Film, path: &str) -> std::io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let magic = if self.is_scalar() { "Pf" } else { "PF" };
+        // The negative scale marks the data as little endian
+        write!(file, "{}\n{} {}\n-1.0\n", magic, film.width, film.height)?;
+        // Rows go from the bottom up
+        for j in (0..film.height).rev() {
+            for i in 0..film.width {
+                let v = self.value(film, i, j);
+                let channels = if self.is_scalar() {
+                    &[v.x][..]
+                } else {
+                    &[v.x, v.y, v.z][..]
+                };
+                for c in channels {
+                    file.write_all(&(*c as f32).to_le_bytes())?;
+                }
+            }
+        }
+        file.flush()
+    }
+}
+
+/// Writes the number of samples each pixel got as a false color image, going from dark blue
+/// (none) through green and yellow to red (max_spp).
+pub fn write_heatmap(film: &Film, max_spp: usize, path: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", film.width, film.height)?;
+    for j in 0..film.height {
+        for i in 0..film.width {
+            let col = heat(film.features(i, j).samples / max_spp as f64).as_col();
+            file.write_all(&[col.r, col.g, col.b])?;
+        }
+    }
+    file.flush()
+}
+
+/// False color for t from 0 (dark blue) through green and yellow to 1 (red), sRGB encoded.
+pub fn heat(t: f64) -> Vector3 {
+    let stops = [
+        Vector3::new(0.05, 0.0, 0.3),
+        Vector3::new(0.0, 0.4, 1.0),
+        Vector3::new(0.0, 0.9, 0.3),
+        Vector3::new(1.0, 0.9, 0.0),
+        Vector3::new(1.0, 0.0, 0.0),
+    ];
+    let pos = clamp(t, 0.0, 1.0) * (stops.len() - 1) as f64;
+    let k = (pos as usize).min(stops.len() - 2);
+    let f = pos - k as f64;
+    (1.0 - f) * stops[k] + f * stops[k + 1]
+}
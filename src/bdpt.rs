@@ -0,0 +1,617 @@
+use crate::denoise::Features;
+use crate::film::{Film, PathRadiance};
+use crate::light::Light;
+use crate::sampler::Sampler;
+use crate::scene::{Scene, Surface};
+use crate::vector3::*;
+
+/// Subpaths are cut off after this many bounces, whatever russian roulette says.
+const MAX_BOUNCES: usize = 32;
+
+/// The image being rendered, and the time the paths are at.
+#[derive(Clone, Copy)]
+struct Frame {
+    width: usize,
+    height: usize,
+    time: f64,
+}
+
+/// What a subpath vertex is on.
+#[derive(Clone, Copy)]
+enum Kind<'a> {
+    Camera,
+    /// A point on the light with the given index: where light subpaths start, and where camera
+    /// subpaths can end up
+    Light(usize),
+    /// An object, and its index in the scene, reached by a ray with direction dir. Emissive
+    /// objects have their lights.
+    Surface {
+        object: &'a Renderable,
+        index: Option<usize>,
+        dir: Vector3,
+        light: Option<usize>,
+    },
+}
+
+/// A vertex of a camera or light subpath.
+#[derive(Clone, Copy)]
+struct Vertex<'a> {
+    kind: Kind<'a>,
+    pos: Vector3,
+    /// Geometric normal. Zero for the camera and point lights, which aren't on a surface.
+    normal: Vector3,
+    /// Throughput of the subpath from its start up to here
+    beta: Vector3,
+    /// Area pdf of the subpath getting here from the vertex before, and of it getting to the
+    /// vertex before from here, had it come the other way
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    /// Scattered into a discrete direction, which no connection can hit
+    delta: bool,
+}
+
+impl<'a> Vertex<'a> {
+    fn new(kind: Kind<'a>, pos: Vector3, normal: Vector3, beta: Vector3) -> Vertex<'a> {
+        Vertex {
+            kind,
+            pos,
+            normal,
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn is_on_surface(&self) -> bool {
+        self.normal != Vector3::new(0.0, 0.0, 0.0)
+    }
+
+    /// Can a path be made by connecting this vertex to another one?
+    fn is_connectible(&self, scene: &Scene) -> bool {
+        match self.kind {
+            Kind::Camera => true,
+            Kind::Light(i) => !scene.lights[i].is_infinite(),
+            Kind::Surface { object, .. } => !object.material.is_specular(),
+        }
+    }
+
+    /// BSDF times cosine, for light going between this vertex and the point p. Only surfaces
+    /// scatter.
+    fn f(&self, p: Vector3) -> Vector3 {
+        match self.kind {
+            Kind::Surface { object, dir, .. } => {
+                let wi = (p - self.pos).normalize();
+                object.material.eval(dir, self.normal, wi)
+            }
+            _ => Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Turns a solid angle pdf of going from here towards next into an area pdf at next.
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.pos - self.pos;
+        let dist2 = w * w;
+        if dist2 == 0.0 {
+            return 0.0;
+        }
+        let mut pdf = pdf / dist2;
+        if next.is_on_surface() {
+            pdf *= (next.normal * w).abs() / dist2.sqrt();
+        }
+        pdf
+    }
+
+    /// Area pdf of the vertex after this one being next, when the one before was prev.
+    fn pdf(&self, scene: &Scene, prev: Option<&Vertex>, next: &Vertex, frame: Frame) -> f64 {
+        let wn = (next.pos - self.pos).normalize();
+        let pdf = match self.kind {
+            Kind::Light(_) => return self.pdf_light(scene, next),
+            Kind::Camera => {
+                let Frame {
+                    width,
+                    height,
+                    time,
+                } = frame;
+                scene.camera.importance(wn, width, height, time).1
+            }
+            Kind::Surface { object, .. } => match prev {
+                Some(prev) => {
+                    let dir = (self.pos - prev.pos).normalize();
+                    object.material.pdf(dir, self.normal, wn)
+                }
+                None => 0.0,
+            },
+        };
+        self.convert_density(pdf, next)
+    }
+
+    /// Area pdf at next of this vertex, on a light, emitting light towards it.
+    fn pdf_light(&self, scene: &Scene, next: &Vertex) -> f64 {
+        let light = match self.light() {
+            Some(i) => &scene.lights[i],
+            None => return 0.0,
+        };
+        let wn = (next.pos - self.pos).normalize();
+        let (_, pdf_dir) = light.pdf_le(self.normal, wn);
+        self.convert_density(pdf_dir, next)
+    }
+
+    /// Area pdf of a light path starting at this vertex, on a light, with the light picked in
+    /// proportion to its power. next is where the light path goes.
+    fn pdf_light_origin(&self, scene: &Scene, next: &Vertex) -> f64 {
+        let i = match self.light() {
+            Some(i) => i,
+            None => return 0.0,
+        };
+        let wn = (next.pos - self.pos).normalize();
+        let (pdf_pos, _) = scene.lights[i].pdf_le(self.normal, wn);
+        pdf_pos * scene.emission_sampler.pmf(i)
+    }
+
+    /// The light the vertex is on, if any.
+    fn light(&self) -> Option<usize> {
+        match self.kind {
+            Kind::Light(i) => Some(i),
+            Kind::Surface { light, .. } => light,
+            Kind::Camera => None,
+        }
+    }
+
+    /// Radiance the vertex emits towards prev.
+    fn le(&self, scene: &Scene, prev: &Vertex) -> Vector3 {
+        match self.light() {
+            Some(i) => scene.lights[i].emitted((self.pos - prev.pos).normalize(), self.normal),
+            None => Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// A camera subpath that left the scene, to pick up the background and distant lights.
+struct Escape {
+    dir: Vector3,
+    beta: Vector3,
+    /// Solid angle pdf of the direction. None for camera rays and specular bounces.
+    pdf: Option<f64>,
+}
+
+/// Bidirectional path tracing: traces a subpath from the camera, along (origin, dir), and one
+/// from a light, and connects every vertex of one to every vertex of the other. Each way of
+/// making a path is weighted against all the others it could have been made with (MIS, with
+/// the power heuristic). Connections straight to the camera land anywhere in the image, and go
+/// to the film as splats. Returns the radiance for the pixel the camera ray went through.
+///
+/// Light paths start at lights picked in proportion to their power. Lights at infinity (the sun,
+/// the background) are only reached from the camera subpath, and weighted against light
+/// sampling the way the path tracer does it. Media aren't handled.
+#[allow(clippy::too_many_arguments)]
+pub fn sample_scene(
+    origin: Vector3,
+    dir: Vector3,
+    time: f64,
+    scene: &Scene,
+    min_depth: usize,
+    sampler: &mut dyn Sampler,
+    features: &mut Features,
+    film: &mut Film,
+) -> PathRadiance {
+    let mut col = PathRadiance::default();
+    let zero = Vector3::new(0.0, 0.0, 0.0);
+    let one = Vector3::new(1.0, 1.0, 1.0);
+    let (width, height) = (film.width, film.height);
+    let frame = Frame {
+        width,
+        height,
+        time,
+    };
+
+    let mut camera_path = vec![Vertex::new(Kind::Camera, origin, zero, one)];
+    let pdf_dir = scene.camera.importance(dir, width, height, time).1;
+    let walk = (origin, dir, one, pdf_dir);
+    let escape = random_walk(
+        scene,
+        walk,
+        time,
+        min_depth,
+        sampler,
+        &mut camera_path,
+        true,
+    );
+    if let Some(first) = camera_path.get(1) {
+        *features = first_hit(scene, first, origin);
+    }
+
+    let mut light_path = vec![];
+    let u_pick = sampler.get_1d();
+    let (u_pos, u_dir) = (sampler.get_2d(), sampler.get_2d());
+    if let Some((index, pmf)) = scene.emission_sampler.sample(u_pick) {
+        let light = &scene.lights[index];
+        if let Some(es) = light.sample_le(u_pos, u_dir) {
+            let pdf_pos = es.pdf_pos * pmf;
+            let mut start = Vertex::new(Kind::Light(index), es.pos, es.normal, es.le);
+            start.pdf_fwd = pdf_pos;
+            light_path.push(start);
+            let cos = if es.normal == zero {
+                1.0
+            } else {
+                (es.normal * es.dir).abs()
+            };
+            let beta = (cos / (pdf_pos * es.pdf_dir)) * es.le;
+            let walk = (es.pos + 0.001 * es.dir, es.dir, beta, es.pdf_dir);
+            random_walk(
+                scene,
+                walk,
+                time,
+                min_depth,
+                sampler,
+                &mut light_path,
+                false,
+            );
+        }
+    }
+
+    if let Some(escape) = escape {
+        let bounces = camera_path.len() - 1;
+        col.add(bounces, escaped(scene, &camera_path, &escape));
+    }
+    // Light sampling (s = 1) doesn't need the light subpath, so it's there even without one
+    for t in 1..=camera_path.len() {
+        for s in 0..=light_path.len().max(1) {
+            if s + t < 2 || (s == 1 && t == 1) || s + t - 2 > MAX_BOUNCES {
+                continue;
+            }
+            let (u_pick, u_light) = (sampler.get_1d(), sampler.get_2d());
+            let u = (u_pick, u_light);
+            let path = (camera_path.as_slice(), light_path.as_slice());
+            if let Some((l, raster)) = connect(scene, path, s, t, frame, u) {
+                let mut splat = PathRadiance::default();
+                match raster {
+                    Some((x, y)) => {
+                        splat.add(s + t - 2, l);
+                        film.add_splat(x, y, splat);
+                    }
+                    None => col.add(s + t - 2, l),
+                }
+            }
+        }
+    }
+    col
+}
+
+/// Extends path, which ends where the ray walk = (origin, direction, throughput, solid angle
+/// pdf of the direction) starts, until it leaves the scene, russian roulette ends it, or it runs
+/// into something that doesn't scatter. Camera subpaths that leave the scene say how.
+fn random_walk<'a>(
+    scene: &'a Scene,
+    walk: (Vector3, Vector3, Vector3, f64),
+    time: f64,
+    min_depth: usize,
+    sampler: &mut dyn Sampler,
+    path: &mut Vec<Vertex<'a>>,
+    camera: bool,
+) -> Option<Escape> {
+    let (mut origin, mut dir, mut beta, mut pdf_fwd) = walk;
+    let mut specular = camera;
+    let mut bounces = 0;
+    loop {
+        let (u_lobe, u_dir, u_roulette) = (sampler.get_1d(), sampler.get_2d(), sampler.get_1d());
+        let hit = match scene.intersect(origin, dir, time) {
+            Some(hit) => hit,
+            None => {
+                return camera.then_some(Escape {
+                    dir,
+                    beta,
+                    pdf: (!specular).then_some(pdf_fwd),
+                })
+            }
+        };
+        let pos = origin + hit.dist * dir;
+        let g = match hit.surface {
+            // Light paths can't bounce off lights, so they don't go there. Camera paths end there.
+            Surface::Light(_) => {
+                if camera {
+                    let mut v = Vertex::new(Kind::Light(hit.light?), pos, hit.normal, beta);
+                    v.pdf_fwd = path.last()?.convert_density(pdf_fwd, &v);
+                    path.push(v);
+                }
+                return None;
+            }
+            Surface::Object(g) => g,
+        };
+        let kind = Kind::Surface {
+            object: g,
+            index: hit.object,
+            dir,
+            light: hit.light,
+        };
+        let mut v = Vertex::new(kind, pos, hit.normal, beta);
+        let prev = path.len() - 1;
+        v.pdf_fwd = path[prev].convert_density(pdf_fwd, &v);
+        bounces += 1;
+        if bounces > MAX_BOUNCES {
+            path.push(v);
+            return None;
+        }
+        let s = match g
+            .material
+            .sample(dir, hit.normal, [u_lobe, u_dir[0], u_dir[1]])
+        {
+            Some(s) => s,
+            None => {
+                path.push(v);
+                return None;
+            }
+        };
+        beta = beta.star(s.weight);
+        let mut pdf_rev = g.material.pdf(-s.wi, hit.normal, -dir);
+        pdf_fwd = s.pdf;
+        specular = s.is_specular();
+        if specular {
+            v.delta = true;
+            pdf_fwd = 0.0;
+            pdf_rev = 0.0;
+        }
+        path[prev].pdf_rev = v.convert_density(pdf_rev, &path[prev]);
+        path.push(v);
+        if bounces > min_depth {
+            let survival = beta.max_component().min(0.95);
+            if u_roulette >= survival {
+                return None;
+            }
+            beta = (1.0 / survival) * beta;
+        }
+        if beta.max_component() <= 0.0 {
+            return None;
+        }
+        origin = pos + 0.001 * s.wi;
+        dir = s.wi;
+    }
+}
+
+/// Radiance from the background and distant lights that a camera subpath found by leaving the
+/// scene. The sun could have been sampled as a light too, so it's weighted against that.
+fn escaped(scene: &Scene, path: &[Vertex], escape: &Escape) -> Vector3 {
+    let last = path.last().unwrap();
+    let mut le = scene.background(escape.dir);
+    for (i, light) in scene.lights.iter().enumerate() {
+        let l = light.escaped(escape.dir);
+        if l.max_component() > 0.0 {
+            let weight = match escape.pdf {
+                Some(pdf) => {
+                    power_heuristic(pdf, scene.light_pdf(last.pos, last.normal, i, escape.dir))
+                }
+                None => 1.0,
+            };
+            le = le + weight * l;
+        }
+    }
+    le.star(escape.beta)
+}
+
+/// The path made of the first s vertices of the light subpath and the first t of the camera
+/// subpath, (camera path, light path) in path: its MIS weighted contribution, and where it
+/// landed in the image if it was connected straight to the camera. u has the random numbers for
+/// sampling a light, for s = 1.
+fn connect(
+    scene: &Scene,
+    path: (&[Vertex], &[Vertex]),
+    s: usize,
+    t: usize,
+    frame: Frame,
+    u: (f64, [f64; 2]),
+) -> Option<(Vector3, Option<(f64, f64)>)> {
+    let (camera_path, light_path) = path;
+    let Frame {
+        width,
+        height,
+        time,
+    } = frame;
+    let pt = &camera_path[t - 1];
+    // Camera paths that reached a light can't go on to somewhere else
+    if matches!(pt.kind, Kind::Light(_)) && s > 0 {
+        return None;
+    }
+    let mut sampled = None;
+    let mut raster = None;
+    let l = if s == 0 {
+        // The camera subpath found a light by itself
+        let le = pt.le(scene, &camera_path[t - 2]);
+        if let Kind::Surface { object, .. } = pt.kind {
+            if pt.light().is_none() {
+                // Moving emissive objects aren't lights, so this is the only way to find them
+                return Some((pt.beta.star(object.material.emissive), None));
+            }
+        }
+        pt.beta.star(le)
+    } else if t == 1 {
+        // The light subpath is connected to the camera
+        let qs = &light_path[s - 1];
+        if !qs.is_connectible(scene) {
+            return None;
+        }
+        raster = Some(scene.camera.project(qs.pos, width, height, time)?);
+        let cam = scene.camera.position(time);
+        let to_camera = cam - qs.pos;
+        let dist = to_camera.norm();
+        let wi = (1.0 / dist) * to_camera;
+        let (importance, _) = scene.camera.importance(-wi, width, height, time);
+        // Importance over the pdf of the camera being where it is, as seen from qs
+        let cos = -wi * scene.camera.forward(time);
+        let beta = importance * cos / (dist * dist);
+        let l = (beta * qs.f(cam)).star(qs.beta);
+        if l.max_component() <= 0.0 || scene.occluded(qs.pos + 0.001 * wi, wi, dist - 0.002, time) {
+            return None;
+        }
+        let zero = Vector3::new(0.0, 0.0, 0.0);
+        sampled = Some(Vertex::new(
+            Kind::Camera,
+            cam,
+            zero,
+            Vector3::new(beta, beta, beta),
+        ));
+        l
+    } else if s == 1 {
+        // Next event estimation: a light gets sampled from the camera subpath, picked the way the
+        // path tracer picks them. The weights treat the light vertex as if it had started a light
+        // path, which is fine as long as every way of making the path agrees on that.
+        if !pt.is_connectible(scene) {
+            return None;
+        }
+        let (u_pick, u_light) = u;
+        let (index, pmf) = scene.light_sampler.sample(pt.pos, pt.normal, u_pick)?;
+        let light = &scene.lights[index];
+        let ls = light.sample_li(pt.pos, u_light)?;
+        let f = pt.f(pt.pos + ls.wi);
+        if f.max_component() <= 0.0
+            || scene.occluded(pt.pos + 0.001 * ls.wi, ls.wi, ls.dist - 0.002, time)
+        {
+            return None;
+        }
+        let l = (1.0 / (ls.pdf * pmf)) * f.star(ls.li).star(pt.beta);
+        if light.is_infinite() {
+            // Not a vertex light paths can start from, so only the path tracer's two ways count
+            let weight = if light.is_delta() {
+                1.0
+            } else {
+                let Kind::Surface { object, dir, .. } = pt.kind else {
+                    return None;
+                };
+                let bsdf_pdf = object.material.pdf(dir, pt.normal, ls.wi);
+                power_heuristic(ls.pdf * pmf, bsdf_pdf)
+            };
+            return Some((weight * l, None));
+        }
+        let pos = pt.pos + ls.dist * ls.wi;
+        let normal = match light {
+            Light::Area { shape, .. } => shape.normal(pos),
+            _ => Vector3::new(0.0, 0.0, 0.0),
+        };
+        let mut v = Vertex::new(
+            Kind::Light(index),
+            pos,
+            normal,
+            (1.0 / (ls.pdf * pmf)) * ls.li,
+        );
+        v.pdf_fwd = v.pdf_light_origin(scene, pt);
+        sampled = Some(v);
+        l
+    } else {
+        // The two subpaths are joined
+        let qs = &light_path[s - 1];
+        if !qs.is_connectible(scene) || !pt.is_connectible(scene) {
+            return None;
+        }
+        let d = pt.pos - qs.pos;
+        let dist = d.norm();
+        let l = (1.0 / (dist * dist)) * qs.beta.star(qs.f(pt.pos)).star(pt.f(qs.pos)).star(pt.beta);
+        if l.max_component() <= 0.0 {
+            return None;
+        }
+        let w = (1.0 / dist) * d;
+        if scene.occluded(qs.pos + 0.001 * w, w, dist - 0.002, time) {
+            return None;
+        }
+        l
+    };
+    if l.max_component() <= 0.0 {
+        return None;
+    }
+    let weight = mis_weight(scene, path, sampled, s, t, frame);
+    Some((weight * l, raster))
+}
+
+/// Power heuristic weight of the path made with s light and t camera vertices, against all the
+/// other ways of making it with subpaths of other lengths. sampled is the vertex a light or
+/// camera sample added, for s = 1 or t = 1. The ratios of the pdfs of neighbouring ways follow
+/// from the pdfs of sampling each vertex both ways along the path, so the vertices around the
+/// connection need theirs updated for this way of making it.
+fn mis_weight(
+    scene: &Scene,
+    path: (&[Vertex], &[Vertex]),
+    sampled: Option<Vertex>,
+    s: usize,
+    t: usize,
+    frame: Frame,
+) -> f64 {
+    if s + t == 2 {
+        return 1.0;
+    }
+    let mut camera_path = path.0[..t].to_vec();
+    let mut light_path = path.1[..s.min(path.1.len())].to_vec();
+    if let Some(v) = sampled {
+        if s == 1 {
+            light_path.truncate(0);
+            light_path.push(v);
+        } else {
+            camera_path[0] = v;
+        }
+    }
+    // Connected vertices aren't specular, whatever they were sampled with
+    camera_path[t - 1].delta = false;
+    if s > 0 {
+        light_path[s - 1].delta = false;
+    }
+    let pt = camera_path[t - 1];
+    let pt_minus = (t > 1).then(|| camera_path[t - 2]);
+    let qs = (s > 0).then(|| light_path[s - 1]);
+    let qs_minus = (s > 1).then(|| light_path[s - 2]);
+    camera_path[t - 1].pdf_rev = match &qs {
+        Some(qs) => qs.pdf(scene, qs_minus.as_ref(), &pt, frame),
+        None => pt.pdf_light_origin(scene, pt_minus.as_ref().unwrap()),
+    };
+    if let Some(pt_minus) = &pt_minus {
+        camera_path[t - 2].pdf_rev = match &qs {
+            Some(qs) => pt.pdf(scene, Some(qs), pt_minus, frame),
+            None => pt.pdf_light(scene, pt_minus),
+        };
+    }
+    if let Some(qs) = &qs {
+        light_path[s - 1].pdf_rev = pt.pdf(scene, pt_minus.as_ref(), qs, frame);
+        if let Some(qs_minus) = &qs_minus {
+            light_path[s - 2].pdf_rev = qs.pdf(scene, Some(&pt), qs_minus, frame);
+        }
+    }
+
+    // Zero pdfs come from specular vertices, which get skipped anyway
+    let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+    for i in (1..t).rev() {
+        ratio *= remap(camera_path[i].pdf_rev) / remap(camera_path[i].pdf_fwd);
+        if !camera_path[i].delta && !camera_path[i - 1].delta {
+            sum += ratio * ratio;
+        }
+    }
+    ratio = 1.0;
+    for i in (0..s).rev() {
+        ratio *= remap(light_path[i].pdf_rev) / remap(light_path[i].pdf_fwd);
+        let delta_before = match light_path[i].kind {
+            Kind::Light(index) if i == 0 => scene.lights[index].is_delta(),
+            _ => i > 0 && light_path[i - 1].delta,
+        };
+        if !light_path[i].delta && !delta_before {
+            sum += ratio * ratio;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
+
+/// What the camera sees first at v, for the denoiser and the AOVs.
+fn first_hit(scene: &Scene, v: &Vertex, origin: Vector3) -> Features {
+    let dir = (v.pos - origin).normalize();
+    let (albedo, object) = match v.kind {
+        Kind::Surface { object, index, .. } => (object.material.base_col, index),
+        _ => (Vector3::new(1.0, 1.0, 1.0), None),
+    };
+    Features {
+        albedo,
+        normal: if v.normal * dir > 0.0 {
+            -v.normal
+        } else {
+            v.normal
+        },
+        depth: (v.pos - origin).norm(),
+        object,
+        material: object.map(|i| scene.material_ids[i]),
+    }
+}
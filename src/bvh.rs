@@ -0,0 +1,174 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::vector3::*;
+
+/// Items per leaf, at most
+const LEAF_SIZE: usize = 4;
+
+/// Set once something wants the steps counted, so that renders that don't aren't slowed down
+static COUNTING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Nodes visited and items tested by the traversals on this thread so far
+    static STEPS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Starts counting the steps of the traversals, for steps().
+pub fn count_steps() {
+    COUNTING.store(true, Ordering::Relaxed);
+}
+
+/// Nodes visited and items tested by the traversals on this thread since count_steps, nested
+/// ones (like those of meshes in the BVH of a scene) included. For seeing how hard the BVHs
+/// work.
+pub fn steps() -> usize {
+    STEPS.get()
+}
+
+/// Bounding volume hierarchy over a list of boxes, which can stand for anything: the triangles
+/// of a mesh, or the objects of a scene. Moving things should have boxes that hold them over
+/// the whole motion.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    /// Item indices, in the order the leaves refer to them
+    items: Vec<usize>,
+}
+
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    Leaf {
+        start: usize,
+        count: usize,
+    },
+    /// The first child comes right after the node, the second one is at second
+    Interior {
+        second: usize,
+        axis: usize,
+    },
+}
+
+impl Bvh {
+    /// Splits the boxes in half along their longest axis, recursively.
+    pub fn new(boxes: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: vec![],
+            items: (0..boxes.len()).collect(),
+        };
+        if !boxes.is_empty() {
+            bvh.build(boxes, 0, boxes.len());
+        }
+        bvh
+    }
+
+    fn build(&mut self, boxes: &[Aabb], start: usize, end: usize) -> usize {
+        let bounds = self.items[start..end]
+            .iter()
+            .fold(Aabb::empty(), |b, &i| b.union(&boxes[i]));
+        let index = self.nodes.len();
+        let count = end - start;
+        if count <= LEAF_SIZE {
+            self.nodes.push(Node {
+                bounds,
+                kind: NodeKind::Leaf { start, count },
+            });
+            return index;
+        }
+        let centroids = self.items[start..end].iter().fold(Aabb::empty(), |b, &i| {
+            b.union(&Aabb::point(boxes[i].center()))
+        });
+        let d = centroids.diagonal();
+        let axis = if d.x >= d.y && d.x >= d.z {
+            0
+        } else if d.y >= d.z {
+            1
+        } else {
+            2
+        };
+        let key = |i: usize| {
+            let c = boxes[i].center();
+            [c.x, c.y, c.z][axis]
+        };
+        let mid = start + count / 2;
+        self.items[start..end]
+            .select_nth_unstable_by(count / 2, |&a, &b| key(a).total_cmp(&key(b)));
+        // Placeholder, until the second child's index is known
+        self.nodes.push(Node {
+            bounds,
+            kind: NodeKind::Leaf { start, count: 0 },
+        });
+        self.build(boxes, start, mid);
+        let second = self.build(boxes, mid, end);
+        self.nodes[index].kind = NodeKind::Interior { second, axis };
+        index
+    }
+
+    /// Updates the bounds of the nodes for items that have moved, keeping the tree as it is.
+    /// Much cheaper than building it again, but the tree gets worse the further things move.
+    pub fn refit(&mut self, boxes: &[Aabb]) {
+        // Children come after their parents, so going backwards they're done first
+        for index in (0..self.nodes.len()).rev() {
+            self.nodes[index].bounds = match self.nodes[index].kind {
+                NodeKind::Leaf { start, count } => self.items[start..start + count]
+                    .iter()
+                    .fold(Aabb::empty(), |b, &i| b.union(&boxes[i])),
+                NodeKind::Interior { second, .. } => self.nodes[index + 1]
+                    .bounds
+                    .union(&self.nodes[second].bounds),
+            };
+        }
+    }
+
+    /// Calls hit for the items whose boxes the ray passes through before max_dist, nearer
+    /// boxes first. hit returns how far along the ray it hit the item, if it did, which cuts
+    /// the ray short.
+    pub fn traverse(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        max_dist: f64,
+        mut hit: impl FnMut(usize) -> Option<f64>,
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut max_dist = max_dist;
+        let mut stack = vec![0];
+        let mut steps = 0;
+        while let Some(index) = stack.pop() {
+            steps += 1;
+            let node = &self.nodes[index];
+            match node.bounds.intersect(origin, dir) {
+                Some((t0, _)) if t0 < max_dist => {}
+                _ => continue,
+            }
+            match node.kind {
+                NodeKind::Leaf { start, count } => {
+                    steps += count;
+                    for &item in &self.items[start..start + count] {
+                        if let Some(dist) = hit(item) {
+                            max_dist = max_dist.min(dist);
+                        }
+                    }
+                }
+                NodeKind::Interior { second, axis } => {
+                    // Pushed last, popped first
+                    if [dir.x, dir.y, dir.z][axis] < 0.0 {
+                        stack.push(index + 1);
+                        stack.push(second);
+                    } else {
+                        stack.push(second);
+                        stack.push(index + 1);
+                    }
+                }
+            }
+        }
+        if COUNTING.load(Ordering::Relaxed) {
+            STEPS.set(STEPS.get() + steps);
+        }
+    }
+}
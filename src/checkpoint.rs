@@ -0,0 +1,112 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+
+use crate::config::Config;
+use crate::film::Film;
+use crate::integrator::IntegratorKind;
+use crate::sampler::SamplerKind;
+
+const MAGIC: &[u8; 8] = b"RTCHECK3";
+/// Longer settings than this mean the checkpoint is broken
+const MAX_SETTINGS: u64 = 64 * 1024;
+
+/// Everything about a render that changes what ends up in the film. A checkpoint can only be
+/// resumed with the same settings, and for the same frame.
+fn settings(config: &Config, frame: Option<usize>) -> String {
+    let mut settings = format!(
+        "{} {}x{} seed {} {:?} {:?} {:?} {:?} {:?} {:?} {:?} fog {:?} {} {:?} shutter {}",
+        config.scene,
+        config.width,
+        config.height,
+        config.seed,
+        config.sampler,
+        config.filter,
+        config.light_sampling,
+        config.integrator,
+        config.bounces,
+        config.working_space,
+        config.background,
+        config.fog,
+        config.fog_anisotropy,
+        config.density_grid,
+        config.shutter,
+    );
+    // The strata depend on the sample count
+    if config.sampler == SamplerKind::Stratified {
+        settings += &format!(" spp {}", config.spp);
+    }
+    if config.integrator == IntegratorKind::PhotonMapping {
+        settings += &format!(
+            " photons {:?} radius {}",
+            config.photons, config.photon_radius
+        );
+    }
+    if config.integrator == IntegratorKind::AmbientOcclusion {
+        settings += &format!(" ao distance {}", config.ao_distance);
+    }
+    if config.spectral {
+        settings += " spectral";
+    }
+    if let Some(frame) = frame {
+        settings += &format!(" frame {}", frame);
+    }
+    settings
+}
+
+/// Saves the film, and how many samples per pixel the finished passes took. The samplers
+/// don't need saving: their state follows from the seed and the samples each pixel has taken,
+/// which the film keeps track of. Metropolis chains aren't saved either, resumed renders just
+/// start new ones.
+pub fn save(
+    path: &str,
+    config: &Config,
+    frame: Option<usize>,
+    film: &Film,
+    done: usize,
+) -> std::io::Result<()> {
+    // Written to a temporary file first, so that a crash halfway leaves the old checkpoint be
+    let tmp = format!("{}.tmp", path);
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    let settings = settings(config, frame);
+    out.write_all(MAGIC)?;
+    out.write_all(&(settings.len() as u64).to_le_bytes())?;
+    out.write_all(settings.as_bytes())?;
+    out.write_all(&(done as u64).to_le_bytes())?;
+    film.save(&mut out)?;
+    out.into_inner()?.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+/// Loads a checkpoint into film, and returns the samples per pixel done.
+pub fn load(
+    path: &str,
+    config: &Config,
+    frame: Option<usize>,
+    film: &mut Film,
+) -> std::io::Result<usize> {
+    let bad = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, msg));
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(bad("not a checkpoint"));
+    }
+    let mut len = [0; 8];
+    input.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_SETTINGS {
+        return Err(bad("corrupt settings"));
+    }
+    let mut saved = vec![0; len as usize];
+    input.read_exact(&mut saved)?;
+    if saved != settings(config, frame).as_bytes() {
+        return Err(bad(&format!(
+            "checkpoint is of a render with other settings ({})",
+            String::from_utf8_lossy(&saved)
+        )));
+    }
+    let mut done = [0; 8];
+    input.read_exact(&mut done)?;
+    film.load(&mut input)?;
+    Ok(u64::from_le_bytes(done) as usize)
+}
@@ -0,0 +1,102 @@
+use crate::vector3::*;
+
+/// RGB color spaces, all linear. Scene colors are written in linear sRGB, and get converted to
+/// the working space the render happens in, and from there to the output space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    /// Linear sRGB, which shares its primaries with Rec.709
+    Srgb,
+    /// ACES AP1 primaries, D60 white
+    AcesCg,
+    /// DCI-P3 primaries, D65 white
+    DisplayP3,
+}
+
+// CIE xy chromaticities
+const D65: (f64, f64) = (0.3127, 0.3290);
+const D60: (f64, f64) = (0.32168, 0.33767);
+
+impl ColorSpace {
+    pub fn by_name(name: &str) -> Option<ColorSpace> {
+        match name {
+            "srgb" | "rec709" => Some(ColorSpace::Srgb),
+            "acescg" => Some(ColorSpace::AcesCg),
+            "display-p3" => Some(ColorSpace::DisplayP3),
+            _ => None,
+        }
+    }
+
+    /// Chromaticities of the red, green and blue primaries, and of the white point.
+    fn chromaticities(&self) -> ([(f64, f64); 3], (f64, f64)) {
+        match self {
+            ColorSpace::Srgb => ([(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)], D65),
+            ColorSpace::AcesCg => ([(0.713, 0.293), (0.165, 0.830), (0.128, 0.044)], D60),
+            ColorSpace::DisplayP3 => ([(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)], D65),
+        }
+    }
+
+    /// RGB to CIE XYZ, from the chromaticities. The white point maps to Y = 1.
+    pub fn rgb_to_xyz(&self) -> Matrix3 {
+        let (primaries, white) = self.chromaticities();
+        let [r, g, b] = primaries.map(xy_to_xyz);
+        let p = Matrix3::from_cols(r, g, b);
+        // Scale the primaries so that they add up to the white point
+        let s = p.inverse() * xy_to_xyz(white);
+        p * Matrix3::diag(s)
+    }
+
+    /// The matrix taking colors from self to other. The white points are matched up with a
+    /// Bradford transform, the same as most other tools do.
+    pub fn conversion(&self, other: ColorSpace) -> Matrix3 {
+        let bradford = Matrix3::new([
+            [0.8951, 0.2664, -0.1614],
+            [-0.7502, 1.7135, 0.0367],
+            [0.0389, -0.0685, 1.0296],
+        ]);
+        let (from_white, to_white) = (self.chromaticities().1, other.chromaticities().1);
+        let (cone_from, cone_to) = (
+            bradford * xy_to_xyz(from_white),
+            bradford * xy_to_xyz(to_white),
+        );
+        let scale = Vector3::new(
+            cone_to.x / cone_from.x,
+            cone_to.y / cone_from.y,
+            cone_to.z / cone_from.z,
+        );
+        let adapt = bradford.inverse() * Matrix3::diag(scale) * bradford;
+        other.rgb_to_xyz().inverse() * adapt * self.rgb_to_xyz()
+    }
+
+    /// The transfer function images in this space are normally stored with. ACEScg is meant to
+    /// stay linear.
+    pub fn encode(&self, col: Vector3) -> Vector3 {
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => {
+                Vector3::new(srgb_oetf(col.x), srgb_oetf(col.y), srgb_oetf(col.z))
+            }
+            ColorSpace::AcesCg => col,
+        }
+    }
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> Vector3 {
+    Vector3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// The piecewise sRGB encoding, linear near black.
+pub fn srgb_oetf(v: f64) -> f64 {
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Inverse of srgb_oetf, for 8-bit inputs.
+pub fn srgb_to_linear(v: f64) -> f64 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
@@ -0,0 +1,441 @@
+use crate::aov::Aov;
+use crate::color::ColorSpace;
+use crate::filter::Filter;
+use crate::integrator::IntegratorKind;
+use crate::lightsampler::LightSampling;
+use crate::sampler::SamplerKind;
+use crate::scene::Scene;
+use crate::tiles::TileOrder;
+use crate::tonemapper::Tonemap;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: basic-raytracer [options]
+
+Options:
+  --scene <example|lights|volumes|cloud|subsurface|motion|animation|prism>
+                                         Which scene to render [example]
+  --frames <first-last|frame>            Render these frames of the scene's animation, to
+                                         output_0001.ppm and so on (checkpoints, passes and
+                                         the heatmap get numbered the same way)
+  --width <n>                            Image width in pixels [680]
+  --height <n>                           Image height in pixels [480]
+  --spp <n>                              Samples per pixel, at most when adaptive [100]
+  --adaptive <threshold>                 Stop sampling a pixel once the relative error of its
+                                         luminance is below threshold (e.g. 0.01), and the
+                                         render once every pixel is
+  --min-spp <n>                          Samples every pixel gets, when adaptive [16]
+  --time-limit <seconds>                 Stop after the last pass that fits in this time (per
+                                         frame, for animations)
+  --write-interval <seconds>             Write the image so far this often, between passes
+  --checkpoint <file>                    Save the render's progress to file, every so often,
+                                         at the end, and on Ctrl-C
+  --checkpoint-interval <seconds>        How often to save the checkpoint [300]
+  --resume                               Continue the render saved in the checkpoint file
+  --heatmap                              Write the samples taken per pixel to heatmap.ppm
+  --filter <box|tent|gaussian|mitchell|blackman-harris>
+                                         Pixel reconstruction filter [gaussian]
+  --filter-radius <r>                    Filter radius in pixels, overriding the filter's default
+  --sampler <independent|stratified|halton|sobol>
+                                         Where the random numbers come from [sobol]
+  --seed <n>                             Seed for the random numbers [0]
+  --threads <n>                          Number of render threads [all cores]
+  --shutter <fraction>                   How long the shutter stays open, as a fraction of the
+                                         motion in the scene, for motion blur [0.5]
+  --tile-size <n>                        Size of the square tiles threads work on [32]
+  --tile-order <hilbert|spiral|scanline> Order the tiles are rendered in [hilbert]
+  --light-sampling <uniform|power|bvh>  How lights are picked for direct lighting [bvh]
+  --integrator <path|bdpt|ppm|mlt>       Path tracing, bidirectional path tracing, which also
+                                         traces paths from the lights, progressive photon
+                                         mapping, or Metropolis light transport, which mutates
+                                         the random numbers of bright paths for more like
+                                         them. ppm and mlt go a sample per pixel per pass, and
+                                         mlt takes no --adaptive or --denoise, and leaves the
+                                         feature AOVs empty. bdpt and ppm ignore the bounce
+                                         limits other than --min-depth, and scenes with media
+                                         get path traced anyway [path]
+  --integrator <ao|normals|depth|uv|barycentric|bvh-cost|furnace>
+                                         Quick looks for setting up scenes, which ignore
+                                         media: ambient occlusion, normals, depth (1 / (1 +
+                                         distance)), surface coordinates, triangle vertex
+                                         weights, BVH nodes and primitives tested per camera
+                                         ray (red at 150), or the white furnace test, where
+                                         every material turns white and the lights go out
+                                         under a white sky
+  --photons <n>                          Photons traced per pass, for ppm [width x height]
+  --photon-radius <r>                    Radius photons are gathered in at first, for ppm, in
+                                         scene units. It shrinks from pass to pass [0.05]
+  --ao-distance <d>                      How far ambient occlusion looks for things in the way,
+                                         in scene units [1]
+  --min-depth <n>                        Bounces before russian roulette kicks in [3]
+  --max-diffuse <n|inf>                  Limit on diffuse and glossy bounces [inf]
+  --max-specular <n|inf>                 Limit on mirror reflections [inf]
+  --max-transmission <n|inf>             Limit on refractions [inf]
+  --tonemap <clamp|reinhard|reinhard-extended|aces|agx|hable>
+                                         Tonemapping operator [clamp]
+  --white <L>                            White point of reinhard-extended [automatic]
+  --white-percentile <p>                 Percentile of luminance used as the automatic white
+                                         point [99]
+  --exposure <ev>                        Exposure adjustment in stops, before tonemapping [0]
+                                         (with --auto-exposure, on top of the automatic one)
+  --auto-exposure                        Expose for middle gray at the log average luminance
+  --working-space <srgb|acescg|display-p3>
+                                         Color space the render happens in [srgb]
+  --output-space <srgb|acescg|display-p3>
+                                         Color space of the output image [srgb]
+                                         (sRGB and P3 get the sRGB curve, ACEScg stays linear)
+  --background <file.ppm>                Latitude-longitude environment map, sRGB encoded
+  --fog <density>                        Fill the space around objects with fog, which
+                                         scatters this fraction of light per unit length
+  --fog-anisotropy <g>                   Henyey-Greenstein g of the fog, from -1 (back
+                                         scattering) to 1 (forward) [0]
+  --density-grid <file.vol>              Replace the density of the grid volumes in the scene
+                                         (the cloud) with this grid. Header: VOL nx ny nz,
+                                         then little endian f32s, x fastest, then y, then z
+  --denoise                              Denoise the render, guided by albedo, normals and depth
+  --spectral                             Trace every path at three wavelengths rather than in
+                                         RGB, for dispersion through glass. Only for path and
+                                         mlt, and scenes without media; the rest get RGB
+  --aovs <all|pass,pass,...>             Also write these passes, as output_<pass>.pfm:
+                                         depth, normal, albedo, material, object, direct,
+                                         indirect, emission, samples
+  --help                                 Print this text";
+
+/// How many bounces of each kind a path may take. None means no limit, leaving termination
+/// entirely to russian roulette.
+#[derive(Debug, Clone, Copy)]
+pub struct BounceLimits {
+    /// Paths are never terminated by russian roulette before this many bounces.
+    pub min_depth: usize,
+    pub diffuse: Option<usize>,
+    pub specular: Option<usize>,
+    pub transmission: Option<usize>,
+}
+
+/// Settings for a render, read from the command line.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub scene: String,
+    /// First and last frame of the animation to render, None for a single image
+    pub frames: Option<(usize, usize)>,
+    pub width: usize,
+    pub height: usize,
+    /// Samples per pixel. With adaptive sampling, the most a pixel gets.
+    pub spp: usize,
+    /// Relative error threshold for adaptive sampling. None to always take spp samples.
+    pub adaptive: Option<f64>,
+    pub min_spp: usize,
+    pub heatmap: bool,
+    /// The render stops early rather than go over this
+    pub time_limit: Option<Duration>,
+    /// How often the image gets written during the render. None for only at the end.
+    pub write_interval: Option<Duration>,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: Duration,
+    /// Start from the checkpoint, rather than from scratch
+    pub resume: bool,
+    pub filter: Filter,
+    pub sampler: SamplerKind,
+    /// Renders with the same seed (and settings) come out exactly the same.
+    pub seed: u64,
+    /// None to use every core
+    pub threads: Option<usize>,
+    /// Samples are spread over the time from 0 to this, within the motion from 0 to 1.
+    pub shutter: f64,
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    pub light_sampling: LightSampling,
+    pub integrator: IntegratorKind,
+    /// Photons per pass, None for one per pixel
+    pub photons: Option<usize>,
+    pub photon_radius: f64,
+    /// How far ambient occlusion looks for things in the way
+    pub ao_distance: f64,
+    pub bounces: BounceLimits,
+    pub tonemap: Tonemap,
+    /// In stops: the image is scaled by 2^exposure before tonemapping.
+    pub exposure: f64,
+    pub auto_exposure: bool,
+    /// The white point, if reinhard-extended is left to pick it, is this percentile of luminance.
+    pub white_percentile: f64,
+    pub working_space: ColorSpace,
+    pub output_space: ColorSpace,
+    pub background: Option<String>,
+    /// Density of the atmosphere, None for clear air
+    pub fog: Option<f64>,
+    pub fog_anisotropy: f64,
+    pub density_grid: Option<String>,
+    pub denoise: bool,
+    /// Trace paths at a few wavelengths each, rather than in RGB
+    pub spectral: bool,
+    pub aovs: Vec<Aov>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            scene: "example".to_string(),
+            frames: None,
+            width: 680,
+            height: 480,
+            spp: 100,
+            adaptive: None,
+            min_spp: 16,
+            heatmap: false,
+            time_limit: None,
+            write_interval: None,
+            checkpoint: None,
+            checkpoint_interval: Duration::from_secs(300),
+            resume: false,
+            filter: Filter::by_name("gaussian").unwrap(),
+            sampler: SamplerKind::Sobol,
+            seed: 0,
+            threads: None,
+            shutter: 0.5,
+            tile_size: 32,
+            tile_order: TileOrder::Hilbert,
+            light_sampling: LightSampling::Bvh,
+            integrator: IntegratorKind::Path,
+            photons: None,
+            photon_radius: 0.05,
+            ao_distance: 1.0,
+            bounces: BounceLimits {
+                min_depth: 3,
+                diffuse: None,
+                specular: None,
+                transmission: None,
+            },
+            tonemap: Tonemap::Clamp,
+            exposure: 0.0,
+            auto_exposure: false,
+            white_percentile: 99.0,
+            working_space: ColorSpace::Srgb,
+            output_space: ColorSpace::Srgb,
+            background: None,
+            fog: None,
+            fog_anisotropy: 0.0,
+            density_grid: None,
+            denoise: false,
+            spectral: false,
+            aovs: vec![],
+        }
+    }
+}
+
+impl Config {
+    /// Parses the command line arguments. On error (or --help), returns the text to show.
+    pub fn from_args() -> Result<Config, String> {
+        Config::parse(std::env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut filter_radius: Option<f64> = None;
+        let mut white: Option<f64> = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}\n\n{}", arg, USAGE))
+            };
+            match arg.as_str() {
+                "--scene" => {
+                    let name = value()?;
+                    if !Scene::NAMES.contains(&name.as_str()) {
+                        return Err(invalid(&arg, &name));
+                    }
+                    config.scene = name
+                }
+                "--frames" => {
+                    let range = value()?;
+                    config.frames = Some(match range.split_once('-') {
+                        Some((first, last)) => (parse_num(&arg, first)?, parse_num(&arg, last)?),
+                        None => {
+                            let frame = parse_num(&arg, &range)?;
+                            (frame, frame)
+                        }
+                    });
+                    if config.frames.is_some_and(|(first, last)| first > last) {
+                        return Err(invalid(&arg, &range));
+                    }
+                }
+                "--width" => config.width = parse_num(&arg, &value()?)?,
+                "--height" => config.height = parse_num(&arg, &value()?)?,
+                "--spp" => config.spp = parse_num(&arg, &value()?)?,
+                "--adaptive" => config.adaptive = Some(parse_num(&arg, &value()?)?),
+                "--min-spp" => config.min_spp = parse_num(&arg, &value()?)?,
+                "--heatmap" => config.heatmap = true,
+                "--checkpoint" => config.checkpoint = Some(value()?),
+                "--checkpoint-interval" => {
+                    config.checkpoint_interval = parse_secs(&arg, &value()?)?
+                }
+                "--resume" => config.resume = true,
+                "--time-limit" => config.time_limit = Some(parse_secs(&arg, &value()?)?),
+                "--write-interval" => config.write_interval = Some(parse_secs(&arg, &value()?)?),
+                "--filter" => {
+                    let name = value()?;
+                    config.filter = Filter::by_name(&name).ok_or_else(|| invalid(&arg, &name))?
+                }
+                "--sampler" => {
+                    let name = value()?;
+                    config.sampler =
+                        SamplerKind::by_name(&name).ok_or_else(|| invalid(&arg, &name))?
+                }
+                "--seed" => config.seed = parse_num(&arg, &value()?)?,
+                "--threads" => config.threads = Some(parse_num(&arg, &value()?)?),
+                "--shutter" => config.shutter = parse_num(&arg, &value()?)?,
+                "--tile-size" => config.tile_size = parse_num(&arg, &value()?)?,
+                "--tile-order" => {
+                    let name = value()?;
+                    config.tile_order =
+                        TileOrder::by_name(&name).ok_or_else(|| invalid(&arg, &name))?
+                }
+                "--filter-radius" => filter_radius = Some(parse_num(&arg, &value()?)?),
+                "--light-sampling" => {
+                    config.light_sampling = match value()?.as_str() {
+                        "uniform" => LightSampling::Uniform,
+                        "power" => LightSampling::Power,
+                        "bvh" => LightSampling::Bvh,
+                        other => return Err(invalid(&arg, other)),
+                    }
+                }
+                "--integrator" => {
+                    let name = value()?;
+                    config.integrator =
+                        IntegratorKind::by_name(&name).ok_or_else(|| invalid(&arg, &name))?
+                }
+                "--photons" => config.photons = Some(parse_num(&arg, &value()?)?),
+                "--photon-radius" => config.photon_radius = parse_num(&arg, &value()?)?,
+                "--ao-distance" => config.ao_distance = parse_num(&arg, &value()?)?,
+                "--min-depth" => config.bounces.min_depth = parse_num(&arg, &value()?)?,
+                "--max-diffuse" => config.bounces.diffuse = parse_limit(&arg, &value()?)?,
+                "--max-specular" => config.bounces.specular = parse_limit(&arg, &value()?)?,
+                "--max-transmission" => config.bounces.transmission = parse_limit(&arg, &value()?)?,
+                "--tonemap" => {
+                    let name = value()?;
+                    config.tonemap = Tonemap::by_name(&name).ok_or_else(|| invalid(&arg, &name))?
+                }
+                "--white" => white = Some(parse_num(&arg, &value()?)?),
+                "--exposure" => config.exposure = parse_num(&arg, &value()?)?,
+                "--auto-exposure" => config.auto_exposure = true,
+                "--white-percentile" => config.white_percentile = parse_num(&arg, &value()?)?,
+                "--working-space" => {
+                    let name = value()?;
+                    config.working_space =
+                        ColorSpace::by_name(&name).ok_or_else(|| invalid(&arg, &name))?
+                }
+                "--output-space" => {
+                    let name = value()?;
+                    config.output_space =
+                        ColorSpace::by_name(&name).ok_or_else(|| invalid(&arg, &name))?
+                }
+                "--background" => config.background = Some(value()?),
+                "--fog" => config.fog = Some(parse_num(&arg, &value()?)?),
+                "--fog-anisotropy" => config.fog_anisotropy = parse_num(&arg, &value()?)?,
+                "--density-grid" => config.density_grid = Some(value()?),
+                "--denoise" => config.denoise = true,
+                "--spectral" => config.spectral = true,
+                "--aovs" => {
+                    let list = value()?;
+                    config.aovs = if list == "all" {
+                        Aov::ALL.to_vec()
+                    } else {
+                        list.split(',')
+                            .map(|name| Aov::by_name(name).ok_or_else(|| invalid(&arg, name)))
+                            .collect::<Result<_, _>>()?
+                    }
+                }
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                _ => return Err(format!("Unknown option {}\n\n{}", arg, USAGE)),
+            }
+        }
+        if let Some(radius) = filter_radius {
+            if !(radius.is_finite() && radius > 0.0) {
+                return Err(format!("--filter-radius must be positive\n\n{}", USAGE));
+            }
+            config.filter = config.filter.with_radius(radius);
+        }
+        if config.resume && config.checkpoint.is_none() {
+            return Err(format!("--resume needs a --checkpoint file\n\n{}", USAGE));
+        }
+        if !(0.0..=100.0).contains(&config.white_percentile) {
+            return Err(format!(
+                "--white-percentile must be within 0 and 100\n\n{}",
+                USAGE
+            ));
+        }
+        if !(0.0..=1.0).contains(&config.shutter) {
+            return Err(format!("--shutter must be within 0 and 1\n\n{}", USAGE));
+        }
+        if config.fog.is_some_and(|d| d < 0.0) || config.fog_anisotropy.abs() >= 1.0 {
+            return Err(format!(
+                "--fog must not be negative, and --fog-anisotropy must be within -1 and 1\n\n{}",
+                USAGE
+            ));
+        }
+        if let Some(w) = white {
+            if !(w.is_finite() && w > 0.0) {
+                return Err(format!("--white must be positive\n\n{}", USAGE));
+            }
+            if config.tonemap != (Tonemap::ReinhardExtended { white: None }) {
+                return Err(format!(
+                    "--white only applies to --tonemap reinhard-extended\n\n{}",
+                    USAGE
+                ));
+            }
+            config.tonemap = Tonemap::ReinhardExtended { white: Some(w) };
+        }
+        if config.photons == Some(0)
+            || !(config.photon_radius.is_finite() && config.photon_radius > 0.0)
+        {
+            return Err(format!(
+                "--photons and --photon-radius must be positive\n\n{}",
+                USAGE
+            ));
+        }
+        if config.ao_distance <= 0.0 {
+            return Err(format!("--ao-distance must be positive\n\n{}", USAGE));
+        }
+        if config.integrator == IntegratorKind::Metropolis
+            && (config.adaptive.is_some() || config.denoise)
+        {
+            return Err(format!(
+                "mlt samples don't belong to pixels, so --adaptive and --denoise don't work \
+                 with it\n\n{}",
+                USAGE
+            ));
+        }
+        if config.width == 0
+            || config.height == 0
+            || config.spp == 0
+            || config.tile_size == 0
+            || config.threads == Some(0)
+        {
+            return Err(format!(
+                "Image size, sample count, tile size and thread count must be positive\n\n{}",
+                USAGE
+            ));
+        }
+        Ok(config)
+    }
+}
+
+fn invalid(arg: &str, value: &str) -> String {
+    format!("Invalid value {} for {}\n\n{}", value, arg, USAGE)
+}
+
+fn parse_num<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| invalid(arg, value))
+}
+
+fn parse_secs(arg: &str, value: &str) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(parse_num(arg, value)?).map_err(|_| invalid(arg, value))
+}
+
+fn parse_limit(arg: &str, value: &str) -> Result<Option<usize>, String> {
+    if value == "inf" {
+        Ok(None)
+    } else {
+        parse_num(arg, value).map(Some)
+    }
+}
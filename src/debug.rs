@@ -0,0 +1,265 @@
+use crate::aov::heat;
+use crate::bvh;
+use crate::color::srgb_to_linear;
+use crate::denoise::Features;
+use crate::film::{Film, PathRadiance};
+use crate::hit_features;
+use crate::integrator::Integrator;
+use crate::material::Material;
+use crate::sampler::Sampler;
+use crate::scene::{Hit, Scene, Surface};
+use crate::vector3::*;
+
+/// BVH cost that shows up red in the heatmap, in nodes visited and primitives tested
+const MAX_BVH_COST: f64 = 150.0;
+
+/// The first surface a ray runs into, not counting the boundaries of media, and where it is.
+/// It goes into features.
+fn first_hit<'a>(
+    scene: &'a Scene,
+    origin: Vector3,
+    dir: Vector3,
+    time: f64,
+    features: &mut Features,
+) -> Option<(Hit<'a>, Vector3)> {
+    let mut orig = origin;
+    loop {
+        let hit = scene.intersect(orig, dir, time)?;
+        let pos = orig + hit.dist * dir;
+        match hit.surface {
+            Surface::Object(g) if g.material.is_passthrough() => orig = pos + 0.001 * dir,
+            _ => {
+                *features = hit_features(scene, &hit, pos, origin, dir);
+                return Some((hit, pos));
+            }
+        }
+    }
+}
+
+/// A color for the camera to see, as if it were emitted.
+fn shown(col: Vector3) -> PathRadiance {
+    PathRadiance {
+        emission: col,
+        ..PathRadiance::default()
+    }
+}
+
+/// White where a random direction over the first hit is open for distance, black where
+/// something's in the way. Directions go by cosine, which averages out to the ambient occlusion.
+pub struct AmbientOcclusion {
+    pub distance: f64,
+}
+impl Integrator for AmbientOcclusion {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        let u = sampler.get_2d();
+        let (hit, pos) = match first_hit(scene, origin, dir, time, features) {
+            Some(hit) => hit,
+            None => return PathRadiance::default(),
+        };
+        let n = if hit.normal * dir > 0.0 {
+            -hit.normal
+        } else {
+            hit.normal
+        };
+        let wi = n.local_to_world(cosine_hemisphere(u));
+        if scene.occluded(pos + 0.001 * n, wi, self.distance, time) {
+            PathRadiance::default()
+        } else {
+            shown(Vector3::new(1.0, 1.0, 1.0))
+        }
+    }
+}
+
+/// The geometric normal, outwards, with its components from [-1, 1] mapped to [0, 1].
+pub struct Normals;
+impl Integrator for Normals {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        _sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        match first_hit(scene, origin, dir, time, features) {
+            Some((hit, _)) => shown(0.5 * (hit.normal + Vector3::new(1.0, 1.0, 1.0))),
+            None => PathRadiance::default(),
+        }
+    }
+}
+
+/// Distance to the first hit, as gray: white up close, fading to black far away.
+pub struct Depth;
+impl Integrator for Depth {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        _sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        match first_hit(scene, origin, dir, time, features) {
+            Some((_, pos)) => {
+                let v = 1.0 / (1.0 + (pos - origin).norm());
+                shown(Vector3::new(v, v, v))
+            }
+            None => PathRadiance::default(),
+        }
+    }
+}
+
+/// Surface coordinates, with u in red and v in green.
+pub struct Uv;
+impl Integrator for Uv {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        _sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        match first_hit(scene, origin, dir, time, features) {
+            Some((hit, pos)) => {
+                let [u, v] = scene.uv(&hit, pos);
+                shown(Vector3::new(u, v, 0.0))
+            }
+            None => PathRadiance::default(),
+        }
+    }
+}
+
+/// The weights of the vertices of triangles, in red, green and blue, which shows the edges of
+/// meshes. Other surfaces are black.
+pub struct Barycentric;
+impl Integrator for Barycentric {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        _sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        match first_hit(scene, origin, dir, time, features).and_then(|(hit, _)| hit.barycentric) {
+            Some(weights) => shown(weights),
+            None => PathRadiance::default(),
+        }
+    }
+}
+
+/// How much work finding the camera ray's hit was, in nodes of the BVHs visited and primitives
+/// tested, as a heatmap from blue (none) to red (MAX_BVH_COST or more).
+pub struct BvhCost;
+impl Integrator for BvhCost {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        _sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        let before = bvh::steps();
+        if let Some(hit) = scene.intersect(origin, dir, time) {
+            *features = hit_features(scene, &hit, origin + hit.dist * dir, origin, dir);
+        }
+        let cost = (bvh::steps() - before) as f64;
+        // The heatmap colors are for the screen, and the film is linear
+        let col = heat(cost / MAX_BVH_COST);
+        shown(Vector3::new(
+            srgb_to_linear(col.x),
+            srgb_to_linear(col.y),
+            srgb_to_linear(col.z),
+        ))
+    }
+}
+
+/// The white furnace test: every surface keeps its way of scattering, but turns white, and
+/// the lights go out, under a sky of radiance 1. Paths only end when they escape to the sky,
+/// so a scene of materials that neither lose nor make energy comes out the same white as the
+/// sky, and anything darker or brighter shows up.
+pub struct WhiteFurnace {
+    pub min_depth: usize,
+}
+impl Integrator for WhiteFurnace {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        let white = Vector3::new(1.0, 1.0, 1.0);
+        let mut radiance = PathRadiance::default();
+        let (mut orig, mut dir) = (origin, dir);
+        let mut throughput = white;
+        let mut depth = 0;
+        loop {
+            let (u_lobe, u_dir, u_roulette) =
+                (sampler.get_1d(), sampler.get_2d(), sampler.get_1d());
+            let hit = match scene.intersect(orig, dir, time) {
+                Some(hit) => hit,
+                None => {
+                    radiance.add(depth, throughput);
+                    break;
+                }
+            };
+            let pos = orig + hit.dist * dir;
+            let g = match hit.surface {
+                Surface::Object(g) if !g.material.is_passthrough() => g,
+                // Lights and the boundaries of media aren't there
+                _ => {
+                    orig = pos + 0.001 * dir;
+                    continue;
+                }
+            };
+            if depth == 0 {
+                *features = hit_features(scene, &hit, pos, origin, dir);
+            }
+            let mat = Material {
+                base_col: white,
+                ..g.material.clone()
+            };
+            let s = match mat.sample(dir, hit.normal, [u_lobe, u_dir[0], u_dir[1]]) {
+                Some(s) => s,
+                None => break,
+            };
+            throughput = throughput.star(s.weight);
+            depth += 1;
+            if depth > self.min_depth {
+                let survival = throughput.max_component().min(0.95);
+                if u_roulette >= survival {
+                    break;
+                }
+                throughput = (1.0 / survival) * throughput;
+            }
+            orig = pos + 0.001 * s.wi;
+            dir = s.wi;
+        }
+        radiance
+    }
+}
@@ -0,0 +1,126 @@
+use crate::tonemapper::luminance;
+use crate::vector3::*;
+
+/// What a camera ray ran into first. These guide the denoiser to keep edges sharp, and make up
+/// some of the AOVs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Features {
+    pub albedo: Vector3,
+    /// Facing the camera. Zero for rays that escaped.
+    pub normal: Vector3,
+    pub depth: f64,
+    /// Index of the object in the scene, None for lights and the background
+    pub object: Option<usize>,
+    /// Objects with equal materials share the material ID
+    pub material: Option<usize>,
+}
+
+/// Per pixel averages of the features, along with the variance of the pixel's mean luminance.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelFeatures {
+    pub features: Features,
+    pub variance: f64,
+    /// How many samples the pixel got
+    pub samples: f64,
+}
+
+// Edge stopping parameters, after the SVGF paper (Schied et al.)
+const SIGMA_LUMINANCE: f64 = 4.0;
+const NORMAL_POWER: i32 = 128;
+/// Allowed relative depth difference, per pixel of distance
+const SIGMA_DEPTH: f64 = 0.02;
+const ITERATIONS: usize = 5;
+
+/// Edge-avoiding à-trous wavelet filter (Dammertz et al.), with the edge stopping functions of
+/// SVGF. Every iteration blurs with a 5x5 B-spline kernel whose taps are twice as far apart as in
+/// the last one, skipping over neighbours that differ too much in normal, depth or luminance,
+/// relative to how noisy the pixel is.
+/// The filter works on the lighting alone: the color is divided by the albedo beforehand, so
+/// that texture detail isn't blurred, and multiplied back afterwards.
+pub fn denoise(frame: &mut [Vector3], aux: &[PixelFeatures], width: usize, height: usize) {
+    let eps = 1e-4;
+    let demodulate = |c: f64, a: f64| if a > eps { c / a } else { c };
+    let mut light: Vec<Vector3> = frame
+        .iter()
+        .zip(aux)
+        .map(|(c, a)| {
+            let a = a.features.albedo;
+            Vector3::new(
+                demodulate(c.x, a.x),
+                demodulate(c.y, a.y),
+                demodulate(c.z, a.z),
+            )
+        })
+        .collect();
+    // The variance is of the color, but the filter works on the lighting
+    let mut variance: Vec<f64> = aux
+        .iter()
+        .zip(frame.iter())
+        .zip(&light)
+        .map(|((a, c), l)| {
+            let scale = luminance(*l) / luminance(*c).max(eps);
+            a.variance * scale * scale
+        })
+        .collect();
+
+    let kernel = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        let mut next_light = light.clone();
+        let mut next_variance = variance.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let p = y * width + x;
+                let fp = aux[p].features;
+                // Nothing to go by for the background
+                if fp.normal.norm() == 0.0 {
+                    continue;
+                }
+                let lum_p = luminance(light[p]);
+                let lum_scale = SIGMA_LUMINANCE * variance[p].max(0.0).sqrt() + eps;
+                let (mut sum, mut sum_var, mut sum_w) = (Vector3::default(), 0.0, 0.0);
+                for (j, ky) in kernel.iter().enumerate() {
+                    let qy = y as isize + (j as isize - 2) * step;
+                    if qy < 0 || qy >= height as isize {
+                        continue;
+                    }
+                    for (i, kx) in kernel.iter().enumerate() {
+                        let qx = x as isize + (i as isize - 2) * step;
+                        if qx < 0 || qx >= width as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+                        let fq = aux[q].features;
+                        let w_normal = (fp.normal * fq.normal).max(0.0).powi(NORMAL_POWER);
+                        let dist = (((i as isize - 2).pow(2) + (j as isize - 2).pow(2)) as f64)
+                            .sqrt()
+                            * step as f64;
+                        let w_depth = (-(fp.depth - fq.depth).abs()
+                            / (SIGMA_DEPTH * fp.depth * dist + eps))
+                            .exp();
+                        let w_lum = (-(lum_p - luminance(light[q])).abs() / lum_scale).exp();
+                        let w = kx * ky * w_normal * w_depth * w_lum;
+                        sum = sum + w * light[q];
+                        sum_var += w * w * variance[q];
+                        sum_w += w;
+                    }
+                }
+                // The pixel itself always has a weight, as long as it has a normal
+                next_light[p] = (1.0 / sum_w) * sum;
+                next_variance[p] = sum_var / (sum_w * sum_w);
+            }
+        }
+        light = next_light;
+        variance = next_variance;
+    }
+
+    for ((c, l), a) in frame.iter_mut().zip(&light).zip(aux) {
+        let a = a.features.albedo;
+        let remodulate = |l: f64, a: f64| if a > eps { l * a } else { l };
+        *c = Vector3::new(
+            remodulate(l.x, a.x),
+            remodulate(l.y, a.y),
+            remodulate(l.z, a.z),
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use crate::denoise::{Features, PixelFeatures};
+use crate::filter::Filter;
+use crate::tonemapper::luminance;
+use crate::vector3::*;
+use std::io::{Read, Write};
+use std::ops::{Add, Mul, Range};
+
+/// The radiance a path carries back, split up by how many bounces the light took to get to the
+/// camera.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PathRadiance {
+    /// Seen directly by the camera: emissive surfaces, lights and the background
+    pub emission: Vector3,
+    /// Light that reached the camera after a single bounce
+    pub direct: Vector3,
+    /// Everything else
+    pub indirect: Vector3,
+}
+impl PathRadiance {
+    /// Adds light that took the given number of bounces to reach the camera.
+    pub fn add(&mut self, bounces: usize, col: Vector3) {
+        let part = match bounces {
+            0 => &mut self.emission,
+            1 => &mut self.direct,
+            _ => &mut self.indirect,
+        };
+        *part = *part + col;
+    }
+    pub fn total(&self) -> Vector3 {
+        self.emission + self.direct + self.indirect
+    }
+}
+impl Add for PathRadiance {
+    type Output = PathRadiance;
+
+    fn add(self, other: PathRadiance) -> PathRadiance {
+        PathRadiance {
+            emission: self.emission + other.emission,
+            direct: self.direct + other.direct,
+            indirect: self.indirect + other.indirect,
+        }
+    }
+}
+impl Mul<PathRadiance> for f64 {
+    type Output = PathRadiance;
+
+    fn mul(self, l: PathRadiance) -> PathRadiance {
+        PathRadiance {
+            emission: self * l.emission,
+            direct: self * l.direct,
+            indirect: self * l.indirect,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct FilmPixel {
+    /// Sum of filter weighted radiance
+    sum: PathRadiance,
+    /// Sum of the filter weights
+    weight: f64,
+}
+
+/// Running mean and variance, updated one value at a time with Welford's algorithm, which
+/// doesn't lose precision the way summing squares does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Welford {
+    pub count: f64,
+    pub mean: f64,
+    /// Sum of squared differences from the mean
+    m2: f64,
+}
+impl Welford {
+    pub fn add(&mut self, x: f64) {
+        self.count += 1.0;
+        let delta = x - self.mean;
+        self.mean += delta / self.count;
+        self.m2 += delta * (x - self.mean);
+    }
+    /// Combines the statistics of two sets of values (Chan et al.)
+    pub fn merge(&mut self, other: &Welford) {
+        let count = self.count + other.count;
+        if count == 0.0 {
+            return;
+        }
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count / count;
+        self.m2 += other.m2 + delta * delta * self.count * other.count / count;
+        self.count = count;
+    }
+    /// Sample variance of the values
+    pub fn variance(&self) -> f64 {
+        if self.count > 1.0 {
+            self.m2 / (self.count - 1.0)
+        } else {
+            0.0
+        }
+    }
+    /// Variance of the mean, as an estimate of the true mean
+    pub fn variance_of_mean(&self) -> f64 {
+        if self.count > 0.0 {
+            self.variance() / self.count
+        } else {
+            0.0
+        }
+    }
+    /// Standard error relative to the mean. Means near black count as 1e-3, so dark pixels
+    /// don't need an unreasonable number of samples.
+    pub fn relative_error(&self) -> f64 {
+        self.variance_of_mean().sqrt() / self.mean.abs().max(1e-3)
+    }
+}
+
+/// Unfiltered sums over the samples taken within a pixel, for the denoiser and the AOVs.
+#[derive(Debug, Clone, Copy, Default)]
+struct AuxPixel {
+    albedo: Vector3,
+    normal: Vector3,
+    depth: f64,
+    /// Luminance of the samples
+    lum: Welford,
+    /// IDs can't be averaged, so these are from the first sample
+    object: Option<usize>,
+    material: Option<usize>,
+}
+
+/// Floating point image that samples get splatted into. A film can cover only a rectangle of
+/// the image, so threads working on a tile of the image can keep their own film.
+#[derive(Debug, Clone)]
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub filter: Filter,
+    /// The columns and rows the film covers
+    cols: Range<usize>,
+    rows: Range<usize>,
+    pixels: Vec<FilmPixel>,
+    aux: Vec<AuxPixel>,
+    /// Light traced from the lights to the camera, which lands anywhere in the image, summed per
+    /// pixel. Only films of the whole image have these.
+    splats: Vec<PathRadiance>,
+    /// Splats a tile got, as (pixel index in the image, radiance), for the whole image to add up
+    tile_splats: Vec<(usize, PathRadiance)>,
+    /// Samples taken, altogether. Each may have traced a light path, so the splats get spread
+    /// over them.
+    samples_taken: f64,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Film {
+        Film {
+            splats: vec![PathRadiance::default(); width * height],
+            ..Film::tile(width, height, filter, 0..width, 0..height)
+        }
+    }
+
+    /// A film for the given rectangle of the image, as well as the pixels around it that
+    /// samples from the rectangle get splatted into.
+    pub fn tile(
+        width: usize,
+        height: usize,
+        filter: Filter,
+        cols: Range<usize>,
+        rows: Range<usize>,
+    ) -> Film {
+        let margin = filter.radius().ceil() as usize;
+        let cols = cols.start.saturating_sub(margin)..(cols.end + margin).min(width);
+        let rows = rows.start.saturating_sub(margin)..(rows.end + margin).min(height);
+        let size = cols.len() * rows.len();
+        Film {
+            width,
+            height,
+            filter,
+            cols,
+            rows,
+            pixels: vec![FilmPixel::default(); size],
+            aux: vec![AuxPixel::default(); size],
+            splats: vec![],
+            tile_splats: vec![],
+            samples_taken: 0.0,
+        }
+    }
+
+    /// A film for samples that can land anywhere in the image, like the mutations of Metropolis
+    /// chains, which only get splatted. It has no pixels: the splats go in a list, with room for
+    /// capacity of them, which merging into the whole image adds up in order.
+    pub fn splats_only(width: usize, height: usize, filter: Filter, capacity: usize) -> Film {
+        Film {
+            width,
+            height,
+            filter,
+            cols: 0..0,
+            rows: 0..0,
+            pixels: vec![],
+            aux: vec![],
+            splats: vec![],
+            tile_splats: Vec::with_capacity(capacity),
+            samples_taken: 0.0,
+        }
+    }
+
+    /// Where pixel (i, j) of the image is stored
+    fn index(&self, i: usize, j: usize) -> usize {
+        (j - self.rows.start) * self.cols.len() + (i - self.cols.start)
+    }
+
+    /// Adds a radiance sample, taken at the continuous image position (x, y), to every pixel
+    /// within the filter radius. Pixel (i, j) covers [i, i + 1) x [j, j + 1).
+    pub fn add_sample(&mut self, x: f64, y: f64, radiance: PathRadiance) {
+        let r = self.filter.radius();
+        let x0 = ((x - 0.5 - r).ceil().max(0.0) as usize).max(self.cols.start);
+        let x1 = ((x - 0.5 + r).floor() as isize).min(self.cols.end as isize - 1);
+        let y0 = ((y - 0.5 - r).ceil().max(0.0) as usize).max(self.rows.start);
+        let y1 = ((y - 0.5 + r).floor() as isize).min(self.rows.end as isize - 1);
+        for j in y0 as isize..=y1 {
+            for i in x0 as isize..=x1 {
+                let w = self.filter.eval(i as f64 + 0.5 - x, j as f64 + 0.5 - y);
+                if w != 0.0 {
+                    let index = self.index(i as usize, j as usize);
+                    let p = &mut self.pixels[index];
+                    p.sum = p.sum + w * radiance;
+                    p.weight += w;
+                }
+            }
+        }
+    }
+
+    /// Adds light traced to the camera from the lights, which reached it at the continuous image
+    /// position (x, y). Splats aren't filtered, they just go to the pixel they land in.
+    pub fn add_splat(&mut self, x: f64, y: f64, radiance: PathRadiance) {
+        let index =
+            (y as usize).min(self.height - 1) * self.width + (x as usize).min(self.width - 1);
+        if self.splats.is_empty() {
+            self.tile_splats.push((index, radiance));
+        } else {
+            self.splats[index] = self.splats[index] + radiance;
+        }
+    }
+
+    /// Counts a sample that only splatted, and belongs to no pixel in particular.
+    pub fn add_splat_sample(&mut self) {
+        self.samples_taken += 1.0;
+    }
+
+    /// Records the first hit features and radiance of a sample taken within pixel (i, j).
+    pub fn add_features(&mut self, i: usize, j: usize, features: &Features, radiance: Vector3) {
+        let index = self.index(i, j);
+        let p = &mut self.aux[index];
+        if p.lum.count == 0.0 {
+            p.object = features.object;
+            p.material = features.material;
+        }
+        p.albedo = p.albedo + features.albedo;
+        p.normal = p.normal + features.normal;
+        p.depth += features.depth;
+        p.lum.add(luminance(radiance));
+        self.samples_taken += 1.0;
+    }
+
+    /// Adds the samples of another film of the same image to this one.
+    pub fn merge(&mut self, other: &Film) {
+        let rows = self.rows.start.max(other.rows.start)..self.rows.end.min(other.rows.end);
+        let cols = self.cols.start.max(other.cols.start)..self.cols.end.min(other.cols.end);
+        for j in rows {
+            for i in cols.clone() {
+                let (s, d) = (other.index(i, j), self.index(i, j));
+                let src = other.pixels[s];
+                let dst = &mut self.pixels[d];
+                dst.sum = dst.sum + src.sum;
+                dst.weight += src.weight;
+                let src = other.aux[s];
+                let dst = &mut self.aux[d];
+                dst.albedo = dst.albedo + src.albedo;
+                dst.normal = dst.normal + src.normal;
+                dst.depth += src.depth;
+                if dst.lum.count == 0.0 {
+                    dst.object = src.object;
+                    dst.material = src.material;
+                }
+                dst.lum.merge(&src.lum);
+            }
+        }
+        for &(index, radiance) in &other.tile_splats {
+            if self.splats.is_empty() {
+                self.tile_splats.push((index, radiance));
+            } else {
+                self.splats[index] = self.splats[index] + radiance;
+            }
+        }
+        self.samples_taken += other.samples_taken;
+    }
+
+    /// The reconstructed radiance of pixel (i, j).
+    pub fn pixel(&self, i: usize, j: usize) -> Vector3 {
+        self.light(i, j).total()
+    }
+
+    /// The reconstructed radiance of pixel (i, j), split up by the number of bounces.
+    pub fn light(&self, i: usize, j: usize) -> PathRadiance {
+        let p = self.pixels[self.index(i, j)];
+        let mut light = if p.weight > 0.0 {
+            (1.0 / p.weight) * p.sum
+        } else {
+            PathRadiance::default()
+        };
+        // Every sample is a chance for a light path to land in any pixel
+        if !self.splats.is_empty() && self.samples_taken > 0.0 {
+            let per_pixel = self.samples_taken / (self.width * self.height) as f64;
+            light = light + (1.0 / per_pixel) * self.splats[j * self.width + i];
+        }
+        light
+    }
+
+    /// How many samples pixel (i, j) has taken
+    pub fn samples(&self, i: usize, j: usize) -> usize {
+        self.aux[self.index(i, j)].lum.count as usize
+    }
+
+    /// Relative standard error of the luminance of pixel (i, j), from its samples so far.
+    pub fn relative_error(&self, i: usize, j: usize) -> f64 {
+        self.aux[self.index(i, j)].lum.relative_error()
+    }
+
+    /// Averaged features of pixel (i, j), and the variance of its mean luminance.
+    pub fn features(&self, i: usize, j: usize) -> PixelFeatures {
+        let p = self.aux[self.index(i, j)];
+        let n = p.lum.count;
+        if n == 0.0 {
+            return PixelFeatures::default();
+        }
+        let normal = if p.normal.norm() > 0.0 {
+            p.normal.normalize()
+        } else {
+            p.normal
+        };
+        PixelFeatures {
+            features: Features {
+                albedo: (1.0 / n) * p.albedo,
+                normal,
+                depth: p.depth / n,
+                object: p.object,
+                material: p.material,
+            },
+            variance: p.lum.variance_of_mean(),
+            samples: n,
+        }
+    }
+
+    /// Writes out everything the film has accumulated, for a checkpoint.
+    pub fn save(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let mut put = |values: &[f64]| -> std::io::Result<()> {
+            for v in values {
+                out.write_all(&v.to_le_bytes())?;
+            }
+            Ok(())
+        };
+        // IDs are stored as floats too, with -1 for none
+        let id = |id: Option<usize>| id.map_or(-1.0, |id| id as f64);
+        for (p, a) in self.pixels.iter().zip(&self.aux) {
+            let l = &p.sum;
+            for v in [l.emission, l.direct, l.indirect, a.albedo, a.normal] {
+                put(&[v.x, v.y, v.z])?;
+            }
+            put(&[p.weight, a.depth, a.lum.count, a.lum.mean, a.lum.m2])?;
+            put(&[id(a.object), id(a.material)])?;
+        }
+        for l in &self.splats {
+            for v in [l.emission, l.direct, l.indirect] {
+                put(&[v.x, v.y, v.z])?;
+            }
+        }
+        put(&[self.samples_taken])
+    }
+
+    /// Reads back what save wrote, into this film, which has to cover the same pixels.
+    pub fn load(&mut self, input: &mut impl Read) -> std::io::Result<()> {
+        let mut get = || -> std::io::Result<f64> {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            Ok(f64::from_le_bytes(bytes))
+        };
+        for (p, a) in self.pixels.iter_mut().zip(self.aux.iter_mut()) {
+            let mut vec =
+                || -> std::io::Result<Vector3> { Ok(Vector3::new(get()?, get()?, get()?)) };
+            p.sum = PathRadiance {
+                emission: vec()?,
+                direct: vec()?,
+                indirect: vec()?,
+            };
+            a.albedo = vec()?;
+            a.normal = vec()?;
+            p.weight = get()?;
+            a.depth = get()?;
+            a.lum = Welford {
+                count: get()?,
+                mean: get()?,
+                m2: get()?,
+            };
+            let id = |v: f64| if v < 0.0 { None } else { Some(v as usize) };
+            a.object = id(get()?);
+            a.material = id(get()?);
+        }
+        for l in self.splats.iter_mut() {
+            let mut vec =
+                || -> std::io::Result<Vector3> { Ok(Vector3::new(get()?, get()?, get()?)) };
+            *l = PathRadiance {
+                emission: vec()?,
+                direct: vec()?,
+                indirect: vec()?,
+            };
+        }
+        self.samples_taken = get()?;
+        Ok(())
+    }
+}
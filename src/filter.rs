@@ -0,0 +1,111 @@
+use std::f64::consts::PI;
+
+/// Pixel reconstruction filters. Samples are weighted by the filter, evaluated at their offset
+/// from the center of each pixel within the filter radius.
+#[derive(Debug, Clone, Copy)]
+pub enum Filter {
+    Box {
+        radius: f64,
+    },
+    Tent {
+        radius: f64,
+    },
+    Gaussian {
+        radius: f64,
+        sigma: f64,
+    },
+    /// Mitchell-Netravali, with the usual B = C = 1/3
+    Mitchell {
+        radius: f64,
+    },
+    BlackmanHarris {
+        radius: f64,
+    },
+}
+
+impl Filter {
+    /// The filter with its default radius. None for unknown names.
+    pub fn by_name(name: &str) -> Option<Filter> {
+        match name {
+            "box" => Some(Filter::Box { radius: 0.5 }),
+            "tent" => Some(Filter::Tent { radius: 1.0 }),
+            "gaussian" => Some(Filter::Gaussian {
+                radius: 1.5,
+                sigma: 0.5,
+            }),
+            "mitchell" => Some(Filter::Mitchell { radius: 2.0 }),
+            "blackman-harris" => Some(Filter::BlackmanHarris { radius: 1.5 }),
+            _ => None,
+        }
+    }
+
+    pub fn radius(&self) -> f64 {
+        match self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::Mitchell { radius }
+            | Filter::BlackmanHarris { radius } => *radius,
+        }
+    }
+
+    pub fn with_radius(self, radius: f64) -> Filter {
+        match self {
+            Filter::Box { .. } => Filter::Box { radius },
+            Filter::Tent { .. } => Filter::Tent { radius },
+            // Keep the shape of the bell the same relative to the radius
+            Filter::Gaussian { radius: old, sigma } => Filter::Gaussian {
+                radius,
+                sigma: sigma * radius / old,
+            },
+            Filter::Mitchell { .. } => Filter::Mitchell { radius },
+            Filter::BlackmanHarris { .. } => Filter::BlackmanHarris { radius },
+        }
+    }
+
+    /// Weight of a sample at offset (x, y) from a pixel center. All the filters are separable.
+    /// Mitchell-Netravali has negative lobes.
+    pub fn eval(&self, x: f64, y: f64) -> f64 {
+        let r = self.radius();
+        if x.abs() > r || y.abs() > r {
+            return 0.0;
+        }
+        let f = |t: f64| -> f64 {
+            match self {
+                Filter::Box { .. } => 1.0,
+                Filter::Tent { .. } => r - t.abs(),
+                Filter::Gaussian { sigma, .. } => {
+                    let g = |t: f64| (-t * t / (2.0 * sigma * sigma)).exp();
+                    (g(t) - g(r)).max(0.0)
+                }
+                Filter::Mitchell { .. } => mitchell_1d(2.0 * t / r),
+                Filter::BlackmanHarris { .. } => {
+                    let s = 0.5 * (t / r + 1.0);
+                    0.35875 - 0.48829 * (2.0 * PI * s).cos() + 0.14128 * (4.0 * PI * s).cos()
+                        - 0.01168 * (6.0 * PI * s).cos()
+                }
+            }
+        };
+        f(x) * f(y)
+    }
+}
+
+/// The Mitchell-Netravali cubic on [-2, 2]
+fn mitchell_1d(x: f64) -> f64 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let x = x.abs();
+    if x <= 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+            + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x <= 2.0 {
+        ((-b - 6.0 * c) * x * x * x
+            + (6.0 * b + 30.0 * c) * x * x
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,303 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use crate::vector3::*;
+
+/// Voxels of density, for clouds and smoke. Densities are at the voxel centers and get
+/// interpolated in between.
+#[derive(Debug, Clone)]
+pub struct Grid {
+    pub nx: usize,
+    pub ny: usize,
+    pub nz: usize,
+    /// x varies fastest, then y, then z
+    data: Vec<f32>,
+}
+
+impl Grid {
+    /// Loads a grid from a .vol file: a text header of "VOL", then the resolution along x, y and
+    /// z, separated by whitespace, then a single whitespace character and nx * ny * nz little
+    /// endian 32-bit floats, x varying fastest, then y, then z.
+    pub fn load(path: &str) -> std::io::Result<Grid> {
+        let bytes = fs::read(path)?;
+        let bad = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, msg));
+        let mut fields = vec![];
+        let mut pos = 0;
+        while fields.len() < 4 {
+            while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            let start = pos;
+            while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                return Err(bad("truncated header"));
+            }
+            fields.push(String::from_utf8_lossy(&bytes[start..pos]).to_string());
+        }
+        pos += 1;
+        if fields[0] != "VOL" {
+            return Err(bad("not a .vol grid"));
+        }
+        let num = |s: &str| s.parse::<usize>().map_err(|_| bad("invalid header"));
+        let (nx, ny, nz) = (num(&fields[1])?, num(&fields[2])?, num(&fields[3])?);
+        if nx == 0 || ny == 0 || nz == 0 {
+            return Err(bad("empty grid"));
+        }
+        // The size comes from the header, so it can be anything
+        let end = nx
+            .checked_mul(ny)
+            .and_then(|n| n.checked_mul(nz))
+            .and_then(|n| n.checked_mul(4))
+            .and_then(|n| n.checked_add(pos))
+            .ok_or_else(|| bad("invalid header"))?;
+        let mut data: Vec<f32> = bytes
+            .get(pos..end)
+            .ok_or_else(|| bad("truncated voxel data"))?
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        // Infinite density would make for an infinite majorant, which tracking never gets past
+        if data.iter().any(|d| !d.is_finite()) {
+            return Err(bad("densities must be finite"));
+        }
+        for d in data.iter_mut() {
+            *d = d.max(0.0);
+        }
+        Ok(Grid { nx, ny, nz, data })
+    }
+
+    /// A puffy cloud of fractal noise, n voxels across, densest in the middle and fading out
+    /// towards the sides of the grid.
+    pub fn cloud(n: usize) -> Grid {
+        let mut data = Vec::with_capacity(n * n * n);
+        for k in 0..n {
+            for j in 0..n {
+                for i in 0..n {
+                    let p = (1.0 / n as f64)
+                        * Vector3::new(i as f64 + 0.5, j as f64 + 0.5, k as f64 + 0.5);
+                    // Squashed vertically, and flatter at the bottom than at the top
+                    let c = p - Vector3::new(0.5, 0.35, 0.5);
+                    let r = Vector3::new(c.x, 1.6 * c.y.max(0.0) + 3.0 * c.y.min(0.0), c.z).norm();
+                    let mut noise = 0.0;
+                    let mut amplitude = 0.5;
+                    let mut frequency = 4.0;
+                    for _ in 0..5 {
+                        noise += amplitude * value_noise(frequency * p);
+                        amplitude *= 0.5;
+                        frequency *= 2.0;
+                    }
+                    let d = (noise + 0.45 - 1.2 * r) * 6.0;
+                    data.push(clamp(d, 0.0, 1.0) as f32);
+                }
+            }
+        }
+        Grid {
+            nx: n,
+            ny: n,
+            nz: n,
+            data,
+        }
+    }
+
+    fn voxel(&self, i: usize, j: usize, k: usize) -> f64 {
+        self.data[(k * self.ny + j) * self.nx + i] as f64
+    }
+
+    /// Trilinearly interpolated density at p, with the grid spanning [0, 1]^3.
+    pub fn lookup(&self, p: Vector3) -> f64 {
+        // Position between voxel centers, clamped to the edge voxels
+        let coord = |x: f64, n: usize| {
+            let x = clamp(x * n as f64 - 0.5, 0.0, n as f64 - 1.0);
+            let i = (x as usize).min(n.saturating_sub(2));
+            (i, (i + 1).min(n - 1), x - i as f64)
+        };
+        let (i0, i1, fx) = coord(p.x, self.nx);
+        let (j0, j1, fy) = coord(p.y, self.ny);
+        let (k0, k1, fz) = coord(p.z, self.nz);
+        let lerp = |a: f64, b: f64, t: f64| a + t * (b - a);
+        let at_k = |k: usize| {
+            lerp(
+                lerp(self.voxel(i0, j0, k), self.voxel(i1, j0, k), fx),
+                lerp(self.voxel(i0, j1, k), self.voxel(i1, j1, k), fx),
+                fy,
+            )
+        };
+        lerp(at_k(k0), at_k(k1), fz)
+    }
+
+    /// Largest voxel value that interpolation within [lo, hi] (in [0, 1]^3) can reach.
+    fn max_in(&self, lo: Vector3, hi: Vector3) -> f64 {
+        let range = |lo: f64, hi: f64, n: usize| {
+            let first = (lo * n as f64 - 0.5).floor().max(0.0) as usize;
+            let last = ((hi * n as f64 - 0.5).floor() + 1.0).max(0.0) as usize;
+            first.min(n - 1)..=last.min(n - 1)
+        };
+        let mut max = 0.0f64;
+        for k in range(lo.z, hi.z, self.nz) {
+            for j in range(lo.y, hi.y, self.ny) {
+                for i in range(lo.x, hi.x, self.nx) {
+                    max = max.max(self.voxel(i, j, k));
+                }
+            }
+        }
+        max
+    }
+}
+
+/// Smoothly interpolated random values at the integer lattice points.
+fn value_noise(p: Vector3) -> f64 {
+    let hash = |x: i64, y: i64, z: i64| {
+        let mut h = (x as u64).wrapping_mul(0x9e3779b97f4a7c15)
+            ^ (y as u64).wrapping_mul(0xc2b2ae3d27d4eb4f)
+            ^ (z as u64).wrapping_mul(0x165667b19e3779f9);
+        h ^= h >> 31;
+        h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+        h ^= h >> 29;
+        (h >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let smooth = |t: f64| t * t * (3.0 - 2.0 * t);
+    let (fx, fy, fz) = (smooth(p.x - x0), smooth(p.y - y0), smooth(p.z - z0));
+    let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+    let lerp = |a: f64, b: f64, t: f64| a + t * (b - a);
+    let at_z = |z: i64| {
+        lerp(
+            lerp(hash(x0, y0, z), hash(x0 + 1, y0, z), fx),
+            lerp(hash(x0, y0 + 1, z), hash(x0 + 1, y0 + 1, z), fx),
+            fy,
+        )
+    };
+    lerp(at_z(z0), at_z(z0 + 1), fz)
+}
+
+/// Resolution of the majorant grid along each axis
+const MAJORANT_RES: usize = 16;
+
+/// A density grid placed in the scene, filling an axis aligned box. A coarser grid keeps the
+/// most density within each of its cells, so that tracking can take long steps through the
+/// thin parts of the volume.
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+    pub bounds: Aabb,
+    grid: Grid,
+    majorants: Vec<f64>,
+}
+
+impl DensityGrid {
+    pub fn new(grid: Grid, bounds: Aabb) -> DensityGrid {
+        let n = MAJORANT_RES;
+        let mut majorants = Vec::with_capacity(n * n * n);
+        for k in 0..n {
+            for j in 0..n {
+                for i in 0..n {
+                    let lo = (1.0 / n as f64) * Vector3::new(i as f64, j as f64, k as f64);
+                    let hi = (1.0 / n as f64)
+                        * Vector3::new(i as f64 + 1.0, j as f64 + 1.0, k as f64 + 1.0);
+                    majorants.push(grid.max_in(lo, hi));
+                }
+            }
+        }
+        DensityGrid {
+            bounds,
+            grid,
+            majorants,
+        }
+    }
+
+    /// Density at the world space point p. Zero outside of the box.
+    pub fn density(&self, p: Vector3) -> f64 {
+        if !self.bounds.contains(p) {
+            return 0.0;
+        }
+        let d = self.bounds.diagonal();
+        let local = p - self.bounds.min;
+        self.grid
+            .lookup(Vector3::new(local.x / d.x, local.y / d.y, local.z / d.z))
+    }
+
+    /// The stretches of the ray within [0, t_max] that cross the cells of the majorant grid, in
+    /// order, as (start, end, majorant density).
+    pub fn segments(&self, origin: Vector3, dir: Vector3, t_max: f64) -> Segments<'_> {
+        let n = MAJORANT_RES as f64;
+        let d = self.bounds.diagonal();
+        let (t0, t1) = match self.bounds.intersect(origin, dir) {
+            Some((t0, t1)) => (t0.max(0.0), t1.min(t_max)),
+            None => (0.0, 0.0),
+        };
+        let start = origin + t0 * dir - self.bounds.min;
+        let (mut cell, mut step, mut next_t, mut delta_t) = ([0; 3], [0; 3], [0.0; 3], [0.0; 3]);
+        for (axis, ((p, dir), size)) in [start.x, start.y, start.z]
+            .into_iter()
+            .zip([dir.x, dir.y, dir.z])
+            .zip([d.x, d.y, d.z])
+            .enumerate()
+        {
+            let cell_size = size / n;
+            let c = clamp((p / cell_size).floor(), 0.0, n - 1.0);
+            cell[axis] = c as i64;
+            if dir > 0.0 {
+                step[axis] = 1;
+                next_t[axis] = t0 + ((c + 1.0) * cell_size - p) / dir;
+                delta_t[axis] = cell_size / dir;
+            } else if dir < 0.0 {
+                step[axis] = -1;
+                next_t[axis] = t0 + (c * cell_size - p) / dir;
+                delta_t[axis] = -cell_size / dir;
+            } else {
+                next_t[axis] = f64::INFINITY;
+                delta_t[axis] = f64::INFINITY;
+            }
+        }
+        Segments {
+            grid: self,
+            cell,
+            step,
+            next_t,
+            delta_t,
+            t: t0,
+            t_end: t1,
+        }
+    }
+
+    fn majorant(&self, cell: [i64; 3]) -> f64 {
+        let n = MAJORANT_RES;
+        let [i, j, k] = cell.map(|c| c as usize);
+        self.majorants[(k * n + j) * n + i]
+    }
+}
+
+/// Walks a ray through the majorant grid with a 3D DDA (Amanatides and Woo).
+pub struct Segments<'a> {
+    grid: &'a DensityGrid,
+    cell: [i64; 3],
+    step: [i64; 3],
+    /// Ray distance at which the next cell boundary along each axis is crossed
+    next_t: [f64; 3],
+    delta_t: [f64; 3],
+    t: f64,
+    t_end: f64,
+}
+
+impl Iterator for Segments<'_> {
+    type Item = (f64, f64, f64);
+
+    fn next(&mut self) -> Option<(f64, f64, f64)> {
+        if self.t >= self.t_end {
+            return None;
+        }
+        let axis = (0..3)
+            .min_by(|&a, &b| self.next_t[a].total_cmp(&self.next_t[b]))
+            .unwrap();
+        let end = self.next_t[axis].min(self.t_end);
+        let segment = (self.t, end, self.grid.majorant(self.cell));
+        self.t = end;
+        self.cell[axis] += self.step[axis];
+        self.next_t[axis] += self.delta_t[axis];
+        if !(0..MAJORANT_RES as i64).contains(&self.cell[axis]) {
+            self.t_end = self.t;
+        }
+        Some(segment)
+    }
+}
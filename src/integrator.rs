@@ -0,0 +1,184 @@
+use crate::bdpt;
+use crate::bvh;
+use crate::config::{BounceLimits, Config};
+use crate::debug::{AmbientOcclusion, Barycentric, BvhCost, Depth, Normals, Uv, WhiteFurnace};
+use crate::denoise::Features;
+use crate::film::{Film, PathRadiance};
+use crate::photons::{self, PhotonMap};
+use crate::sample_scene;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vector3::*;
+
+/// How the light reaching the camera gets found, or for the debug integrators, what gets shown
+/// instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegratorKind {
+    /// Paths from the camera, with light sampling at every bounce
+    Path,
+    /// Paths from the camera and from the lights, connected every which way
+    Bidirectional,
+    /// Camera paths that gather photons traced from the lights, with the radius they're gathered
+    /// in shrinking from pass to pass
+    PhotonMapping,
+    /// The path tracer, with its random numbers mutated by Markov chains
+    Metropolis,
+    /// How much of the hemisphere above the first hit is open, out to a distance
+    AmbientOcclusion,
+    /// Geometric normals, from [-1, 1] to [0, 1]
+    Normals,
+    /// Distance to the first hit
+    Depth,
+    /// Surface coordinates, u in red and v in green
+    Uv,
+    /// Vertex weights on triangles
+    Barycentric,
+    /// Nodes visited and primitives tested by the camera ray, as a heatmap
+    BvhCost,
+    /// Every surface white, under a white sky: materials that don't lose or make energy come
+    /// out as white as the sky
+    WhiteFurnace,
+}
+
+impl IntegratorKind {
+    pub fn by_name(name: &str) -> Option<IntegratorKind> {
+        match name {
+            "path" => Some(IntegratorKind::Path),
+            "bdpt" => Some(IntegratorKind::Bidirectional),
+            "ppm" => Some(IntegratorKind::PhotonMapping),
+            "mlt" => Some(IntegratorKind::Metropolis),
+            "ao" => Some(IntegratorKind::AmbientOcclusion),
+            "normals" => Some(IntegratorKind::Normals),
+            "depth" => Some(IntegratorKind::Depth),
+            "uv" => Some(IntegratorKind::Uv),
+            "barycentric" => Some(IntegratorKind::Barycentric),
+            "bvh-cost" => Some(IntegratorKind::BvhCost),
+            "furnace" => Some(IntegratorKind::WhiteFurnace),
+            _ => None,
+        }
+    }
+
+    /// True for the integrators for setting scenes up, which show something quick to render
+    /// about the surfaces the camera sees, rather than the light coming off them. They look
+    /// straight through the boundaries of media, and ignore the media themselves.
+    pub fn is_debug(&self) -> bool {
+        !matches!(
+            self,
+            IntegratorKind::Path
+                | IntegratorKind::Bidirectional
+                | IntegratorKind::PhotonMapping
+                | IntegratorKind::Metropolis
+        )
+    }
+
+    /// The integrator for a pass. Photon mapping passes bring the photons they traced.
+    pub fn create(&self, config: &Config, photons: Option<PhotonMap>) -> Box<dyn Integrator> {
+        match self {
+            // Metropolis chains mutate the paths of the path tracer
+            IntegratorKind::Path | IntegratorKind::Metropolis => Box::new(PathTracer {
+                bounces: config.bounces,
+            }),
+            IntegratorKind::Bidirectional => Box::new(Bidirectional {
+                min_depth: config.bounces.min_depth,
+            }),
+            IntegratorKind::PhotonMapping => Box::new(PhotonGather {
+                photons: photons.expect("photon mapping passes have photons"),
+            }),
+            IntegratorKind::AmbientOcclusion => Box::new(AmbientOcclusion {
+                distance: config.ao_distance,
+            }),
+            IntegratorKind::Normals => Box::new(Normals),
+            IntegratorKind::Depth => Box::new(Depth),
+            IntegratorKind::Uv => Box::new(Uv),
+            IntegratorKind::Barycentric => Box::new(Barycentric),
+            IntegratorKind::BvhCost => {
+                bvh::count_steps();
+                Box::new(BvhCost)
+            }
+            IntegratorKind::WhiteFurnace => Box::new(WhiteFurnace {
+                min_depth: config.bounces.min_depth,
+            }),
+        }
+    }
+}
+
+/// Finds the light that comes back along camera rays.
+pub trait Integrator: Sync {
+    /// The light arriving at the camera along the ray from origin in direction dir, at time.
+    /// What the ray hits first goes into features. Light that lands in other pixels, as light
+    /// paths can find, gets splatted into film.
+    #[allow(clippy::too_many_arguments)]
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        features: &mut Features,
+        film: &mut Film,
+    ) -> PathRadiance;
+}
+
+struct PathTracer {
+    bounces: BounceLimits,
+}
+impl Integrator for PathTracer {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        sample_scene(origin, dir, time, scene, &self.bounces, sampler, features)
+    }
+}
+
+struct Bidirectional {
+    min_depth: usize,
+}
+impl Integrator for Bidirectional {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        features: &mut Features,
+        film: &mut Film,
+    ) -> PathRadiance {
+        bdpt::sample_scene(
+            origin,
+            dir,
+            time,
+            scene,
+            self.min_depth,
+            sampler,
+            features,
+            film,
+        )
+    }
+}
+
+struct PhotonGather {
+    photons: PhotonMap,
+}
+impl Integrator for PhotonGather {
+    fn li(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        time: f64,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        features: &mut Features,
+        _film: &mut Film,
+    ) -> PathRadiance {
+        photons::sample_scene(origin, dir, time, scene, &self.photons, sampler, features)
+    }
+}
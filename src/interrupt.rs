@@ -0,0 +1,34 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once Ctrl-C is pressed
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Has Ctrl-C been pressed since install?
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Catches the first SIGINT, so that the render can stop cleanly. A second one kills the
+/// program as usual.
+#[cfg(unix)]
+pub fn install() {
+    const SIGINT: i32 = 2;
+    const SIG_DFL: usize = 0;
+    extern "C" {
+        // From libc, which std links with anyway
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+    extern "C" fn on_sigint(_: i32) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        // signal is async-signal-safe
+        unsafe {
+            signal(SIGINT, SIG_DFL);
+        }
+    }
+    unsafe {
+        signal(SIGINT, on_sigint as extern "C" fn(i32) as usize);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
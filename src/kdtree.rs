@@ -0,0 +1,81 @@
+use crate::vector3::*;
+
+/// Balanced kd-tree over a list of points, for finding the ones near a position. It's stored
+/// implicitly: every range of points has its median (along the axis it's split on) in the
+/// middle, the points before it on one side of the split, and those after it on the other.
+pub struct KdTree {
+    /// Point positions, and their indices in the list the tree was built from
+    points: Vec<(Vector3, usize)>,
+    /// The axis the range around each point is split on
+    axes: Vec<usize>,
+}
+
+impl KdTree {
+    pub fn new(points: &[Vector3]) -> KdTree {
+        let mut tree = KdTree {
+            points: points.iter().copied().zip(0..).collect(),
+            axes: vec![0; points.len()],
+        };
+        tree.build(0, points.len());
+        tree
+    }
+
+    /// Splits the range along its longest axis, recursively.
+    fn build(&mut self, start: usize, end: usize) {
+        if end - start <= 1 {
+            return;
+        }
+        let bounds = self.points[start..end]
+            .iter()
+            .fold(Aabb::empty(), |b, (p, _)| b.union(&Aabb::point(*p)));
+        let d = bounds.diagonal();
+        let axis = if d.x >= d.y && d.x >= d.z {
+            0
+        } else if d.y >= d.z {
+            1
+        } else {
+            2
+        };
+        let mid = (start + end) / 2;
+        self.points[start..end].select_nth_unstable_by(mid - start, |a, b| {
+            coord(a.0, axis).total_cmp(&coord(b.0, axis))
+        });
+        self.axes[mid] = axis;
+        self.build(start, mid);
+        self.build(mid + 1, end);
+    }
+
+    /// Calls found with the index of every point within radius of p.
+    pub fn within(&self, p: Vector3, radius: f64, mut found: impl FnMut(usize)) {
+        let r2 = radius * radius;
+        let mut stack = vec![(0, self.points.len())];
+        while let Some((start, end)) = stack.pop() {
+            if start == end {
+                continue;
+            }
+            let mid = (start + end) / 2;
+            let (q, index) = self.points[mid];
+            if (q - p) * (q - p) <= r2 {
+                found(index);
+            }
+            if end - start == 1 {
+                continue;
+            }
+            let d = coord(p, self.axes[mid]) - coord(q, self.axes[mid]);
+            // The side p is on first, the other one only if the sphere reaches across
+            let (near, far) = if d < 0.0 {
+                ((start, mid), (mid + 1, end))
+            } else {
+                ((mid + 1, end), (start, mid))
+            };
+            if d * d <= r2 {
+                stack.push(far);
+            }
+            stack.push(near);
+        }
+    }
+}
+
+fn coord(p: Vector3, axis: usize) -> f64 {
+    [p.x, p.y, p.z][axis]
+}
@@ -0,0 +1,549 @@
+use std::f64::consts::PI;
+
+use crate::vector3::*;
+
+/// A light, as seen from a point being shaded.
+pub struct LightSample {
+    /// Direction from the shading point towards the light
+    pub wi: Vector3,
+    /// Distance to the sampled point on the light. f64::MAX for directional lights.
+    pub dist: f64,
+    /// Incident radiance. For delta lights, the incident irradiance instead.
+    pub li: Vector3,
+    /// Solid angle pdf of wi. 1 for delta lights.
+    pub pdf: f64,
+}
+
+/// Light leaving a light, for tracing paths from the lights.
+pub struct EmissionSample {
+    pub pos: Vector3,
+    /// Surface normal at pos, zero for point lights
+    pub normal: Vector3,
+    pub dir: Vector3,
+    /// Radiance along dir, or intensity for point lights
+    pub le: Vector3,
+    /// Area pdf of pos (1 for point lights), and solid angle pdf of dir
+    pub pdf_pos: f64,
+    pub pdf_dir: f64,
+}
+
+/// The emitting surface of an area light. All shapes only emit on the side their normal faces.
+#[derive(Debug, Clone)]
+pub enum AreaShape {
+    /// Rectangle spanned by the (perpendicular) edges u and v. Emits towards u x v.
+    Rect {
+        corner: Vector3,
+        u: Vector3,
+        v: Vector3,
+    },
+    Disk {
+        center: Vector3,
+        normal: Vector3,
+        radius: f64,
+    },
+    /// Emits towards (b - a) x (c - a), the side triangles are visible from.
+    Triangle { a: Vector3, b: Vector3, c: Vector3 },
+    /// Emits outwards
+    Sphere { center: Vector3, radius: f64 },
+}
+impl AreaShape {
+    pub fn area(&self) -> f64 {
+        match self {
+            AreaShape::Rect { u, v, .. } => u.cross(*v).norm(),
+            AreaShape::Disk { radius, .. } => PI * radius * radius,
+            AreaShape::Triangle { a, b, c } => 0.5 * (*b - *a).cross(*c - *a).norm(),
+            AreaShape::Sphere { radius, .. } => 4.0 * PI * radius * radius,
+        }
+    }
+    /// Surface normal at the point p on the shape.
+    pub fn normal(&self, p: Vector3) -> Vector3 {
+        match self {
+            AreaShape::Rect { u, v, .. } => u.cross(*v).normalize(),
+            AreaShape::Disk { normal, .. } => *normal,
+            AreaShape::Triangle { a, b, c } => (*b - *a).cross(*c - *a).normalize(),
+            AreaShape::Sphere { center, .. } => (p - *center).normalize(),
+        }
+    }
+    pub fn bounds(&self) -> Aabb {
+        match self {
+            AreaShape::Rect { corner, u, v } => Aabb::point(*corner)
+                .union(&Aabb::point(*corner + *u))
+                .union(&Aabb::point(*corner + *v))
+                .union(&Aabb::point(*corner + *u + *v)),
+            AreaShape::Disk {
+                center,
+                normal,
+                radius,
+            } => {
+                // Extent of the disk along each axis
+                let e = |n: f64| radius * (1.0 - n * n).max(0.0).sqrt();
+                let r = Vector3::new(e(normal.x), e(normal.y), e(normal.z));
+                Aabb {
+                    min: *center - r,
+                    max: *center + r,
+                }
+            }
+            AreaShape::Triangle { a, b, c } => Aabb::point(*a)
+                .union(&Aabb::point(*b))
+                .union(&Aabb::point(*c)),
+            AreaShape::Sphere { center, radius } => {
+                let r = Vector3::new(*radius, *radius, *radius);
+                Aabb {
+                    min: *center - r,
+                    max: *center + r,
+                }
+            }
+        }
+    }
+    /// Uniformly distributed point on the shape
+    fn sample_area(&self, u: [f64; 2]) -> Vector3 {
+        match self {
+            AreaShape::Rect {
+                corner,
+                u: edge_u,
+                v: edge_v,
+            } => *corner + u[0] * *edge_u + u[1] * *edge_v,
+            AreaShape::Disk {
+                center,
+                normal,
+                radius,
+            } => {
+                let (x, y) = concentric_disk(u);
+                *center + *radius * normal.local_to_world(Vector3::new(x, y, 0.0))
+            }
+            AreaShape::Triangle { a, b, c } => {
+                let su = u[0].sqrt();
+                let (b0, b1) = (1.0 - su, u[1] * su);
+                b0 * *a + b1 * *b + (1.0 - b0 - b1) * *c
+            }
+            AreaShape::Sphere { center, radius } => *center + *radius * uniform_cone(u, -1.0),
+        }
+    }
+    /// For spheres seen from outside: the cosine of the half angle the sphere subtends from p.
+    fn sphere_cone(&self, p: Vector3) -> Option<f64> {
+        match self {
+            AreaShape::Sphere { center, radius } => {
+                let d2 = (*center - p) * (*center - p);
+                if d2 <= radius * radius {
+                    None
+                } else {
+                    Some((1.0 - radius * radius / d2).max(0.0).sqrt())
+                }
+            }
+            _ => None,
+        }
+    }
+    /// Samples a direction from p towards the shape. Returns (direction, distance, solid angle pdf).
+    pub fn sample(&self, p: Vector3, u: [f64; 2]) -> Option<(Vector3, f64, f64)> {
+        if let Some(cos_max) = self.sphere_cone(p) {
+            // Only sample the part of the sphere visible from p
+            let AreaShape::Sphere { center, .. } = self else {
+                unreachable!()
+            };
+            let axis = (*center - p).normalize();
+            let wi = axis.local_to_world(uniform_cone(u, cos_max));
+            let dist = self.intersect(p, wi).unwrap_or((*center - p).norm());
+            return Some((wi, dist, 1.0 / (2.0 * PI * (1.0 - cos_max))));
+        }
+        let to_light = self.sample_area(u) - p;
+        let dist = to_light.norm();
+        let wi = (1.0 / dist) * to_light;
+        let cos_light = -wi * self.normal(p + to_light);
+        if cos_light <= 0.0 {
+            return None;
+        }
+        Some((wi, dist, dist * dist / (cos_light * self.area())))
+    }
+    /// Solid angle pdf of sample() returning wi.
+    pub fn pdf(&self, p: Vector3, wi: Vector3) -> f64 {
+        if let Some(cos_max) = self.sphere_cone(p) {
+            let AreaShape::Sphere { center, .. } = self else {
+                unreachable!()
+            };
+            return if wi * (*center - p).normalize() >= cos_max {
+                1.0 / (2.0 * PI * (1.0 - cos_max))
+            } else {
+                0.0
+            };
+        }
+        match self.intersect(p, wi) {
+            Some(dist) => {
+                let cos_light = -wi * self.normal(p + dist * wi);
+                if cos_light <= 0.0 {
+                    0.0
+                } else {
+                    dist * dist / (cos_light * self.area())
+                }
+            }
+            None => 0.0,
+        }
+    }
+    /// Ray-shape intersection, from either side. Returns the depth of the intersection.
+    pub fn intersect(&self, origin: Vector3, dir: Vector3) -> Option<f64> {
+        match self {
+            AreaShape::Triangle { a, b, c } => {
+                // Two-sided Möller–Trumbore
+                let (ab, ac) = (*b - *a, *c - *a);
+                let d_cross_ac = dir.cross(ac);
+                let det = ab * d_cross_ac;
+                if det > -f64::EPSILON && det < f64::EPSILON {
+                    return None;
+                }
+                let inv_det = 1.0 / det;
+                let s = origin - *a;
+                let u = inv_det * (s * d_cross_ac);
+                let s_cross_ab = s.cross(ab);
+                let v = inv_det * (dir * s_cross_ab);
+                let t = inv_det * (ac * s_cross_ab);
+                if u < 0.0 || v < 0.0 || u + v > 1.0 || t <= f64::EPSILON {
+                    None
+                } else {
+                    Some(t)
+                }
+            }
+            AreaShape::Sphere { center, radius } => {
+                let l = *center - origin;
+                let tca = l * dir;
+                let d2 = l * l - tca * tca;
+                if d2 > radius * radius {
+                    return None;
+                }
+                let thc = (radius * radius - d2).sqrt();
+                [tca - thc, tca + thc]
+                    .into_iter()
+                    .find(|t| *t > f64::EPSILON)
+            }
+            AreaShape::Rect { corner: anchor, .. } | AreaShape::Disk { center: anchor, .. } => {
+                let n = self.normal(*anchor);
+                let dotpr = dir * n;
+                if dotpr > -f64::EPSILON && dotpr < f64::EPSILON {
+                    return None; // Parallel to the plane of the shape
+                }
+                let t = ((*anchor - origin) * n) / dotpr;
+                if t <= f64::EPSILON {
+                    return None;
+                }
+                let local = origin + t * dir - *anchor;
+                let inside = match self {
+                    AreaShape::Rect { u, v, .. } => {
+                        let a = (local * *u) / (*u * *u);
+                        let b = (local * *v) / (*v * *v);
+                        (0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b)
+                    }
+                    AreaShape::Disk { radius, .. } => local * local <= radius * radius,
+                    _ => unreachable!(),
+                };
+                if inside {
+                    Some(t)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Light {
+    Point {
+        pos: Vector3,
+        intensity: Vector3,
+    },
+    /// A point light that only shines into a cone, fading out between the inner and outer angles.
+    Spot {
+        pos: Vector3,
+        dir: Vector3,
+        intensity: Vector3,
+        cos_inner: f64,
+        cos_outer: f64,
+    },
+    /// Light from a very distant source, like the sun. dir is the direction the light travels in.
+    /// A non-zero angular diameter makes the source a small disk in the sky, with soft shadows.
+    Directional {
+        dir: Vector3,
+        irradiance: Vector3,
+        cos_max: f64,
+    },
+    Area {
+        shape: AreaShape,
+        radiance: Vector3,
+    },
+}
+impl Light {
+    pub fn point(pos: Vector3, intensity: Vector3) -> Light {
+        Light::Point { pos, intensity }
+    }
+    /// Angles in degrees, measured from the axis of the cone.
+    pub fn spot(
+        pos: Vector3,
+        target: Vector3,
+        intensity: Vector3,
+        inner: f64,
+        outer: f64,
+    ) -> Light {
+        Light::Spot {
+            pos,
+            dir: (target - pos).normalize(),
+            intensity,
+            cos_inner: inner.to_radians().cos(),
+            cos_outer: outer.to_radians().cos(),
+        }
+    }
+    /// irradiance is measured on a surface facing the sun. Angular diameter in degrees.
+    pub fn sun(dir: Vector3, irradiance: Vector3, angular_diameter: f64) -> Light {
+        Light::Directional {
+            dir: dir.normalize(),
+            irradiance,
+            cos_max: (0.5 * angular_diameter).to_radians().cos(),
+        }
+    }
+    pub fn rect(corner: Vector3, u: Vector3, v: Vector3, radiance: Vector3) -> Light {
+        Light::Area {
+            shape: AreaShape::Rect { corner, u, v },
+            radiance,
+        }
+    }
+    pub fn disk(center: Vector3, normal: Vector3, radius: f64, radiance: Vector3) -> Light {
+        Light::Area {
+            shape: AreaShape::Disk {
+                center,
+                normal: normal.normalize(),
+                radius,
+            },
+            radiance,
+        }
+    }
+
+    /// Transforms the light's color, e.g. into another color space.
+    pub fn map_color(&mut self, f: impl Fn(Vector3) -> Vector3) {
+        match self {
+            Light::Point { intensity, .. } | Light::Spot { intensity, .. } => {
+                *intensity = f(*intensity)
+            }
+            Light::Directional { irradiance, .. } => *irradiance = f(*irradiance),
+            Light::Area { radiance, .. } => *radiance = f(*radiance),
+        }
+    }
+
+    /// Total emitted power, averaged over the color channels. Directional lights cover the
+    /// scene, whose bounding sphere has radius scene_radius.
+    pub fn power(&self, scene_radius: f64) -> f64 {
+        let avg = |c: &Vector3| (c.x + c.y + c.z) / 3.0;
+        match self {
+            Light::Point { intensity, .. } => 4.0 * PI * avg(intensity),
+            Light::Spot {
+                intensity,
+                cos_inner,
+                cos_outer,
+                ..
+            } => 2.0 * PI * avg(intensity) * (1.0 - 0.5 * (cos_inner + cos_outer)),
+            Light::Directional { irradiance, .. } => {
+                PI * scene_radius * scene_radius * avg(irradiance)
+            }
+            Light::Area { shape, radiance } => PI * avg(radiance) * shape.area(),
+        }
+    }
+
+    /// Delta lights can only be reached by sampling them, never by a ray hitting them.
+    pub fn is_delta(&self) -> bool {
+        match self {
+            Light::Point { .. } | Light::Spot { .. } => true,
+            Light::Directional { cos_max, .. } => *cos_max >= 1.0,
+            Light::Area { .. } => false,
+        }
+    }
+
+    /// Samples a direction from p towards the light.
+    pub fn sample_li(&self, p: Vector3, u: [f64; 2]) -> Option<LightSample> {
+        match self {
+            Light::Point { pos, intensity } => {
+                let to_light = *pos - p;
+                let dist = to_light.norm();
+                Some(LightSample {
+                    wi: (1.0 / dist) * to_light,
+                    dist,
+                    li: (1.0 / (dist * dist)) * *intensity,
+                    pdf: 1.0,
+                })
+            }
+            Light::Spot {
+                pos,
+                dir,
+                intensity,
+                cos_inner,
+                cos_outer,
+            } => {
+                let to_light = *pos - p;
+                let dist = to_light.norm();
+                let wi = (1.0 / dist) * to_light;
+                let falloff = smoothstep(*cos_outer, *cos_inner, -wi * *dir);
+                if falloff <= 0.0 {
+                    return None;
+                }
+                Some(LightSample {
+                    wi,
+                    dist,
+                    li: (falloff / (dist * dist)) * *intensity,
+                    pdf: 1.0,
+                })
+            }
+            Light::Directional {
+                dir,
+                irradiance,
+                cos_max,
+            } => {
+                if *cos_max >= 1.0 {
+                    return Some(LightSample {
+                        wi: -*dir,
+                        dist: f64::MAX,
+                        li: *irradiance,
+                        pdf: 1.0,
+                    });
+                }
+                let solid_angle = 2.0 * PI * (1.0 - cos_max);
+                Some(LightSample {
+                    wi: (-*dir).local_to_world(uniform_cone(u, *cos_max)),
+                    dist: f64::MAX,
+                    li: (1.0 / solid_angle) * *irradiance,
+                    pdf: 1.0 / solid_angle,
+                })
+            }
+            Light::Area { shape, radiance } => {
+                let (wi, dist, pdf) = shape.sample(p, u)?;
+                Some(LightSample {
+                    wi,
+                    dist,
+                    li: *radiance,
+                    pdf,
+                })
+            }
+        }
+    }
+
+    /// Lights at infinity, which light can't be traced from.
+    pub fn is_infinite(&self) -> bool {
+        matches!(self, Light::Directional { .. })
+    }
+
+    /// Samples light leaving the light: a point on it, and a direction. The point is uniformly
+    /// distributed over the surface of area lights, and the direction cosine weighted.
+    pub fn sample_le(&self, u_pos: [f64; 2], u_dir: [f64; 2]) -> Option<EmissionSample> {
+        let zero = Vector3::new(0.0, 0.0, 0.0);
+        match self {
+            Light::Point { pos, intensity } => Some(EmissionSample {
+                pos: *pos,
+                normal: zero,
+                dir: uniform_cone(u_dir, -1.0),
+                le: *intensity,
+                pdf_pos: 1.0,
+                pdf_dir: 1.0 / (4.0 * PI),
+            }),
+            Light::Spot {
+                pos,
+                dir,
+                intensity,
+                cos_inner,
+                cos_outer,
+            } => {
+                let w = dir.local_to_world(uniform_cone(u_dir, *cos_outer));
+                let falloff = smoothstep(*cos_outer, *cos_inner, w * *dir);
+                if falloff <= 0.0 {
+                    return None;
+                }
+                Some(EmissionSample {
+                    pos: *pos,
+                    normal: zero,
+                    dir: w,
+                    le: falloff * *intensity,
+                    pdf_pos: 1.0,
+                    pdf_dir: 1.0 / (2.0 * PI * (1.0 - cos_outer)),
+                })
+            }
+            Light::Directional { .. } => None,
+            Light::Area { shape, radiance } => {
+                let pos = shape.sample_area(u_pos);
+                let normal = shape.normal(pos);
+                let local = cosine_hemisphere(u_dir);
+                Some(EmissionSample {
+                    pos,
+                    normal,
+                    dir: normal.local_to_world(local),
+                    le: *radiance,
+                    pdf_pos: 1.0 / shape.area(),
+                    pdf_dir: local.z / PI,
+                })
+            }
+        }
+    }
+
+    /// Pdfs of sample_le returning a point with the given normal, and the direction dir: (area
+    /// pdf of the point, solid angle pdf of the direction).
+    pub fn pdf_le(&self, normal: Vector3, dir: Vector3) -> (f64, f64) {
+        match self {
+            Light::Point { .. } => (1.0, 1.0 / (4.0 * PI)),
+            Light::Spot {
+                dir: axis,
+                cos_outer,
+                ..
+            } => {
+                if dir * *axis >= *cos_outer {
+                    (1.0, 1.0 / (2.0 * PI * (1.0 - cos_outer)))
+                } else {
+                    (1.0, 0.0)
+                }
+            }
+            Light::Directional { .. } => (0.0, 0.0),
+            Light::Area { shape, .. } => (1.0 / shape.area(), (dir * normal).max(0.0) / PI),
+        }
+    }
+
+    /// Solid angle pdf of sample_li returning wi. Zero for delta lights.
+    pub fn pdf_li(&self, p: Vector3, wi: Vector3) -> f64 {
+        match self {
+            Light::Directional { dir, cos_max, .. } if *cos_max < 1.0 && -wi * *dir >= *cos_max => {
+                1.0 / (2.0 * PI * (1.0 - cos_max))
+            }
+            Light::Area { shape, .. } => shape.pdf(p, wi),
+            _ => 0.0,
+        }
+    }
+
+    /// Radiance emitted towards a ray with direction dir that hit the (area) light where the
+    /// surface normal is normal.
+    pub fn emitted(&self, dir: Vector3, normal: Vector3) -> Vector3 {
+        match self {
+            Light::Area { radiance, .. } if dir * normal < 0.0 => *radiance,
+            _ => Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Radiance arriving along a ray with direction dir that escaped the scene.
+    pub fn escaped(&self, dir: Vector3) -> Vector3 {
+        match self {
+            Light::Directional {
+                dir: light_dir,
+                irradiance,
+                cos_max,
+            } if *cos_max < 1.0 && -dir * *light_dir >= *cos_max => {
+                (1.0 / (2.0 * PI * (1.0 - cos_max))) * *irradiance
+            }
+            _ => Vector3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Depth at which a ray hits the light, if the light has a surface.
+    pub fn intersect(&self, origin: Vector3, dir: Vector3) -> Option<f64> {
+        match self {
+            Light::Area { shape, .. } => shape.intersect(origin, dir),
+            _ => None,
+        }
+    }
+}
+
+fn smoothstep(a: f64, b: f64, x: f64) -> f64 {
+    if a == b {
+        return if x < a { 0.0 } else { 1.0 };
+    }
+    let t = clamp((x - a) / (b - a), 0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
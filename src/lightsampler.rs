@@ -0,0 +1,471 @@
+use crate::light::{AreaShape, Light};
+use crate::vector3::*;
+
+/// How lights get picked for next event estimation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightSampling {
+    /// Every light is equally likely
+    Uniform,
+    /// Proportional to the power of the light, through an alias table. Lights at infinity get
+    /// a fixed share, as in the BVH.
+    Power,
+    /// Proportional to an estimate of the light's contribution at the shading point, through a
+    /// BVH over the lights
+    Bvh,
+}
+
+pub enum LightSampler {
+    Uniform(usize),
+    Power(AliasTable),
+    Bvh(LightBvh),
+}
+impl LightSampler {
+    pub fn new(kind: LightSampling, lights: &[Light]) -> LightSampler {
+        match kind {
+            LightSampling::Uniform => LightSampler::Uniform(lights.len()),
+            LightSampling::Power => {
+                // The power of a light at infinity depends on how big the scene is, and would
+                // crowd out the small lights, so those only rank among themselves
+                let infinite = lights.iter().filter(|l| l.is_infinite()).count();
+                let bounded: f64 = lights
+                    .iter()
+                    .filter(|l| !l.is_infinite())
+                    .map(|l| l.power(0.0))
+                    .sum();
+                let p_inf = infinite_share(infinite, bounded > 0.0);
+                let powers: Vec<f64> = lights
+                    .iter()
+                    .map(|l| {
+                        if l.is_infinite() {
+                            p_inf / infinite as f64
+                        } else if bounded > 0.0 {
+                            (1.0 - p_inf) * l.power(0.0) / bounded
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect();
+                LightSampler::Power(AliasTable::new(&powers))
+            }
+            LightSampling::Bvh => LightSampler::Bvh(LightBvh::new(lights)),
+        }
+    }
+
+    /// Picks a light for the shading point p with normal n (zero if there is no surface).
+    /// Returns (light index, probability of picking it).
+    pub fn sample(&self, p: Vector3, n: Vector3, u: f64) -> Option<(usize, f64)> {
+        match self {
+            LightSampler::Uniform(0) => None,
+            LightSampler::Uniform(count) => {
+                let index = ((u * *count as f64) as usize).min(count - 1);
+                Some((index, 1.0 / *count as f64))
+            }
+            LightSampler::Power(table) => table.sample(u),
+            LightSampler::Bvh(bvh) => bvh.sample(p, n, u),
+        }
+    }
+
+    /// Probability of sample() picking the light with the given index.
+    pub fn pmf(&self, p: Vector3, n: Vector3, light: usize) -> f64 {
+        match self {
+            LightSampler::Uniform(count) => 1.0 / *count as f64,
+            LightSampler::Power(table) => table.pmf(light),
+            LightSampler::Bvh(bvh) => bvh.pmf(p, n, light),
+        }
+    }
+}
+
+/// Probability of picking one of the lights at infinity, rather than one of the others: all of
+/// them together get as much of a chance as all the others together.
+fn infinite_share(infinite: usize, others: bool) -> f64 {
+    let others = if others { 1.0 } else { 0.0 };
+    if infinite == 0 {
+        0.0
+    } else {
+        infinite as f64 / (infinite as f64 + others)
+    }
+}
+
+/// Walker's alias method, for picking from a discrete distribution in constant time.
+/// Built with Vose's algorithm.
+pub struct AliasTable {
+    bins: Vec<AliasBin>,
+}
+struct AliasBin {
+    /// Probability of staying in this bin, rather than going to the alias
+    q: f64,
+    /// Probability of the bin's own item being picked
+    p: f64,
+    alias: usize,
+}
+impl AliasTable {
+    /// Weights need not be normalized. If they are all zero, every item is equally likely.
+    pub fn new(weights: &[f64]) -> AliasTable {
+        let n = weights.len();
+        let sum: f64 = weights.iter().sum();
+        let p: Vec<f64> = if sum > 0.0 {
+            weights.iter().map(|w| w / sum).collect()
+        } else {
+            vec![1.0 / n as f64; n]
+        };
+        let mut bins: Vec<AliasBin> = p
+            .iter()
+            .map(|p| AliasBin {
+                q: p * n as f64,
+                p: *p,
+                alias: 0,
+            })
+            .collect();
+        let (mut under, mut over): (Vec<usize>, Vec<usize>) =
+            (0..n).partition(|i| bins[*i].q < 1.0);
+        while let (Some(u), Some(o)) = (under.pop(), over.pop()) {
+            // Fill the rest of the underfull bin from the overfull one
+            bins[u].alias = o;
+            bins[o].q -= 1.0 - bins[u].q;
+            if bins[o].q < 1.0 {
+                under.push(o);
+            } else {
+                over.push(o);
+            }
+        }
+        // Whatever is left over is (up to rounding error) exactly full
+        for i in under.into_iter().chain(over) {
+            bins[i].q = 1.0;
+        }
+        AliasTable { bins }
+    }
+    /// Returns (index, probability of the index)
+    pub fn sample(&self, u: f64) -> Option<(usize, f64)> {
+        let n = self.bins.len();
+        if n == 0 {
+            return None;
+        }
+        let offset = ((u * n as f64) as usize).min(n - 1);
+        let up = u * n as f64 - offset as f64;
+        let index = if up < self.bins[offset].q {
+            offset
+        } else {
+            self.bins[offset].alias
+        };
+        Some((index, self.bins[index].p))
+    }
+    pub fn pmf(&self, index: usize) -> f64 {
+        self.bins[index].p
+    }
+}
+
+/// A cone of directions around w. cos_theta = -1 is the whole sphere.
+#[derive(Debug, Clone, Copy)]
+struct DirectionCone {
+    w: Vector3,
+    cos_theta: f64,
+}
+impl DirectionCone {
+    fn entire_sphere() -> DirectionCone {
+        DirectionCone {
+            w: Vector3::new(0.0, 0.0, 1.0),
+            cos_theta: -1.0,
+        }
+    }
+    /// Smallest cone containing both cones. From pbrt-v4.
+    fn union(&self, other: &DirectionCone) -> DirectionCone {
+        let theta_a = self.cos_theta.acos();
+        let theta_b = other.cos_theta.acos();
+        let theta_d = clamp(self.w * other.w, -1.0, 1.0).acos();
+        if (theta_d + theta_b).min(std::f64::consts::PI) <= theta_a {
+            return *self;
+        }
+        if (theta_d + theta_a).min(std::f64::consts::PI) <= theta_b {
+            return *other;
+        }
+        let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+        if theta_o >= std::f64::consts::PI {
+            return DirectionCone::entire_sphere();
+        }
+        let axis = self.w.cross(other.w);
+        if axis * axis == 0.0 {
+            return DirectionCone::entire_sphere();
+        }
+        // Rotate self.w towards other.w (Rodrigues' formula)
+        let k = axis.normalize();
+        let theta_r = theta_o - theta_a;
+        let w = theta_r.cos() * self.w
+            + theta_r.sin() * k.cross(self.w)
+            + ((k * self.w) * (1.0 - theta_r.cos())) * k;
+        DirectionCone {
+            w: w.normalize(),
+            cos_theta: theta_o.cos(),
+        }
+    }
+}
+
+/// Where a (group of) light(s) is, how much it emits and into which directions.
+/// Emission is into directions within theta_o + theta_e of w, after the "Importance sampling of
+/// many lights with adaptive tree splitting" paper and pbrt-v4.
+#[derive(Debug, Clone, Copy)]
+struct LightBounds {
+    bounds: Aabb,
+    phi: f64,
+    /// Spread of the normals
+    cone: DirectionCone,
+    /// Spread of emission around each normal
+    cos_theta_e: f64,
+}
+impl LightBounds {
+    /// None for lights that are not located anywhere, like the sun.
+    fn of(light: &Light) -> Option<LightBounds> {
+        let phi = light.power(0.0);
+        match light {
+            Light::Point { pos, .. } => Some(LightBounds {
+                bounds: Aabb::point(*pos),
+                phi,
+                cone: DirectionCone::entire_sphere(),
+                cos_theta_e: 0.0,
+            }),
+            Light::Spot {
+                pos,
+                dir,
+                cos_inner,
+                cos_outer,
+                ..
+            } => Some(LightBounds {
+                bounds: Aabb::point(*pos),
+                phi,
+                cone: DirectionCone {
+                    w: *dir,
+                    cos_theta: *cos_inner,
+                },
+                cos_theta_e: (cos_outer.acos() - cos_inner.acos()).cos(),
+            }),
+            Light::Directional { .. } => None,
+            Light::Area { shape, .. } => Some(LightBounds {
+                bounds: shape.bounds(),
+                phi,
+                cone: match shape {
+                    AreaShape::Sphere { .. } => DirectionCone::entire_sphere(),
+                    _ => DirectionCone {
+                        w: shape.normal(shape.bounds().center()),
+                        cos_theta: 1.0,
+                    },
+                },
+                cos_theta_e: 0.0,
+            }),
+        }
+    }
+
+    fn union(&self, other: &LightBounds) -> LightBounds {
+        if self.phi == 0.0 {
+            return *other;
+        }
+        if other.phi == 0.0 {
+            return *self;
+        }
+        LightBounds {
+            bounds: self.bounds.union(&other.bounds),
+            phi: self.phi + other.phi,
+            cone: self.cone.union(&other.cone),
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+        }
+    }
+
+    /// Conservative estimate of how much the lights contribute at p, with surface normal n.
+    fn importance(&self, p: Vector3, n: Vector3) -> f64 {
+        // cos(max(0, a - b)), and sin of the same
+        fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+            if cos_a > cos_b {
+                1.0
+            } else {
+                cos_a * cos_b + sin_a * sin_b
+            }
+        }
+        fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+            if cos_a > cos_b {
+                0.0
+            } else {
+                sin_a * cos_b - cos_a * sin_b
+            }
+        }
+        let sin_of = |cos: f64| (1.0 - cos * cos).max(0.0).sqrt();
+
+        let (center, radius) = self.bounds.bounding_sphere();
+        let to_p = p - center;
+        let d2 = (to_p * to_p).max(radius * radius);
+        let cos_w = if to_p * to_p > 0.0 {
+            self.cone.w * to_p.normalize()
+        } else {
+            1.0
+        };
+        // Angle the bounds subtend from p
+        let cos_b = if self.bounds.contains(p) || to_p * to_p <= radius * radius {
+            -1.0
+        } else {
+            (1.0 - radius * radius / (to_p * to_p)).max(0.0).sqrt()
+        };
+        let (sin_w, sin_b, sin_o) = (sin_of(cos_w), sin_of(cos_b), sin_of(self.cone.cos_theta));
+        // Smallest angle between the direction to p and any emission direction
+        let cos_x = cos_sub_clamped(sin_w, cos_w, sin_o, self.cone.cos_theta);
+        let sin_x = sin_sub_clamped(sin_w, cos_w, sin_o, self.cone.cos_theta);
+        let cos_p = cos_sub_clamped(sin_x, cos_x, sin_b, cos_b);
+        if cos_p <= self.cos_theta_e {
+            return 0.0;
+        }
+        let mut importance = self.phi * cos_p / d2;
+        if n * n > 0.0 && to_p * to_p > 0.0 {
+            let cos_i = (to_p.normalize() * n).abs();
+            importance *= cos_sub_clamped(sin_of(cos_i), cos_i, sin_b, cos_b);
+        }
+        importance.max(0.0)
+    }
+}
+
+enum LightBvhNode {
+    Leaf(usize),
+    Interior([usize; 2]),
+}
+
+/// Bounding volume hierarchy over the lights, used to pick lights roughly in proportion to
+/// their contribution at the shading point. Lights without a position are picked separately.
+pub struct LightBvh {
+    nodes: Vec<(LightBounds, LightBvhNode)>,
+    infinite: Vec<usize>,
+    /// For each light, the path from the root to its leaf. Bit i set means take the second child
+    /// at depth i. None for lights that aren't in the tree.
+    trails: Vec<Option<u64>>,
+}
+impl LightBvh {
+    pub fn new(lights: &[Light]) -> LightBvh {
+        let mut bvh = LightBvh {
+            nodes: vec![],
+            infinite: vec![],
+            trails: vec![None; lights.len()],
+        };
+        let mut bounded = vec![];
+        for (i, l) in lights.iter().enumerate() {
+            match LightBounds::of(l) {
+                Some(b) if b.phi > 0.0 => bounded.push((i, b)),
+                Some(_) => (),
+                None => bvh.infinite.push(i),
+            }
+        }
+        if !bounded.is_empty() {
+            bvh.build(&mut bounded, 0, 0);
+        }
+        bvh
+    }
+
+    /// Builds the subtree for the given lights, splitting at the median along the widest axis.
+    /// Returns the index of the root of the subtree.
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        // The tree is balanced, so the bit trails won't run out
+        debug_assert!(depth < 64);
+        if lights.len() == 1 {
+            let (index, bounds) = lights[0];
+            self.trails[index] = Some(trail);
+            self.nodes.push((bounds, LightBvhNode::Leaf(index)));
+            return self.nodes.len() - 1;
+        }
+        let centroids = lights.iter().fold(Aabb::empty(), |b, (_, l)| {
+            b.union(&Aabb::point(l.bounds.center()))
+        });
+        let d = centroids.diagonal();
+        let key = |b: &LightBounds| {
+            let c = b.bounds.center();
+            if d.x >= d.y && d.x >= d.z {
+                c.x
+            } else if d.y >= d.z {
+                c.y
+            } else {
+                c.z
+            }
+        };
+        lights.sort_by(|a, b| key(&a.1).total_cmp(&key(&b.1)));
+        let mid = lights.len() / 2;
+        let node = self.nodes.len();
+        let bounds = lights[1..]
+            .iter()
+            .fold(lights[0].1, |acc, (_, b)| acc.union(b));
+        self.nodes.push((bounds, LightBvhNode::Interior([0, 0])));
+        let (left, right) = lights.split_at_mut(mid);
+        let a = self.build(left, trail, depth + 1);
+        let b = self.build(right, trail | (1u64 << depth), depth + 1);
+        self.nodes[node].1 = LightBvhNode::Interior([a, b]);
+        node
+    }
+
+    /// Probability of picking one of the positionless lights rather than going into the tree.
+    fn p_infinite(&self) -> f64 {
+        infinite_share(self.infinite.len(), !self.nodes.is_empty())
+    }
+
+    fn sample(&self, p: Vector3, n: Vector3, u: f64) -> Option<(usize, f64)> {
+        let p_inf = self.p_infinite();
+        if u < p_inf {
+            let count = self.infinite.len();
+            let index = ((u / p_inf * count as f64) as usize).min(count - 1);
+            return Some((self.infinite[index], p_inf / count as f64));
+        }
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut u = ((u - p_inf) / (1.0 - p_inf)).min(1.0 - f64::EPSILON);
+        let mut pmf = 1.0 - p_inf;
+        let mut node = 0;
+        loop {
+            match self.nodes[node].1 {
+                LightBvhNode::Interior(children) => {
+                    let c0 = self.nodes[children[0]].0.importance(p, n);
+                    let c1 = self.nodes[children[1]].0.importance(p, n);
+                    if c0 == 0.0 && c1 == 0.0 {
+                        return None;
+                    }
+                    let p0 = c0 / (c0 + c1);
+                    if u < p0 {
+                        node = children[0];
+                        u = (u / p0).min(1.0 - f64::EPSILON);
+                        pmf *= p0;
+                    } else {
+                        node = children[1];
+                        u = ((u - p0) / (1.0 - p0)).min(1.0 - f64::EPSILON);
+                        pmf *= 1.0 - p0;
+                    }
+                }
+                LightBvhNode::Leaf(light) => {
+                    if node > 0 || self.nodes[node].0.importance(p, n) > 0.0 {
+                        return Some((light, pmf));
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn pmf(&self, p: Vector3, n: Vector3, light: usize) -> f64 {
+        let mut trail = match self.trails[light] {
+            Some(trail) => trail,
+            None if self.infinite.contains(&light) => {
+                return self.p_infinite() / self.infinite.len() as f64
+            }
+            None => return 0.0,
+        };
+        let mut pmf = 1.0 - self.p_infinite();
+        let mut node = 0;
+        // A single light only gets picked where it could matter, as in sample
+        if let LightBvhNode::Leaf(_) = self.nodes[0].1 {
+            if self.nodes[0].0.importance(p, n) == 0.0 {
+                return 0.0;
+            }
+        }
+        while let LightBvhNode::Interior(children) = self.nodes[node].1 {
+            let c0 = self.nodes[children[0]].0.importance(p, n);
+            let c1 = self.nodes[children[1]].0.importance(p, n);
+            if c0 + c1 == 0.0 {
+                return 0.0;
+            }
+            let child = (trail & 1) as usize;
+            pmf *= [c0, c1][child] / (c0 + c1);
+            node = children[child];
+            trail >>= 1;
+        }
+        pmf
+    }
+}
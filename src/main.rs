@@ -1,21 +1,61 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 
 mod vector3;
-use objloader::load_obj_file;
+use config::{BounceLimits, Config};
+use denoise::{denoise, Features};
+use film::{Film, PathRadiance};
+use grid::Grid;
+use integrator::{Integrator, IntegratorKind};
+use material::{BsdfSample, Lobe, Material};
+use medium::{hg_phase, sample_hg, Medium};
+use mlt::Metropolis;
+use photons::{Emitters, PhotonMap};
+use sampler::Sampler;
+use scene::{Hit, Scene, Surface};
+use spectrum::{Channels, Spectral};
+use texture::Texture;
+use tiles::{Progress, Tile};
+use tonemapper::LuminanceStats;
 use vector3::*;
 
+mod animation;
+mod aov;
+mod bdpt;
+mod bvh;
+mod checkpoint;
+mod color;
+mod config;
+mod debug;
+mod denoise;
+mod film;
+mod filter;
+mod grid;
+mod integrator;
+mod interrupt;
+mod kdtree;
+mod light;
+mod lightsampler;
+mod material;
+mod medium;
+mod mlt;
 mod objloader;
-
-//mod tonemapper;
-
-const WIDTH: usize = 680;
-const HEIGHT: usize = 480;
-
-const MAX_BOUNCES: usize = 5;
-const MAX_SAMPLES: usize = 100;
+mod photons;
+mod rng;
+mod sampler;
+mod scene;
+mod spectrum;
+mod texture;
+mod tiles;
+mod tonemapper;
 
 ///
 /// A very basic ray tracer.
@@ -24,274 +64,944 @@ const MAX_SAMPLES: usize = 100;
 ///
 /// TODO: Time it
 fn main() -> std::io::Result<()> {
-    render()?;
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(2);
+        }
+    };
+    render(config)?;
     Ok(())
 }
 
-fn render() -> std::io::Result<()> {
+fn render(mut config: Config) -> std::io::Result<()> {
     // TODO: handle errors
-    // buffer against thread hiccups
-    let par = thread::available_parallelism().unwrap().get() * 4;
-    let pixels = WIDTH * HEIGHT;
-    // Generate pixel ranges for the threads
-    let ranges = (0..par)
-        .into_iter()
-        .map(|t| ((t * pixels) / par)..(((t + 1) * pixels) / par));
-    /*ranges.for_each(|r| {
-        let min = r.clone().min();
-        let max = r.max();
-        println!("{}..{}", &min.unwrap(), max.unwrap())
-    });*/
-    println!("Altogether {} (-1)", pixels);
-
-    let mut screen_buffer = vec![255; WIDTH * HEIGHT * 3];
-
-    let mut file = File::create("output.ppm")?;
-    println!("{}, ", screen_buffer.len());
-
-    let a: Vec<_> = ranges
-        .map(move |t| {
-            println!("SPAWNING");
-            thread::spawn(move || {
-                //println!("{}, {}", t.clone().min().unwrap(), t.clone().max().unwrap());
-                (t.clone(), render_partial(&t))
-            })
-        })
-        .collect();
-
-    a.into_iter().for_each(|t| {
-        let a = t.join().expect("Some thread failed");
-        for i in a.0.clone() {
-            write_pixel_ind(i, a.1[i - a.0.clone().min().unwrap()], &mut screen_buffer)
+    let par = config
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().unwrap().get());
+    println!("Altogether {} (-1)", config.width * config.height);
+
+    let mut scene = Scene::by_name(&config.scene, config.light_sampling, config.working_space)
+        .expect("scene names are checked by the config");
+    if let Some(path) = &config.density_grid {
+        scene.set_density(&Grid::load(path)?);
+    }
+    if let Some(density) = config.fog {
+        scene.atmosphere = Some(Medium::fog(density, config.fog_anisotropy));
+    }
+    if let Some(path) = &config.background {
+        scene.background = Some(Texture::load_ppm(path, config.working_space)?);
+    }
+    let media_too = config.integrator.is_debug()
+        || matches!(
+            config.integrator,
+            IntegratorKind::Path | IntegratorKind::Metropolis
+        );
+    if !media_too && scene.has_media() {
+        println!("The scene has media, which only the path tracer does: path tracing it instead");
+        config.integrator = IntegratorKind::Path;
+    }
+    if config.spectral
+        && !matches!(
+            config.integrator,
+            IntegratorKind::Path | IntegratorKind::Metropolis
+        )
+    {
+        println!("Only the path tracer and mlt render spectrally: rendering in RGB instead");
+        config.spectral = false;
+    }
+    if config.spectral && scene.has_media() {
+        println!("The scene has media, which only RGB renders do: rendering in RGB instead");
+        config.spectral = false;
+    }
+    if config.spectral {
+        scene.spectral = Some(Spectral::new(config.working_space, par));
+    }
+    interrupt::install();
+    match config.frames {
+        Some((first, last)) => {
+            for frame in first..=last {
+                println!("Frame {} ({} to {})", frame, first, last);
+                scene.set_frame(frame);
+                if !render_frame(&scene, &config, par, Some(frame), Path::new("."))? {
+                    break;
+                }
+            }
         }
-        println!("Joined: (something)");
-    });
+        None => {
+            render_frame(&scene, &config, par, None, Path::new("."))?;
+        }
+    }
     println!("par: {}", par);
-    let r = 0..pixels;
+    Ok(())
+}
 
-    //let camera: Camera = Camera {
-    //pos: Vector3::new(0.0, 0.0, 0.0),
-    //dir: Vector3::new(0.0, 1.0, 0.0),
-    //fov: (60.0),
-    //};
+/// Renders the scene as it's posed, to output.ppm in dir, or output_0001.ppm and so on for the
+/// frames of an animation. Returns false if the render was cut short by Ctrl-C.
+fn render_frame(
+    scene: &Scene,
+    config: &Config,
+    par: usize,
+    frame: Option<usize>,
+    dir: &Path,
+) -> std::io::Result<bool> {
+    let (width, height) = (config.width, config.height);
+    let pixels = width * height;
+    let in_dir = |name: &str| dir.join(numbered(name, frame)).display().to_string();
+    let output = in_dir("output.ppm");
+    let checkpoint = config.checkpoint.as_ref().map(|path| numbered(path, frame));
+    let mut film = Film::new(width, height, config.filter);
+
+    // The render goes in passes of 1, 2, 4, ... samples per pixel, which all add up in the
+    // same film. In between, the image so far gets written out every so often, and so do
+    // checkpoints. Photon mapping goes a sample per pass, each with photons of its own, and
+    // Metropolis a mutation per pixel per pass.
+    let start = Instant::now();
+    let (mut last_write, mut last_checkpoint) = (start, start);
+    let mut done = 0;
+    if config.resume {
+        let path = checkpoint.as_ref().unwrap();
+        // Frames the interrupted render never got to start from scratch
+        if frame.is_none() || std::path::Path::new(path).exists() {
+            done = checkpoint::load(path, config, frame, &mut film)?;
+            println!("Resuming from {}, at {} spp", path, done);
+        }
+    }
+    // Carry on with the schedule where the checkpoint left it
+    let progressive = matches!(
+        config.integrator,
+        IntegratorKind::PhotonMapping | IntegratorKind::Metropolis
+    );
+    let emitters =
+        (config.integrator == IntegratorKind::PhotonMapping).then(|| Emitters::new(scene, config));
+    let metropolis = (config.integrator == IntegratorKind::Metropolis)
+        .then(|| Metropolis::new(scene, config, done, par));
+    let mut pass_spp = if progressive {
+        1
+    } else {
+        (done + 1).next_power_of_two()
+    };
+    let mut last_pass: Option<(usize, Duration)> = None;
+    let tiles = tiles::tiles(width, height, config.tile_size, config.tile_order);
+    let progress = Progress::new(done as f64 / config.spp as f64);
+    let mut pass_number = 1;
+    while done < config.spp {
+        let count = pass_spp.min(config.spp - done);
+        if let (Some(limit), Some((last_count, last_time))) = (config.time_limit, last_pass) {
+            // Don't start a pass that won't be done in time
+            let estimate = last_time.mul_f64(count as f64 / last_count as f64);
+            if start.elapsed() + estimate > limit {
+                println!("Out of time");
+                break;
+            }
+        }
+        // Every pixel gets brought up to done + count samples, except for those that have
+        // converged, which sit the rest of the passes out. Pixels may be ahead of the others if
+        // an interrupted pass got to them.
+        let todo: Vec<Range<usize>> = (0..pixels)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                let taken = film.samples(x, y);
+                let active = match config.adaptive {
+                    Some(threshold) => {
+                        taken < config.min_spp || film.relative_error(x, y) >= threshold
+                    }
+                    None => true,
+                };
+                if active {
+                    taken..(done + count).max(taken)
+                } else {
+                    taken..taken
+                }
+            })
+            .collect();
+        let sampling = todo.iter().filter(|r| !r.is_empty()).count();
+        if sampling == 0 {
+            println!("Every pixel has converged");
+            break;
+        }
 
-    /*
-    for y in 0..HEIGHT {
-        for x in 0..WIDTH {}
-    }*/
+        let pass = Pass {
+            number: pass_number,
+            tiles: tiles.clone(),
+            todo,
+            done_before: done as f64 / config.spp as f64,
+            count,
+            fraction: count as f64 / config.spp as f64,
+            integrator: config.integrator.create(
+                config,
+                emitters
+                    .as_ref()
+                    .map(|e| PhotonMap::trace(scene, config, e, done, par)),
+            ),
+            metropolis: metropolis.as_ref(),
+        };
+        pass_number += 1;
+        let pass_start = Instant::now();
+        let finished = render_pass(scene, config, &pass, par, &mut film, &progress);
+        if !finished {
+            println!("Interrupted");
+            if let Some(path) = &checkpoint {
+                checkpoint::save(path, config, frame, &film, done)?;
+                println!("Checkpoint saved to {}", path);
+            }
+            write_image(&film, config, &output, true)?;
+            return Ok(false);
+        }
+        done += count;
+        last_pass = Some((count, pass_start.elapsed()));
+        println!(
+            "{} spp done, {:.1}s, {} pixels still sampling",
+            done,
+            start.elapsed().as_secs_f64(),
+            sampling
+        );
+        if !progressive {
+            pass_spp *= 2;
+        }
 
-    let output = screen_buffer;
-    //let output = screen_buffer.iter().flat_map(|v| [v.x, v.y, v.z]);
-    //output.for_each(|a| print!("{}", a));
+        if let Some(interval) = config.write_interval {
+            if last_write.elapsed() >= interval && done < config.spp {
+                write_image(&film, config, &output, false)?;
+                last_write = Instant::now();
+            }
+        }
+        if let Some(path) = &checkpoint {
+            if last_checkpoint.elapsed() >= config.checkpoint_interval {
+                checkpoint::save(path, config, frame, &film, done)?;
+                last_checkpoint = Instant::now();
+            }
+        }
+    }
 
-    write!(file, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
-    file.write_all(&output)?;
-    Ok(())
+    // The final checkpoint lets a render be continued to a higher sample count
+    if let Some(path) = &checkpoint {
+        checkpoint::save(path, config, frame, &film, done)?;
+    }
+    write_image(&film, config, &output, true)?;
+    if config.heatmap {
+        aov::write_heatmap(&film, config.spp, &in_dir("heatmap.ppm"))?;
+    }
+    for aov in &config.aovs {
+        let path = in_dir(&format!("output_{}.pfm", aov.name()));
+        aov.write(&film, &path)?;
+    }
+    Ok(true)
 }
 
-// Render a part of the image
-fn render_partial(pixel_indices: &std::ops::Range<usize>) -> Vec<Col> {
-    // This should not be here. Just a hack until I figure out sharing between threads
-    let sphere = Sphere::new(Vector3::new(0.0, 0.0, -3.0), 1.0);
-    let sphere2 = Sphere::new(Vector3::new(-150.0, 100.0, -200.0), 50.0);
-    let sphere3 = Sphere::new(Vector3::new(1.8, -1.0, -2.0), 0.22);
-    let sphere4 = Sphere::new(Vector3::new(2.0, 0.0, -3.0), 1.0);
-    let sphere5 = Sphere::new(Vector3::new(0.0, -41.0, -3.0), 40.0);
-    let sphere6 = Sphere::new(Vector3::new(-1.5, -0.3, -2.5), 0.8);
-    let geom = Renderable {
-        material: Material::gray_mat(),
-        geometry: Box::new(sphere),
-    };
-    let geom2 = Renderable {
-        material: Material::white_light(),
-        geometry: Box::new(sphere2),
-    };
-    let geom3 = Renderable {
-        material: Material::bluish(),
-        geometry: Box::new(sphere3),
-    };
-    let geom4 = Renderable {
-        material: Material::gray_mat(),
-        geometry: Box::new(sphere4),
-    };
-    let geom5 = Renderable {
-        material: Material::gray_mat(),
-        geometry: Box::new(sphere5),
-    };
-    let geom6 = Renderable {
-        material: Material::semirough(),
-        geometry: Box::new(sphere6),
+/// The path with the frame number put in before the extension, as in output_0001.ppm, when
+/// rendering an animation.
+fn numbered(path: &str, frame: Option<usize>) -> String {
+    let frame = match frame {
+        Some(frame) => frame,
+        None => return path.to_string(),
     };
-    let geom_bunny = Renderable {
-        material: Material::semirough(),
-        geometry: Box::new(Mesh::from(
-            "./bunny.obj".to_string(),
-            Vector3::new(0.5, -1.2, -2.5),
-            8.0,
-        )),
+    match path.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.contains('/') => {
+            format!("{}_{:04}.{}", stem, frame, ext)
+        }
+        _ => format!("{}_{:04}", path, frame),
+    }
+}
+
+/// Takes the samples with the given indices in every pixel, and adds them to the film.
+/// Returns false if the pass was cut short by Ctrl-C. Pixels are either done or untouched even
+/// then, so the film stays consistent. Metropolis passes go by chain instead of by tile, the
+/// same way.
+fn render_pass(
+    scene: &Scene,
+    config: &Config,
+    pass: &Pass,
+    par: usize,
+    film: &mut Film,
+    progress: &Progress,
+) -> bool {
+    let tiles = &pass.tiles;
+    let (items, unit) = match pass.metropolis {
+        Some(metropolis) => (metropolis.chains(), "chain"),
+        None => (tiles.len(), "tile"),
     };
+    // Threads take the next tile as soon as they're done with one, so no thread sits idle
+    // while there's work left.
+    let next = AtomicUsize::new(0);
+    // Finished tiles get added to the film in order, so that the result is the same no matter
+    // how many threads there are, or which finishes first. Tiles done out of turn wait.
+    struct Merge<'a> {
+        film: &'a mut Film,
+        next: usize,
+        waiting: BTreeMap<usize, Film>,
+    }
+    let merge = Mutex::new(Merge {
+        film,
+        next: 0,
+        waiting: BTreeMap::new(),
+    });
+    thread::scope(|s| {
+        for _ in 0..par {
+            s.spawn(|| loop {
+                let t = next.fetch_add(1, Ordering::Relaxed);
+                if t >= items || interrupt::interrupted() {
+                    break;
+                }
+                let tile_film = match pass.metropolis {
+                    Some(metropolis) => metropolis.run_chain(t, scene, config, pass.count),
+                    None => render_tile(&tiles[t], scene, config, pass),
+                };
+                let mut guard = merge.lock().unwrap();
+                let m = &mut *guard;
+                m.waiting.insert(t, tile_film);
+                while let Some(tile_film) = m.waiting.remove(&m.next) {
+                    m.film.merge(&tile_film);
+                    m.next += 1;
+                }
+                let fraction = pass.done_before + pass.fraction * m.next as f64 / items as f64;
+                progress.update(
+                    fraction,
+                    &format!("pass {}, {} {}/{}", pass.number, unit, m.next, items),
+                );
+            });
+        }
+    });
+    // After Ctrl-C, there may be gaps. The tiles after them still count.
+    let m = merge.into_inner().unwrap();
+    for tile_film in m.waiting.values() {
+        m.film.merge(tile_film);
+    }
+    progress.clear();
+    !interrupt::interrupted()
+}
 
-    let scene = vec![geom2, geom3, geom4, geom5, geom6, geom_bunny];
+/// What a pass needs done.
+struct Pass<'a> {
+    number: usize,
+    tiles: Vec<Tile>,
+    /// Sample indices to take, for every pixel
+    todo: Vec<Range<usize>>,
+    /// Samples per pixel the pass takes
+    count: usize,
+    /// Fraction of the render done before the pass, and done by the pass, for the progress bar
+    done_before: f64,
+    fraction: f64,
+    /// What finds the light along camera rays. For photon mapping, it has the pass's photons.
+    integrator: Box<dyn Integrator>,
+    /// The chains to take further, for Metropolis. They take the place of the tiles.
+    metropolis: Option<&'a Metropolis>,
+}
 
-    let mut ret_vec = vec![
-        Col {
-            r: 255,
-            g: 255,
-            b: 255
-        };
-        pixel_indices.len()
-    ];
-    for i in pixel_indices.clone() {
-        let x = i % WIDTH;
-        let y = i / WIDTH;
-
-        // x and y offsets of the camera direction
-        let xoff = (2.0 * (x as f64 + 0.5) / (WIDTH as f64) - 1.0)
-            * (1.57 / 2.0 as f64).tan()
-            * (WIDTH as f64)
-            / (HEIGHT as f64);
-        let yoff = -(2.0 * (y as f64 + 0.5) / (HEIGHT as f64) - 1.0) * (1.57 / 2.0 as f64).tan();
-
-        let dir: Vector3 = Vector3::new(xoff, yoff, -1.0).normalize();
-
-        //println!("{}, {}, {}", dir.x, dir.y, dir.z);
-        let c = gather_scene(Vector3::new(0.0, 0.0, 0.0), dir, &scene);
-        //+ cast_ray(Vector3::new(0.0, 0.0, 0.0), dir, &sphere2);
-        ret_vec[i - pixel_indices.clone().min().unwrap()] = c.as_col();
-        //        write_pixel_ind(i, c.as_col(), &mut ret_vec);
-    }
-    ret_vec
+/// Develops the film into the image at path: denoising, color conversion, exposure, tonemapping and
+/// encoding. The final image also gets its luminance statistics printed.
+fn write_image(film: &Film, config: &Config, path: &str, report: bool) -> std::io::Result<()> {
+    let (width, height) = (config.width, config.height);
+    let pixels = width * height;
+    let mut frame: Vec<Vector3> = (0..pixels)
+        .map(|i| film.pixel(i % width, i / width))
+        .collect();
+    if config.denoise {
+        let aux: Vec<_> = (0..pixels)
+            .map(|i| film.features(i % width, i / width))
+            .collect();
+        denoise(&mut frame, &aux, width, height);
+    }
+    // Exposure and tonemapping work on the float image in the output space, before it gets
+    // encoded and quantized
+    let to_output = config.working_space.conversion(config.output_space);
+    for col in frame.iter_mut() {
+        *col = to_output * *col;
+    }
+    let stats = LuminanceStats::of(&frame);
+    let mut exposure = config.exposure;
+    if config.auto_exposure {
+        exposure += stats.auto_exposure();
+    }
+    if report {
+        println!(
+            "Luminance: min {:.4}, max {:.4}, mean {:.4}, log average {:.4}",
+            stats.min, stats.max, stats.mean, stats.log_average
+        );
+        if config.auto_exposure {
+            println!("Exposure: {:+.2} EV", exposure);
+        }
+    }
+    config
+        .tonemap
+        .apply(&mut frame, exposure, config.white_percentile);
+
+    let mut screen_buffer = vec![255; pixels * 3];
+    for (i, col) in frame.iter().enumerate() {
+        let col = config.output_space.encode(*col);
+        write_pixel_ind(i, col.as_col(), &mut screen_buffer)
+    }
+    // Written to a temporary file first, so that viewers never see a half written image
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp)?;
+    write!(file, "P6\n{} {}\n255\n", width, height)?;
+    file.write_all(&screen_buffer)?;
+    drop(file);
+    std::fs::rename(tmp, path)
 }
 
-/// returns: on hit: (hit material, hit normal)
-///
-fn intersect_scene(
-    scene: &Vec<Renderable>,
-    origin: Vector3,
-    dir: Vector3,
-    z0: &mut f64,
-) -> Option<(Material, Vector3)> {
-    let mut best = None;
-    for g in scene {
-        match g.geometry.intersect(origin, dir) {
-            Some((z, norm)) => {
-                if z < *z0 {
-                    *z0 = z;
-                    //best = Some(g.material.base_col);
-                    best = Some((g.material.clone(), norm));
-                }
+// Render a tile of the image
+fn render_tile(tile: &Tile, scene: &Scene, config: &Config, pass: &Pass) -> Film {
+    let (cols, rows) = (tile.cols.clone(), tile.rows.clone());
+    let mut film = Film::tile(
+        config.width,
+        config.height,
+        config.filter,
+        cols.clone(),
+        rows.clone(),
+    );
+    let mut sampler = config.sampler.create(config.spp, config.seed);
+    for y in rows {
+        for x in cols.clone() {
+            if interrupt::interrupted() {
+                return film;
             }
-            None => {}
+            gather_scene(x, y, scene, config, pass, sampler.as_mut(), &mut film);
         }
     }
-    best
+    film
 }
 
-/// colcumulative is what we are looking for, in terms of render color
-/// Returns: ("cumulative color", "color factor", (new position, new direction)?) newdir none if no bounce.
-fn cast_ray(
+/// Random walks inside subsurface objects that take more steps than this are given up on.
+const MAX_WALK_STEPS: usize = 256;
+
+/// Traces a single path through the scene, and returns the radiance it carries back.
+/// Paths end when they escape, exceed a bounce limit, or are killed by russian roulette.
+/// What the path hits first is recorded in features, for the denoiser and the AOVs.
+/// Spectral renders trace the path at three wavelengths, and convert what it brings back to RGB.
+fn sample_scene(
     origin: Vector3,
     dir: Vector3,
-    scene: &Vec<Renderable>,
-    col_cum: Vector3,
-    col_factor: Vector3,
-) -> (Vector3, Vector3, Option<(Vector3, Vector3)>) {
-    let mut dist: f64 = f64::MAX;
-    let intr = intersect_scene(scene, origin, dir, &mut dist);
-    match intr {
-        Some((mat, norm)) => {
-            let newpos = dist * dir + origin;
-            // TODO: Extract color calculation to separate function
-            let newdir = new_dir(dir, norm, &mat);
+    time: f64,
+    scene: &Scene,
+    bounces: &BounceLimits,
+    sampler: &mut dyn Sampler,
+    features: &mut Features,
+) -> PathRadiance {
+    let mut channels = match &scene.spectral {
+        Some(spectral) => spectral.sample(sampler.get_1d()),
+        None => Channels::Rgb,
+    };
+    let mut orig = origin;
+    let mut dir = dir;
+    let mut cum_col = PathRadiance::default();
+    let mut col_factor = Vector3::new(1.0, 1.0, 1.0);
+    // Pdf of the last bounce, for weighting lights hit by chance against sampled ones.
+    // None for the camera ray and specular bounces, which light sampling can't reproduce.
+    let mut bsdf_pdf: Option<f64> = None;
+    // Where the last bounce happened, and the normal there (zero in a medium), which the light
+    // sampler needs to reproduce its choice of lights. Medium boundaries don't count as bounces.
+    let mut scatter_pos = origin;
+    let mut prev_norm = Vector3::new(0.0, 0.0, 0.0);
+    // What the path is travelling through, and whether that's the inside of a subsurface
+    // object, where no light gets to directly
+    let mut medium = scene.atmosphere.as_ref();
+    let mut walking = false;
+    let mut walk_steps = 0;
+    // Bounces so far, in total and of each kind
+    let mut depth = 0;
+    let (mut diffuse, mut specular, mut transmission) = (0, 0, 0);
+
+    loop {
+        // Every bounce uses the same sampler dimensions, whether it needs them or not, so that
+        // the dimensions keep their meaning from sample to sample.
+        let u_medium = sampler.get_2d();
+        let (u_pick, u_light, u_shadow) = (sampler.get_1d(), sampler.get_2d(), sampler.get_1d());
+        let (u_lobe, u_dir) = (sampler.get_1d(), sampler.get_2d());
+        let u_roulette = sampler.get_1d();
+
+        let hit = scene.intersect(orig, dir, time);
+        // Free flight through the medium: the path either scatters on the way to the surface,
+        // or makes it there, dimmed by the medium
+        let mut scatter = None;
+        if let Some(m) = medium {
+            let t_max = hit.as_ref().map_or(f64::MAX, |h| h.dist);
+            let ms = m.sample(orig, dir, t_max, col_factor, u_medium);
+            col_factor = col_factor.star(ms.weight);
+            // Absorbed
+            if col_factor.max_component() <= 0.0 {
+                break;
+            }
+            scatter = ms.scatter.map(|t| (m, t));
+        }
+
+        let (pos, norm, s, crossed) = if let Some((m, t)) = scatter {
+            let pos = orig + t * dir;
+            if depth == 0 {
+                *features = Features {
+                    albedo: Vector3::new(1.0, 1.0, 1.0),
+                    normal: -dir,
+                    depth: t,
+                    object: None,
+                    material: None,
+                };
+            }
+            let zero = Vector3::new(0.0, 0.0, 0.0);
+            if !walking {
+                let phase = Scatterer::Medium(m.g());
+                let u = (u_pick, u_light, u_shadow);
+                let direct =
+                    sample_light(scene, pos, zero, dir, &phase, medium, time, u, &channels);
+                cum_col.add(depth + 1, direct.star(col_factor));
+            }
+            // The phase function is sampled exactly, so it leaves the throughput be
+            let (wi, pdf) = sample_hg(dir, m.g(), u_dir);
+            let s = BsdfSample {
+                wi,
+                weight: Vector3::new(1.0, 1.0, 1.0),
+                pdf,
+                lobe: Lobe::Diffuse,
+            };
+            (pos, zero, s, None)
+        } else {
+            let hit = match hit {
+                Some(hit) => hit,
+                None => {
+                    // Clear color acts as emissive does.
+                    let mut escaped = channels.illuminant(scene.background(dir));
+                    for (i, light) in scene.lights.iter().enumerate() {
+                        let le = channels.illuminant(light.escaped(dir));
+                        if le.max_component() > 0.0 {
+                            let weight =
+                                mis_weight(bsdf_pdf, scene, scatter_pos, prev_norm, i, dir);
+                            escaped = escaped + weight * le;
+                        }
+                    }
+                    cum_col.add(depth, escaped.star(col_factor));
+                    break;
+                }
+            };
+            let pos = orig + hit.dist * dir;
+            // Medium boundaries are passed straight through
+            if let Surface::Object(g) = hit.surface {
+                if g.material.is_passthrough() {
+                    col_factor = col_factor.star(channels.albedo(g.material.base_col));
+                    medium = scene.medium_across(g, dir, hit.normal);
+                    orig = pos + 0.001 * dir;
+                    continue;
+                }
+            }
+            if depth == 0 {
+                *features = hit_features(scene, &hit, pos, origin, dir);
+            }
             // emmissive contribution.
-            // Take lambertian falloff into account
-            let cumcol = col_cum + mat.emissive.star(col_factor);
-            // effect of surface color
-            let tot_factor = col_factor.star(mat.base_col);
-            (cumcol, tot_factor, Some((newpos, newdir)))
+            let emitted = channels.illuminant(emitted(scene, &hit, dir));
+            if emitted.max_component() > 0.0 {
+                let weight = match hit.light {
+                    Some(i) => mis_weight(bsdf_pdf, scene, scatter_pos, prev_norm, i, dir),
+                    None => 1.0,
+                };
+                cum_col.add(depth, weight * emitted.star(col_factor));
+            }
+            let g = match hit.surface {
+                Surface::Light(_) => break,
+                Surface::Object(g) => g,
+            };
+            let mat = channels.material(&g.material);
+            if !mat.is_specular() {
+                let surface = Scatterer::Surface(g, &mat, hit.normal);
+                let u = (u_pick, u_light, u_shadow);
+                let direct = sample_light(
+                    scene, pos, hit.normal, dir, &surface, medium, time, u, &channels,
+                );
+                // Light sampled here takes one more bounce to reach the camera
+                cum_col.add(depth + 1, direct.star(col_factor));
+            }
+
+            let s = match mat.sample(dir, hit.normal, [u_lobe, u_dir[0], u_dir[1]]) {
+                Some(s) => s,
+                None => break,
+            };
+            (pos, hit.normal, s, Some(g))
+        };
+
+        // effect of surface color
+        col_factor = col_factor.star(s.weight);
+        // Dispersive glass sends every wavelength its own way, and the path follows the hero's
+        if crossed.is_some_and(|g| g.material.dispersion.is_some()) && s.is_specular() {
+            col_factor = channels.hero_only(col_factor);
         }
-        None => {
-            // Clear color acts as emissive does.
-            // TODO: Extract calculation into inline function
-            let cumcol = col_cum + clear_col(dir).star(col_factor);
-            (cumcol, col_factor, None)
+        if walking && crossed.is_none() {
+            // The steps of a random walk together make up a single bounce, from where the path
+            // went into the object to where it comes back out
+            walk_steps += 1;
+            if walk_steps > MAX_WALK_STEPS {
+                break;
+            }
+        } else {
+            let (count, limit) = match s.lobe {
+                Lobe::Diffuse => (&mut diffuse, bounces.diffuse),
+                Lobe::Specular => (&mut specular, bounces.specular),
+                Lobe::Transmission => (&mut transmission, bounces.transmission),
+            };
+            *count += 1;
+            if limit.is_some_and(|limit| *count > limit) {
+                break;
+            }
+            depth += 1;
+        }
+
+        // Russian roulette: dim paths are likely to be terminated, but the survivors are weighted
+        // up to make up for it. Survival is capped so that even bright paths end eventually.
+        // Random walks are left alone, they're limited by their number of steps instead.
+        if depth > bounces.min_depth && !walking {
+            let survival = col_factor.max_component().min(0.95);
+            if u_roulette >= survival {
+                break;
+            }
+            col_factor = (1.0 / survival) * col_factor;
         }
+
+        // Going through the surface of an object takes the path into another medium
+        if let Some(g) = crossed {
+            if (s.wi * norm) * (dir * norm) > 0.0 {
+                medium = scene.medium_across(g, s.wi, norm);
+                walking = g.material.subsurface.is_some() && s.wi * norm < 0.0;
+                walk_steps = 0;
+            }
+        }
+        bsdf_pdf = if s.is_specular() { None } else { Some(s.pdf) };
+        scatter_pos = pos;
+        prev_norm = norm;
+        orig = pos + (0.001 * s.wi); // prevent self-intersection
+        dir = s.wi;
     }
-    /*
-    if !gemoetry.geometry.intersect(origin, dir, &mut sphere_dist) {
-        Vector3::new(1.0 * dir.y, 0.0, 0.0)
-    } else {
-        gemoetry.material.base_col
-    }*/
+    channels.in_rgb(cum_col)
 }
 
-fn clear_col(dir: Vector3) -> Vector3 {
-    Vector3::new(0.2, 1.0 * (-dir.y).max(0.0), 1.0 * (dir.y).max(0.0))
+/// What the camera sees first: hit, at pos, of a ray from origin going in direction dir.
+fn hit_features(scene: &Scene, hit: &Hit, pos: Vector3, origin: Vector3, dir: Vector3) -> Features {
+    Features {
+        albedo: match hit.surface {
+            Surface::Object(g) => g.material.base_col,
+            Surface::Light(_) => Vector3::new(1.0, 1.0, 1.0),
+        },
+        normal: if hit.normal * dir > 0.0 {
+            -hit.normal
+        } else {
+            hit.normal
+        },
+        depth: (pos - origin).norm(),
+        object: hit.object,
+        material: hit.object.map(|i| scene.material_ids[i]),
+    }
 }
 
-// Calculate direction of bounced ray
-fn new_dir(d: Vector3, n: Vector3, mat: &Material) -> Vector3 {
-    let res = Vector3::on_unit_sphere();
-    //let diff = if (res * n) >= 0.0 { (res + n) } else { -1.0 * res };
-    let diff = (res + n).normalize();
-
-    let spec = d - 2.0 * (d * n) * n;
-    Vector3::lerp(spec, diff, mat.roughness)
-    // Currently, just the reflection vector
-    //n
+/// Radiance emitted towards a ray with direction dir, where it hit something.
+fn emitted(scene: &Scene, hit: &Hit, dir: Vector3) -> Vector3 {
+    match (hit.light, hit.surface) {
+        (Some(i), _) => scene.lights[i].emitted(dir, hit.normal),
+        (None, Surface::Object(g)) => g.material.emissive,
+        (None, Surface::Light(light)) => light.emitted(dir, hit.normal),
+    }
 }
 
-// TODO: sample_scene and cast_ray could be restructured
-fn sample_scene(origin: Vector3, dir: Vector3, scene: &Vec<Renderable>) -> Vector3 {
-    let mut orig = origin;
-    let mut cum_col = Vector3::new(0.0, 0.0, 0.0);
-    let mut col_factor = Vector3::new(1.0, 1.0, 1.0);
-    let mut dir = dir;
+/// MIS weight of a light that a bounce from p (with normal n) and bsdf_pdf ran into.
+fn mis_weight(
+    bsdf_pdf: Option<f64>,
+    scene: &Scene,
+    p: Vector3,
+    n: Vector3,
+    light: usize,
+    dir: Vector3,
+) -> f64 {
+    match bsdf_pdf {
+        Some(pdf) => power_heuristic(pdf, scene.light_pdf(p, n, light, dir)),
+        None => 1.0,
+    }
+}
 
-    for n in (0..=MAX_BOUNCES).rev() {
-        let res = cast_ray(orig, dir, scene, cum_col, col_factor);
-        (cum_col, col_factor) = (res.0, res.1);
-        match res.2 {
-            Some((p, d)) => {
-                orig = p + (0.001 * d); // prevent self-intersection
-                dir = d
+/// What light scatters off at a path vertex: the surface of an object (with its material, in the
+/// path's channels, and its normal), or a medium, with the Henyey-Greenstein g of its phase
+/// function.
+enum Scatterer<'a> {
+    Surface(&'a Renderable, &'a Material, Vector3),
+    Medium(f64),
+}
+impl Scatterer<'_> {
+    /// Fraction of light from wi scattered along dir, cosine included for surfaces.
+    fn eval(&self, dir: Vector3, wi: Vector3) -> Vector3 {
+        match self {
+            Scatterer::Surface(_, mat, norm) => mat.eval(dir, *norm, wi),
+            Scatterer::Medium(g) => {
+                let p = hg_phase(dir * wi, *g);
+                Vector3::new(p, p, p)
             }
-            None => break,
         }
     }
-    cum_col
+    fn pdf(&self, dir: Vector3, wi: Vector3) -> f64 {
+        match self {
+            Scatterer::Surface(_, mat, norm) => mat.pdf(dir, *norm, wi),
+            Scatterer::Medium(g) => hg_phase(dir * wi, *g),
+        }
+    }
 }
 
-// The sample and gather
-fn gather_scene(origin: Vector3, dir: Vector3, scene: &Vec<Renderable>) -> Vector3 {
-    let mut res = Vector3::new(0.0, 0.0, 0.0);
-    for n in 1..=MAX_SAMPLES {
-        let col = sample_scene(
-            origin,
-            (dir + 0.001 * Vector3::on_unit_sphere()).normalize(),
-            scene,
-        );
-        let frac = 1.0 / (n as f64);
-        res = frac * col + (1.0 - frac) * res
+/// Next event estimation: picks a light, and returns its (MIS weighted) direct contribution at pos.
+/// The shadow ray starts out in medium, at the time of the path. u has the random numbers for
+/// picking a light, sampling it, and tracking the shadow ray through grid media. The light comes
+/// back in channels.
+#[allow(clippy::too_many_arguments)]
+fn sample_light(
+    scene: &Scene,
+    pos: Vector3,
+    norm: Vector3,
+    dir: Vector3,
+    scatterer: &Scatterer,
+    medium: Option<&Medium>,
+    time: f64,
+    u: (f64, [f64; 2], f64),
+    channels: &Channels,
+) -> Vector3 {
+    let (u_pick, u_light, u_shadow) = u;
+    let black = Vector3::new(0.0, 0.0, 0.0);
+    let (index, pmf) = match scene.light_sampler.sample(pos, norm, u_pick) {
+        Some(picked) => picked,
+        None => return black,
+    };
+    let light = &scene.lights[index];
+    let ls = match light.sample_li(pos, u_light) {
+        Some(ls) => ls,
+        None => return black,
+    };
+    let f = scatterer.eval(dir, ls.wi);
+    if f.max_component() <= 0.0 {
+        return black;
+    }
+    // Light from the other side of a surface comes through the medium over there
+    let medium = match scatterer {
+        Scatterer::Surface(g, _, n) if (ls.wi * *n) * (dir * *n) > 0.0 => {
+            scene.medium_across(g, ls.wi, *n)
+        }
+        _ => medium,
+    };
+    let tr = scene.transmittance(
+        pos + 0.001 * ls.wi,
+        ls.wi,
+        ls.dist - 0.002,
+        medium,
+        time,
+        u_shadow,
+    );
+    if tr.max_component() <= 0.0 {
+        return black;
     }
-    res
+    let pdf = ls.pdf * pmf;
+    let weight = if light.is_delta() {
+        1.0
+    } else {
+        power_heuristic(pdf, scatterer.pdf(dir, ls.wi))
+    };
+    (weight / pdf) * f.star(channels.illuminant(ls.li)).star(tr)
 }
 
-fn write_pixel(x: usize, y: usize, col: Col, screen_buffer: &mut Vec<u8>) {
-    let base_ind = 3 * (x + y * WIDTH);
-    screen_buffer[base_ind] = col.r;
-    screen_buffer[base_ind + 1] = col.g;
-    screen_buffer[base_ind + 2] = col.b;
+// The sample and gather
+// Takes the samples the pass has for pixel (x, y), and splats them into the film.
+fn gather_scene(
+    x: usize,
+    y: usize,
+    scene: &Scene,
+    config: &Config,
+    pass: &Pass,
+    sampler: &mut dyn Sampler,
+    film: &mut Film,
+) {
+    for n in pass.todo[y * config.width + x].clone() {
+        sampler.start_pixel_sample(x, y, n);
+        let [sx, sy] = sampler.get_pixel_2d();
+        let (px, py) = (x as f64 + sx, y as f64 + sy);
+        // Somewhere in the time the shutter is open
+        let time = config.shutter * sampler.get_1d();
+        let (origin, dir) = scene.camera.ray(px, py, film.width, film.height, time);
+        let mut features = Features::default();
+        let col = pass
+            .integrator
+            .li(origin, dir, time, scene, sampler, &mut features, film);
+        film.add_sample(px, py, col);
+        film.add_features(x, y, &features, col.total());
+    }
 }
-fn write_pixel_ind(i: usize, col: Col, buffer: &mut Vec<u8>) {
+
+fn write_pixel_ind(i: usize, col: Col, buffer: &mut [u8]) {
     let base_ind = 3 * i;
     buffer[base_ind] = col.r;
     buffer[base_ind + 1] = col.g;
     buffer[base_ind + 2] = col.b;
 }
 
-fn ind_to_screenpos(index: usize) -> [usize; 2] {
-    let x = index % WIDTH;
-    let y = index / HEIGHT;
-    [x, y]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renders a small scene with every AOV into dir, and returns the files that came out.
+    fn render_in(dir: &Path, seed: u64, threads: usize) -> Vec<(String, Vec<u8>)> {
+        let config = Config {
+            scene: "volumes".to_string(),
+            width: 24,
+            height: 16,
+            spp: 8,
+            tile_size: 8,
+            seed,
+            aovs: aov::Aov::ALL.to_vec(),
+            ..Config::default()
+        };
+        let scene =
+            Scene::by_name(&config.scene, config.light_sampling, config.working_space).unwrap();
+        std::fs::create_dir_all(dir).unwrap();
+        assert!(render_frame(&scene, &config, threads, None, dir).unwrap());
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| {
+                let path = e.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                (name, std::fs::read(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// The same seed has to give the same image, bit for bit, however many threads render it.
+    #[test]
+    fn renders_are_deterministic() {
+        let tmp = std::env::temp_dir().join(format!("basic-raytracer-{}", std::process::id()));
+        let first = render_in(&tmp.join("first"), 7, 1);
+        let again = render_in(&tmp.join("again"), 7, 1);
+        let threaded = render_in(&tmp.join("threaded"), 7, 3);
+        let reseeded = render_in(&tmp.join("reseeded"), 8, 1);
+        std::fs::remove_dir_all(&tmp).unwrap();
+        assert_eq!(first.len(), 1 + aov::Aov::ALL.len());
+        assert!(first == again, "same seed, different image");
+        assert!(first == threaded, "different thread count, different image");
+        assert!(first != reseeded, "different seed, same image");
+    }
+
+    /// Two gray balls on gray ground under the sky, lit by a small emissive sphere.
+    fn diffuse_scene(config: &Config) -> Scene {
+        let sphere = |center, radius, material| Renderable {
+            material,
+            geometry: Box::new(Sphere::new(center, radius)),
+            medium: None,
+        };
+        let mut objects = vec![
+            sphere(Vector3::new(0.0, -41.0, -3.0), 40.0, Material::gray_mat()),
+            sphere(Vector3::new(-0.6, -0.5, -3.0), 0.5, Material::gray_mat()),
+            sphere(Vector3::new(0.7, -0.7, -2.6), 0.3, Material::gray_mat()),
+        ];
+        // The light is a mirror, and the furnace only wants Lambert surfaces
+        if config.integrator != IntegratorKind::WhiteFurnace {
+            objects.push(sphere(
+                Vector3::new(0.5, 1.2, -2.5),
+                0.3,
+                Material::white_light(),
+            ));
+        }
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, 0.0, -1.0),
+            fov: 1.0,
+            motion: None,
+        };
+        Scene::new(
+            camera,
+            objects,
+            vec![],
+            config.light_sampling,
+            config.working_space,
+        )
+    }
+
+    /// Renders the diffuse scene with the integrator, and returns the radiance of every pixel,
+    /// summed up from the emission, direct and indirect AOVs.
+    fn render_radiance(name: &str, integrator: IntegratorKind, spp: usize) -> Vec<Vector3> {
+        let config = Config {
+            width: 16,
+            height: 12,
+            spp,
+            integrator,
+            aovs: vec![aov::Aov::Emission, aov::Aov::Direct, aov::Aov::Indirect],
+            ..Config::default()
+        };
+        let dir =
+            std::env::temp_dir().join(format!("basic-raytracer-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(render_frame(&diffuse_scene(&config), &config, 2, None, &dir).unwrap());
+        let mut pixels = vec![Vector3::new(0.0, 0.0, 0.0); config.width * config.height];
+        for aov in &config.aovs {
+            let pfm = std::fs::read(dir.join(format!("output_{}.pfm", aov.name()))).unwrap();
+            // Past the three header lines, it's all little endian floats
+            let start = pfm
+                .iter()
+                .enumerate()
+                .filter(|(_, &b)| b == b'\n')
+                .nth(2)
+                .unwrap()
+                .0
+                + 1;
+            let floats: Vec<f64> = pfm[start..]
+                .chunks(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+                .collect();
+            for (p, c) in pixels.iter_mut().zip(floats.chunks(3)) {
+                *p = *p + Vector3::new(c[0], c[1], c[2]);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        pixels
+    }
+
+    fn mean(pixels: &[Vector3]) -> f64 {
+        pixels.iter().map(|p| p.x + p.y + p.z).sum::<f64>() / (3 * pixels.len()) as f64
+    }
+
+    /// What the path tracer makes of the diffuse scene, for the other integrators to match.
+    fn path_mean() -> f64 {
+        static MEAN: std::sync::OnceLock<f64> = std::sync::OnceLock::new();
+        *MEAN.get_or_init(|| mean(&render_radiance("path", IntegratorKind::Path, 256)))
+    }
+
+    fn assert_close(integrator: &str, got: f64, tolerance: f64) {
+        let want = path_mean();
+        assert!(
+            (got - want).abs() <= tolerance * want,
+            "{} gave a mean of {}, the path tracer {}",
+            integrator,
+            got,
+            want
+        );
+    }
+
+    #[test]
+    fn bdpt_matches_path() {
+        let pixels = render_radiance("bdpt", IntegratorKind::Bidirectional, 128);
+        assert_close("bdpt", mean(&pixels), 0.05);
+    }
+
+    #[test]
+    fn ppm_matches_path() {
+        let pixels = render_radiance("ppm", IntegratorKind::PhotonMapping, 64);
+        assert_close("ppm", mean(&pixels), 0.05);
+    }
+
+    #[test]
+    fn mlt_matches_path() {
+        let pixels = render_radiance("mlt", IntegratorKind::Metropolis, 256);
+        // The chains are noisier than independent paths
+        assert_close("mlt", mean(&pixels), 0.1);
+    }
+
+    /// With nothing but Lambert surfaces in a sky of one, every pixel has to come out one.
+    #[test]
+    fn furnace_is_white() {
+        let pixels = render_radiance("furnace", IntegratorKind::WhiteFurnace, 64);
+        assert!(
+            (mean(&pixels) - 1.0).abs() < 0.01,
+            "mean of {}",
+            mean(&pixels)
+        );
+        for p in &pixels {
+            assert!((p.x - 1.0).abs() < 0.1, "pixel of {:?}", p);
+        }
+    }
 }
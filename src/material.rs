@@ -0,0 +1,411 @@
+use std::f64::consts::PI;
+
+use crate::vector3::*;
+
+/// The kind of scattering event a sample came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lobe {
+    /// Any non-specular reflection, glossy included.
+    Diffuse,
+    /// Mirror reflection
+    Specular,
+    /// Refraction through the surface
+    Transmission,
+}
+
+/// A direction drawn from a material's scattering distribution.
+pub struct BsdfSample {
+    pub wi: Vector3,
+    /// BSDF times cosine, divided by the pdf. What the path throughput gets multiplied with.
+    pub weight: Vector3,
+    /// Solid angle pdf of wi. Meaningless for specular samples.
+    pub pdf: f64,
+    pub lobe: Lobe,
+}
+impl BsdfSample {
+    /// Specular samples can't be reproduced by sampling lights.
+    pub fn is_specular(&self) -> bool {
+        self.lobe != Lobe::Diffuse
+    }
+}
+
+/// Index of refraction as a function of wavelength, for glass that spreads white light out
+/// into a rainbow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dispersion {
+    /// n = a + b / λ², with λ in μm
+    Cauchy { a: f64, b: f64 },
+    /// n² = 1 + Σ b λ² / (λ² - c), with λ in μm, which holds over a wider range
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+impl Dispersion {
+    /// The index of refraction at lambda, in nm.
+    pub fn ior(&self, lambda: f64) -> f64 {
+        let l2 = (lambda / 1000.0) * (lambda / 1000.0);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f64 = b.iter().zip(c).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Material {
+    pub base_col: Vector3,
+    pub emissive: Vector3,
+    pub roughness: f64,
+    pub metallic: f64,
+    /// Weight of the smooth dielectric (glass) lobe. The rest of the material is opaque.
+    pub transmission: f64,
+    /// Index of refraction of the dielectric lobe
+    pub ior: f64,
+    /// How the index of refraction changes with wavelength. Only spectral renders see it, RGB
+    /// ones stick to ior.
+    pub dispersion: Option<Dispersion>,
+    /// Mean free path inside the object, per color channel, for subsurface scattering. The
+    /// lambertian lobe then goes into the object rather than reflecting off it, and light random
+    /// walks around inside until it gets back out. base_col is the color that comes back out.
+    pub subsurface: Option<Vector3>,
+    // Normals will be more convoluted
+}
+impl Material {
+    fn new(base_col: Vector3, emissive: Vector3, roughness: f64, metallic: f64) -> Material {
+        Material {
+            base_col,
+            emissive,
+            roughness,
+            metallic,
+            transmission: 0.0,
+            ior: 1.5,
+            dispersion: None,
+            subsurface: None,
+        }
+    }
+
+    pub fn gray_mat() -> Material {
+        Material::new(
+            Vector3::new(0.5, 0.5, 0.5),
+            Vector3::new(0.0, 0.0, 0.0),
+            1.0,
+            0.0,
+        )
+    }
+    pub fn semirough() -> Material {
+        Material::new(
+            Vector3::new(0.9, 0.3, 0.3),
+            Vector3::new(0.0, 0.0, 0.0),
+            0.5,
+            0.0,
+        )
+    }
+    pub fn bluish() -> Material {
+        Material::new(
+            Vector3::new(0.2, 0.2, 0.5),
+            Vector3::new(0.0, 0.0, 0.0),
+            0.0,
+            0.0,
+        )
+    }
+    pub fn white_light() -> Material {
+        Material::new(
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(10.0, 10.0, 10.0),
+            0.0,
+            0.0,
+        )
+    }
+    pub fn glass() -> Material {
+        Material {
+            transmission: 1.0,
+            ..Material::new(
+                Vector3::new(1.0, 1.0, 1.0),
+                Vector3::new(0.0, 0.0, 0.0),
+                0.0,
+                0.0,
+            )
+        }
+    }
+    /// Crown glass (BK7), which splits light into its colors a little.
+    pub fn crown_glass() -> Material {
+        Material {
+            ior: 1.5168,
+            dispersion: Some(Dispersion::Cauchy {
+                a: 1.5046,
+                b: 0.0042,
+            }),
+            ..Material::glass()
+        }
+    }
+    /// Dense flint glass (SF11), which splits light into its colors a lot, as prisms do.
+    pub fn flint_glass() -> Material {
+        Material {
+            ior: 1.7847,
+            dispersion: Some(Dispersion::Sellmeier {
+                b: [1.73759695, 0.313747346, 1.89878101],
+                c: [0.013188707, 0.0623068142, 155.23629],
+            }),
+            ..Material::glass()
+        }
+    }
+    /// An invisible surface, for the boundary of a medium. It's glass with the same index of
+    /// refraction as its surroundings, which neither reflects nor bends light.
+    pub fn medium_boundary() -> Material {
+        Material {
+            ior: 1.0,
+            ..Material::glass()
+        }
+    }
+    /// Candle wax: light goes a long way through it, especially red light.
+    pub fn wax() -> Material {
+        Material {
+            subsurface: Some(Vector3::new(0.6, 0.35, 0.2)),
+            ..Material::new(
+                Vector3::new(0.95, 0.85, 0.6),
+                Vector3::new(0.0, 0.0, 0.0),
+                0.8,
+                0.0,
+            )
+        }
+    }
+    pub fn marble() -> Material {
+        Material {
+            subsurface: Some(Vector3::new(0.25, 0.25, 0.3)),
+            ..Material::new(
+                Vector3::new(0.9, 0.9, 0.88),
+                Vector3::new(0.0, 0.0, 0.0),
+                0.7,
+                0.0,
+            )
+        }
+    }
+    /// Scattering distances after Jensen et al., scaled to the scene, where a unit is about 10cm.
+    pub fn skin() -> Material {
+        Material {
+            subsurface: Some(Vector3::new(0.037, 0.014, 0.008)),
+            ..Material::new(
+                Vector3::new(0.85, 0.6, 0.48),
+                Vector3::new(0.0, 0.0, 0.0),
+                0.9,
+                0.0,
+            )
+        }
+    }
+    pub fn yellowish_light() -> Material {
+        Material::new(
+            Vector3::new(0.0, 0.0, 0.0),
+            Vector3::new(12.0, 12.0, 8.0),
+            0.0,
+            0.0,
+        )
+    }
+
+    // The opaque part of the material is a mix of a lambertian lobe and a glossy (normalized
+    // Phong) lobe around the mirror direction. Roughness moves weight from the glossy lobe to the
+    // lambertian one, and also widens the glossy lobe. Metals have no lambertian lobe at all.
+    // With probability `transmission`, the surface is smooth glass instead.
+    // Subsurface materials refract the lambertian lobe into the object, with a lambertian
+    // distribution, and a path leaving the object from inside does the same the other way.
+    // The color comes from the walk inside, so neither is tinted by base_col.
+    //
+    // The normals passed in are geometric normals, facing out of the object. The functions flip
+    // them as needed.
+
+    /// Weight of the opaque lobes
+    fn opaque_weight(&self) -> f64 {
+        1.0 - clamp(self.transmission, 0.0, 1.0)
+    }
+    /// Probability of picking the lambertian lobe among the opaque ones, also its weight there.
+    fn diffuse_weight(&self) -> f64 {
+        clamp(self.roughness, 0.0, 1.0) * (1.0 - clamp(self.metallic, 0.0, 1.0))
+    }
+    /// Phong exponent of the glossy lobe. None for a perfect mirror.
+    fn phong_exponent(&self) -> Option<f64> {
+        if self.roughness < 1e-3 {
+            None
+        } else {
+            Some(2.0 / (self.roughness * self.roughness) - 2.0)
+        }
+    }
+    /// True if rays go straight through the surface, tinted by base_col but otherwise as if it
+    /// weren't there.
+    pub fn is_passthrough(&self) -> bool {
+        self.transmission >= 1.0 && self.ior == 1.0
+    }
+    /// True if the material only scatters into discrete directions, so sampling lights is pointless.
+    pub fn is_specular(&self) -> bool {
+        self.opaque_weight() == 0.0
+            || (self.diffuse_weight() == 0.0 && self.phong_exponent().is_none())
+    }
+
+    /// Samples a scattered direction. dir is the incoming ray direction.
+    /// Returns None if the path should be terminated.
+    pub fn sample(&self, dir: Vector3, n: Vector3, u: [f64; 3]) -> Option<BsdfSample> {
+        if self.is_leaving(dir, n) {
+            let wi = n.local_to_world(cosine_hemisphere([u[1], u[2]]));
+            return Some(BsdfSample {
+                wi,
+                weight: Vector3::new(1.0, 1.0, 1.0),
+                pdf: wi * n / PI,
+                lobe: Lobe::Diffuse,
+            });
+        }
+        let opaque = self.opaque_weight();
+        if u[0] >= opaque {
+            // Smooth dielectric. Reuse the random number to pick between reflection and refraction.
+            return Some(self.sample_dielectric(dir, n, (u[0] - opaque) / (1.0 - opaque)));
+        }
+        let u0 = u[0] / opaque;
+        let n = facing(dir, n);
+        let refl = reflect(dir, n);
+        let wi = if u0 < self.diffuse_weight() {
+            if self.subsurface.is_some() {
+                (-n).local_to_world(cosine_hemisphere([u[1], u[2]]))
+            } else {
+                n.local_to_world(cosine_hemisphere([u[1], u[2]]))
+            }
+        } else {
+            match self.phong_exponent() {
+                Some(e) => {
+                    let cos_a = u[1].powf(1.0 / (e + 1.0));
+                    let sin_a = (1.0 - cos_a * cos_a).max(0.0).sqrt();
+                    let phi = 2.0 * PI * u[2];
+                    refl.local_to_world(Vector3::new(sin_a * phi.cos(), sin_a * phi.sin(), cos_a))
+                }
+                None => {
+                    // Mirror. The selection probability cancels out with the lobe weight.
+                    return Some(BsdfSample {
+                        wi: refl,
+                        weight: self.base_col,
+                        pdf: 0.0,
+                        lobe: Lobe::Specular,
+                    });
+                }
+            }
+        };
+        let pdf = self.pdf(dir, n, wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            wi,
+            weight: (1.0 / pdf) * self.eval(dir, n, wi),
+            pdf,
+            lobe: Lobe::Diffuse,
+        })
+    }
+
+    /// Fresnel weighted choice between mirror reflection and refraction.
+    fn sample_dielectric(&self, dir: Vector3, n: Vector3, u: f64) -> BsdfSample {
+        let entering = dir * n < 0.0;
+        let n = facing(dir, n);
+        // Ratio of the indices of refraction, from the incident side to the other side
+        let eta = if entering { 1.0 / self.ior } else { self.ior };
+        let cos_i = -dir * n;
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+        let reflectance = fresnel_dielectric(cos_i, eta);
+        if u < reflectance || sin2_t >= 1.0 {
+            BsdfSample {
+                wi: reflect(dir, n),
+                weight: self.base_col,
+                pdf: 0.0,
+                lobe: Lobe::Specular,
+            }
+        } else {
+            let cos_t = (1.0 - sin2_t).sqrt();
+            BsdfSample {
+                wi: (eta * dir + (eta * cos_i - cos_t) * n).normalize(),
+                weight: self.base_col,
+                pdf: 0.0,
+                lobe: Lobe::Transmission,
+            }
+        }
+    }
+
+    /// True for a path at the end of its random walk, about to leave a subsurface object.
+    fn is_leaving(&self, dir: Vector3, n: Vector3) -> bool {
+        self.subsurface.is_some() && dir * n > 0.0
+    }
+
+    /// BSDF times the cosine term, for the non-specular part of the material.
+    pub fn eval(&self, dir: Vector3, n: Vector3, wi: Vector3) -> Vector3 {
+        if self.is_leaving(dir, n) {
+            let f = (wi * n).max(0.0) / PI;
+            return Vector3::new(f, f, f);
+        }
+        let n = facing(dir, n);
+        let cos = wi * n;
+        let diffuse = self.diffuse_weight();
+        if self.subsurface.is_some() && cos < 0.0 {
+            let f = self.opaque_weight() * diffuse * -cos / PI;
+            return Vector3::new(f, f, f);
+        }
+        if cos <= 0.0 {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+        let mut f = if self.subsurface.is_some() {
+            0.0
+        } else {
+            diffuse / PI
+        };
+        if let Some(e) = self.phong_exponent() {
+            let cos_a = (reflect(dir, n) * wi).max(0.0);
+            f += (1.0 - diffuse) * (e + 2.0) / (2.0 * PI) * cos_a.powf(e);
+        }
+        (self.opaque_weight() * f * cos) * self.base_col
+    }
+
+    /// Solid angle pdf of sample() returning wi, for the non-specular part of the material.
+    pub fn pdf(&self, dir: Vector3, n: Vector3, wi: Vector3) -> f64 {
+        if self.is_leaving(dir, n) {
+            return (wi * n).max(0.0) / PI;
+        }
+        let n = facing(dir, n);
+        let cos = wi * n;
+        let diffuse = self.diffuse_weight();
+        if self.subsurface.is_some() && cos < 0.0 {
+            return self.opaque_weight() * diffuse * -cos / PI;
+        }
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        let mut pdf = if self.subsurface.is_some() {
+            0.0
+        } else {
+            diffuse * cos / PI
+        };
+        if let Some(e) = self.phong_exponent() {
+            let cos_a = (reflect(dir, n) * wi).max(0.0);
+            pdf += (1.0 - diffuse) * (e + 1.0) / (2.0 * PI) * cos_a.powf(e);
+        }
+        self.opaque_weight() * pdf
+    }
+}
+
+fn reflect(d: Vector3, n: Vector3) -> Vector3 {
+    d - 2.0 * (d * n) * n
+}
+
+/// The normal flipped to the side the ray with direction d arrives from.
+fn facing(d: Vector3, n: Vector3) -> Vector3 {
+    if d * n > 0.0 {
+        -n
+    } else {
+        n
+    }
+}
+
+/// Fraction of light reflected off a smooth dielectric boundary, for unpolarized light.
+/// eta is the ratio of the indices of refraction (incident side over transmitted side).
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {
+        return 1.0; // Total internal reflection
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_s * r_s + r_p * r_p)
+}
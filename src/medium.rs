@@ -0,0 +1,275 @@
+use std::f64::consts::PI;
+
+use crate::grid::DensityGrid;
+use crate::rng::Pcg32;
+use crate::vector3::*;
+
+/// A participating medium, like fog or smoke, that absorbs and scatters light along rays
+/// rather than at surfaces. Coefficients are per unit length, per color channel.
+#[derive(Debug, Clone)]
+pub enum Medium {
+    /// The same density everywhere
+    Homogeneous {
+        sigma_a: Vector3,
+        sigma_s: Vector3,
+        /// Henyey-Greenstein asymmetry: negative scatters back, positive forward
+        g: f64,
+    },
+    /// Density from a voxel grid, which scales the coefficients. Rays are tracked through it
+    /// with null collisions against the majorant grid.
+    Grid {
+        density: Box<DensityGrid>,
+        sigma_a: Vector3,
+        sigma_s: Vector3,
+        g: f64,
+    },
+}
+
+/// Where a ray through a medium got to.
+pub struct MediumSample {
+    /// Distance to the scattering event, None if the ray made it to t_max
+    pub scatter: Option<f64>,
+    /// What the path throughput gets multiplied with
+    pub weight: Vector3,
+}
+
+impl Medium {
+    /// Fog with the given density, which scatters but doesn't absorb.
+    pub fn fog(density: f64, g: f64) -> Medium {
+        Medium::Homogeneous {
+            sigma_a: Vector3::new(0.0, 0.0, 0.0),
+            sigma_s: Vector3::new(density, density, density),
+            g,
+        }
+    }
+
+    /// The inside of a subsurface scattering object, with the given mean free path, where a
+    /// random walk comes back out with about the given albedo. The single scattering albedo
+    /// that gives it is fitted from simulations, as in Cycles (Chiang et al. 2016).
+    pub fn subsurface(albedo: Vector3, mean_free_path: Vector3) -> Medium {
+        let channel = |a: f64, d: f64| {
+            let a = clamp(a, 0.0, 0.999);
+            let single = 1.0 - (a * (-5.09406 + a * (2.61188 - a * 4.31805))).exp();
+            let s = 1.9 - a + 3.5 * (a - 0.8) * (a - 0.8);
+            let sigma_t = 1.0 / (d * s).max(1e-12);
+            (single * sigma_t, (1.0 - single) * sigma_t)
+        };
+        let (sx, ax) = channel(albedo.x, mean_free_path.x);
+        let (sy, ay) = channel(albedo.y, mean_free_path.y);
+        let (sz, az) = channel(albedo.z, mean_free_path.z);
+        Medium::Homogeneous {
+            sigma_a: Vector3::new(ax, ay, az),
+            sigma_s: Vector3::new(sx, sy, sz),
+            g: 0.0,
+        }
+    }
+
+    /// Transforms the coefficients, e.g. into another color space.
+    pub fn map_color(&mut self, f: impl Fn(Vector3) -> Vector3) {
+        match self {
+            Medium::Homogeneous {
+                sigma_a, sigma_s, ..
+            }
+            | Medium::Grid {
+                sigma_a, sigma_s, ..
+            } => {
+                *sigma_a = f(*sigma_a);
+                *sigma_s = f(*sigma_s);
+            }
+        }
+    }
+
+    pub fn g(&self) -> f64 {
+        match self {
+            Medium::Homogeneous { g, .. } | Medium::Grid { g, .. } => *g,
+        }
+    }
+
+    /// Samples a free flight distance along the ray, up to t_max (the next surface).
+    /// In a homogeneous medium, the distance is sampled for a color channel picked by u[0], and
+    /// weighted by the average pdf over the channels, so that colored media don't get noisy.
+    /// Grids use delta tracking, with random numbers seeded from u.
+    /// Channels count in proportion to the throughput of the path so far, which keeps long
+    /// random walks through colored media from piling up noise in channels that don't matter.
+    pub fn sample(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        t_max: f64,
+        throughput: Vector3,
+        u: [f64; 2],
+    ) -> MediumSample {
+        let total = throughput.x + throughput.y + throughput.z;
+        let p = if total > 0.0 {
+            (1.0 / total) * throughput
+        } else {
+            (1.0 / 3.0) * Vector3::new(1.0, 1.0, 1.0)
+        };
+        // Average over the channels, weighted by how likely they are to be picked
+        let avg = |v: Vector3| v * p;
+        match self {
+            Medium::Homogeneous {
+                sigma_a, sigma_s, ..
+            } => {
+                let sigma_t = *sigma_a + *sigma_s;
+                let channel = if u[0] < p.x {
+                    0
+                } else if u[0] < p.x + p.y {
+                    1
+                } else {
+                    2
+                };
+                let st = [sigma_t.x, sigma_t.y, sigma_t.z][channel];
+                let t = if st > 0.0 {
+                    -(1.0 - u[1]).ln() / st
+                } else {
+                    f64::INFINITY
+                };
+                let tr = exp(-t.min(t_max) * sigma_t);
+                if t < t_max {
+                    // Pdf of scattering at t
+                    let pdf = avg(sigma_t.star(tr));
+                    MediumSample {
+                        scatter: Some(t),
+                        weight: (1.0 / pdf) * tr.star(*sigma_s),
+                    }
+                } else {
+                    // Pdf of passing through
+                    let pdf = avg(tr);
+                    MediumSample {
+                        scatter: None,
+                        weight: (1.0 / pdf) * tr,
+                    }
+                }
+            }
+            Medium::Grid {
+                density,
+                sigma_a,
+                sigma_s,
+                ..
+            } => {
+                // Spectral tracking (Kutz et al. 2017): tentative collisions come at the rate of
+                // the majorant of the densest channel. Each is an absorption, a scattering or a
+                // null collision, with probabilities from the coefficients averaged over the
+                // channels (as above), and the throughput makes up for the channels being
+                // different.
+                let mut rng = Pcg32::new(u[0].to_bits(), u[1].to_bits());
+                let sigma_t = *sigma_a + *sigma_s;
+                let scale = sigma_t.max_component();
+                let ones = Vector3::new(1.0, 1.0, 1.0);
+                let mut weight = ones;
+                for (t0, t1, majorant) in density.segments(origin, dir, t_max) {
+                    let mu = majorant * scale;
+                    if mu <= 0.0 {
+                        continue;
+                    }
+                    let mut t = t0;
+                    loop {
+                        t -= (1.0 - rng.uniform()).ln() / mu;
+                        if t >= t1 {
+                            break;
+                        }
+                        let d = density.density(origin + t * dir);
+                        let (a, s) = (d * *sigma_a, d * *sigma_s);
+                        let null = mu * ones - a - s;
+                        let xi = rng.uniform() * mu;
+                        if xi < avg(a) {
+                            return MediumSample {
+                                scatter: None,
+                                weight: Vector3::new(0.0, 0.0, 0.0),
+                            };
+                        }
+                        if xi < avg(a) + avg(s) {
+                            return MediumSample {
+                                scatter: Some(t),
+                                weight: (1.0 / avg(s)) * weight.star(s),
+                            };
+                        }
+                        weight = (1.0 / avg(null)) * weight.star(null);
+                    }
+                }
+                MediumSample {
+                    scatter: None,
+                    weight,
+                }
+            }
+        }
+    }
+
+    /// Fraction of light that makes it dist along the ray. Exact for homogeneous media, an
+    /// unbiased estimate by ratio tracking for grids.
+    pub fn transmittance(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        dist: f64,
+        rng: &mut Pcg32,
+    ) -> Vector3 {
+        match self {
+            Medium::Homogeneous {
+                sigma_a, sigma_s, ..
+            } => exp(-dist * (*sigma_a + *sigma_s)),
+            Medium::Grid {
+                density,
+                sigma_a,
+                sigma_s,
+                ..
+            } => {
+                let sigma_t = *sigma_a + *sigma_s;
+                let scale = sigma_t.max_component();
+                let ones = Vector3::new(1.0, 1.0, 1.0);
+                let mut tr = ones;
+                for (t0, t1, majorant) in density.segments(origin, dir, dist) {
+                    let mu = majorant * scale;
+                    if mu <= 0.0 {
+                        continue;
+                    }
+                    let mut t = t0;
+                    loop {
+                        t -= (1.0 - rng.uniform()).ln() / mu;
+                        if t >= t1 {
+                            break;
+                        }
+                        let d = density.density(origin + t * dir);
+                        tr = tr.star(ones - (d / mu) * sigma_t);
+                    }
+                    if tr.max_component() <= 0.0 {
+                        break;
+                    }
+                }
+                tr
+            }
+        }
+    }
+}
+
+fn exp(v: Vector3) -> Vector3 {
+    Vector3::new(v.x.exp(), v.y.exp(), v.z.exp())
+}
+
+/// Henyey-Greenstein phase function, for cos_theta between the directions of travel before and
+/// after scattering. Integrates to one over the sphere.
+pub fn hg_phase(cos_theta: f64, g: f64) -> f64 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.max(1e-12).sqrt())
+}
+
+/// Samples the direction after scattering, for a ray travelling in dir. The phase function is
+/// sampled exactly, so the pdf is hg_phase.
+pub fn sample_hg(dir: Vector3, g: f64, u: [f64; 2]) -> (Vector3, f64) {
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u[0]
+    } else {
+        let sqr = (1.0 - g * g) / (1.0 - g + 2.0 * g * u[0]);
+        (1.0 + g * g - sqr * sqr) / (2.0 * g)
+    };
+    let cos_theta = clamp(cos_theta, -1.0, 1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u[1];
+    let wi = dir.local_to_world(Vector3::new(
+        sin_theta * phi.cos(),
+        sin_theta * phi.sin(),
+        cos_theta,
+    ));
+    (wi, hg_phase(cos_theta, g))
+}
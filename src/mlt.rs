@@ -0,0 +1,272 @@
+use std::f64::consts::PI;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::config::Config;
+use crate::denoise::Features;
+use crate::film::{Film, PathRadiance};
+use crate::lightsampler::AliasTable;
+use crate::rng::Pcg32;
+use crate::sample_scene;
+use crate::sampler::{Sampler, ONE_MINUS_EPSILON};
+use crate::scene::Scene;
+use crate::tonemapper::luminance;
+
+/// Paths traced up front, to find out how bright the image is, and where to start the chains
+const BOOTSTRAP_SAMPLES: usize = 100_000;
+const CHAINS: usize = 1000;
+/// Chance of a mutation throwing away all the random numbers and starting over, which keeps the
+/// chains from getting stuck in one bright spot
+const LARGE_STEP: f64 = 0.3;
+/// Standard deviation of the small steps, by which every random number of the path gets nudged
+const SIGMA: f64 = 0.01;
+
+/// Primary sample space Metropolis light transport, on top of the path tracer. The path
+/// tracer turns a vector of random numbers into a path, and chains of mutations of those
+/// vectors wander around the ones whose paths carry the most light. Where a chain spends its
+/// time is what makes the image, so once one finds a narrow way for light to get through, it
+/// keeps on taking it.
+///
+/// The chains persist from pass to pass: a pass takes every chain a bit further.
+pub struct Metropolis {
+    /// Average luminance of the paths, over the whole image. The chains only know relative
+    /// brightness, this puts a scale on it.
+    brightness: f64,
+    chains: Vec<Mutex<Chain>>,
+}
+
+/// Where a chain is at: the random numbers of its current path, and what the path brought in.
+struct Chain {
+    sampler: MltSampler,
+    /// For deciding which mutations get accepted
+    rng: Pcg32,
+    pos: (f64, f64),
+    radiance: PathRadiance,
+}
+
+impl Metropolis {
+    /// Traces the bootstrap paths over par threads, and starts the chains at some of them,
+    /// picked in proportion to their luminance. start is the sample count the render starts
+    /// from, so that resumed renders get chains of their own.
+    pub fn new(scene: &Scene, config: &Config, start: usize, par: usize) -> Metropolis {
+        let seed = config.seed.wrapping_mul(0x9e3779b97f4a7c15) ^ start as u64;
+        let chunk = BOOTSTRAP_SAMPLES.div_ceil(par);
+        let weights: Vec<f64> = thread::scope(|s| {
+            let threads: Vec<_> = (0..par)
+                .map(|t| {
+                    s.spawn(move || {
+                        (t * chunk..((t + 1) * chunk).min(BOOTSTRAP_SAMPLES))
+                            .map(|i| {
+                                let mut sampler = MltSampler::new(i as u64, seed);
+                                let (_, l) = trace(scene, config, &mut sampler);
+                                luminance(l.total())
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|t| t.join().unwrap())
+                .collect()
+        });
+        let brightness = weights.iter().sum::<f64>() / BOOTSTRAP_SAMPLES as f64;
+        let table = AliasTable::new(&weights);
+        let chains = (0..CHAINS)
+            .map(|c| {
+                let mut rng = Pcg32::new(c as u64, !seed);
+                let (i, _) = table.sample(rng.uniform()).unwrap();
+                // Same seed, same random numbers, same path as in the bootstrap
+                let mut sampler = MltSampler::new(i as u64, seed);
+                let (pos, radiance) = trace(scene, config, &mut sampler);
+                Mutex::new(Chain {
+                    sampler,
+                    rng,
+                    pos,
+                    radiance,
+                })
+            })
+            .collect();
+        Metropolis { brightness, chains }
+    }
+
+    pub fn chains(&self) -> usize {
+        self.chains.len()
+    }
+
+    /// Takes chain c as far as its share of count mutations per pixel, and returns the film
+    /// its samples got splatted into. That's only a list of the splats, not an image's worth of
+    /// pixels, as there are a lot of chains.
+    pub fn run_chain(&self, c: usize, scene: &Scene, config: &Config, count: usize) -> Film {
+        let total = config.width * config.height * count;
+        let mutations = total * (c + 1) / CHAINS - total * c / CHAINS;
+        // The proposed path and the current one, at most
+        let mut film = Film::splats_only(config.width, config.height, config.filter, 2 * mutations);
+        let mut chain = self.chains[c].lock().unwrap();
+        let chain = &mut *chain;
+        let mut importance = luminance(chain.radiance.total());
+        for _ in 0..mutations {
+            chain.sampler.start_iteration();
+            let (pos, radiance) = trace(scene, config, &mut chain.sampler);
+            let proposed = luminance(radiance.total());
+            let accept = if importance > 0.0 {
+                (proposed / importance).min(1.0)
+            } else {
+                1.0
+            };
+            // Both the current path and the proposed one get splatted, in proportion to the
+            // chance of the chain going on with them, which gets the same image in the end,
+            // only with less noise
+            if proposed > 0.0 {
+                let weight = accept * self.brightness / proposed;
+                film.add_splat(pos.0, pos.1, weight * radiance);
+            }
+            if importance > 0.0 {
+                let weight = (1.0 - accept) * self.brightness / importance;
+                film.add_splat(chain.pos.0, chain.pos.1, weight * chain.radiance);
+            }
+            film.add_splat_sample();
+            if chain.rng.uniform() < accept {
+                chain.sampler.accept();
+                (chain.pos, chain.radiance, importance) = (pos, radiance, proposed);
+            } else {
+                chain.sampler.reject();
+            }
+        }
+        film
+    }
+}
+
+/// Traces the path the sampler's random numbers make, through a position in the image that
+/// the first two of them pick. Returns the position, and the light the path brings back.
+fn trace(scene: &Scene, config: &Config, sampler: &mut MltSampler) -> ((f64, f64), PathRadiance) {
+    let (width, height) = (config.width, config.height);
+    let [sx, sy] = sampler.get_2d();
+    let (px, py) = (sx * width as f64, sy * height as f64);
+    let time = config.shutter * sampler.get_1d();
+    let (origin, dir) = scene.camera.ray(px, py, width, height, time);
+    let mut features = Features::default();
+    let radiance = sample_scene(
+        origin,
+        dir,
+        time,
+        scene,
+        &config.bounces,
+        sampler,
+        &mut features,
+    );
+    ((px, py), radiance)
+}
+
+/// One of the random numbers of a path, and what it was before the mutation that's being tried.
+#[derive(Debug, Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    /// The iteration the number last changed in
+    modified: usize,
+    backup: f64,
+    modified_backup: usize,
+}
+
+/// Hands out the random numbers of a chain's path, mutated from those of the path before. The
+/// numbers get mutated lazily, when they're asked for, so paths can be as long as they like:
+/// a number not asked for in a while gets all the small steps it missed at once.
+struct MltSampler {
+    rng: Pcg32,
+    samples: Vec<PrimarySample>,
+    /// Mutations accepted so far, plus the one being tried. The first path is iteration 0.
+    iteration: usize,
+    large_step: bool,
+    last_large_step: usize,
+    /// Which number is next
+    dimension: usize,
+}
+
+impl MltSampler {
+    fn new(stream: u64, seed: u64) -> MltSampler {
+        MltSampler {
+            rng: Pcg32::new(stream, seed),
+            samples: vec![],
+            iteration: 0,
+            // The first path has fresh numbers all round
+            large_step: true,
+            last_large_step: 0,
+            dimension: 0,
+        }
+    }
+
+    /// Starts on a mutation of the path.
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.uniform() < LARGE_STEP;
+        self.dimension = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    /// Goes back to the numbers before the mutation.
+    fn reject(&mut self) {
+        for x in self.samples.iter_mut() {
+            if x.modified == self.iteration {
+                x.value = x.backup;
+                x.modified = x.modified_backup;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    /// The next number, brought up to date with the mutations so far.
+    fn next(&mut self) -> f64 {
+        let i = self.dimension;
+        self.dimension += 1;
+        if i == self.samples.len() {
+            // A number no path has gotten to before, which may as well be anything
+            let value = self.rng.uniform();
+            self.samples.push(PrimarySample {
+                value,
+                modified: self.iteration,
+                backup: value,
+                modified_backup: self.iteration,
+            });
+            return value;
+        }
+        let x = &mut self.samples[i];
+        // Numbers from before the last accepted large step were thrown away with it
+        if x.modified < self.last_large_step {
+            x.value = self.rng.uniform();
+            x.modified = self.last_large_step;
+        }
+        x.backup = x.value;
+        x.modified_backup = x.modified;
+        if self.large_step {
+            x.value = self.rng.uniform();
+        } else if x.modified < self.iteration {
+            // Small steps add up to a normal distribution, wrapped around [0, 1)
+            let steps = (self.iteration - x.modified) as f64;
+            let (u1, u2) = (self.rng.uniform(), self.rng.uniform());
+            let normal = (-2.0 * (1.0 - u1).ln()).sqrt() * (2.0 * PI * u2).cos();
+            x.value += SIGMA * steps.sqrt() * normal;
+            x.value = (x.value - x.value.floor()).min(ONE_MINUS_EPSILON);
+        }
+        x.modified = self.iteration;
+        x.value
+    }
+}
+
+impl Sampler for MltSampler {
+    /// Chains don't go pixel by pixel, they pick their own: this only goes back to the first
+    /// number of the path.
+    fn start_pixel_sample(&mut self, _x: usize, _y: usize, _index: usize) {
+        self.dimension = 0;
+    }
+    fn get_1d(&mut self) -> f64 {
+        self.next()
+    }
+    fn get_2d(&mut self) -> [f64; 2] {
+        [self.next(), self.next()]
+    }
+}
@@ -5,15 +5,6 @@ use std::vec;
 
 use crate::vector3::{Triangle, Vector3};
 
-/// Macro that acts like sscanf.
-/// https://stackoverflow.com/questions/31046763/does-rust-have-anything-like-scanf
-macro_rules! scan {
-    ( $string:expr, $sep:expr, $( $x:ty ),+ ) => {{
-        let mut iter = $string.split($sep);
-        ($(iter.next().and_then(|word| word.parse::<$x>().ok()),)*)
-    }}
-}
-
 /// Based on https://doc.rust-lang.org/rust-by-example/std_misc/file/read_lines.html
 /// Returns a vector of triangles
 pub fn load_obj_file(path: String) -> Vec<Triangle> {
@@ -22,11 +13,11 @@ pub fn load_obj_file(path: String) -> Vec<Triangle> {
         // Consumes the iterator, returns an (Optional) String
         let mut verts: Vec<Vector3> = vec![];
         let mut tris: Vec<Triangle> = vec![];
-        for line in lines.flatten() {
+        for line in lines.map_while(Result::ok) {
             // Very brutish parser, easy to break.
             // TODO: Error handling for erroneous files
             let words = line.split(" ").collect::<Vec<&str>>();
-            let command = words[0].chars().nth(0);
+            let command = words[0].chars().next();
             if command == Some('v') {
                 let x = words[1].parse().unwrap();
                 let y = words[2].parse().unwrap();
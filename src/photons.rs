@@ -0,0 +1,424 @@
+use std::f64::consts::PI;
+use std::thread;
+
+use crate::config::Config;
+use crate::denoise::Features;
+use crate::film::PathRadiance;
+use crate::kdtree::KdTree;
+use crate::light::Light;
+use crate::lightsampler::AliasTable;
+use crate::material::Material;
+use crate::rng::Pcg32;
+use crate::sampler::Sampler;
+use crate::scene::{Scene, Surface};
+use crate::spectrum::Channels;
+use crate::vector3::*;
+use crate::{emitted, hit_features, mis_weight, sample_light, Scatterer};
+
+/// Photons, and camera paths through glass and mirrors, are cut off after this many bounces,
+/// whatever russian roulette says.
+const MAX_BOUNCES: usize = 32;
+/// How much of the gather area is kept from one pass to the next (Knaus and Zwicker's alpha).
+/// Smaller shrinks it faster, for less blur but more noise.
+const ALPHA: f64 = 2.0 / 3.0;
+/// Photons traced from every emitter up front, to find out how many to give each
+const PILOT_PHOTONS: usize = 256;
+
+/// Light left on a surface by a photon.
+struct Photon {
+    pos: Vector3,
+    /// Which way the photon was going
+    dir: Vector3,
+    power: Vector3,
+}
+
+/// The photons of one pass of progressive photon mapping, and the radius to gather them in.
+/// Every pass traces photons of its own, and gathers them in a smaller radius than the pass
+/// before, so that the passes are independent estimates, which average out to the right answer
+/// as both the noise and the blur go away.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    tree: KdTree,
+    radius: f64,
+    /// How many photons were traced, whether they got stored anywhere or not
+    traced: usize,
+}
+
+impl PhotonMap {
+    /// Traces the photons for the pass that takes sample index (0 for the first pass) in every
+    /// pixel, over par threads. The photons come out the same however many threads there are.
+    pub fn trace(
+        scene: &Scene,
+        config: &Config,
+        emitters: &Emitters,
+        index: usize,
+        par: usize,
+    ) -> PhotonMap {
+        let traced = config.photons.unwrap_or(config.width * config.height);
+        let chunk = traced.div_ceil(par);
+        let seed = config.seed.wrapping_mul(0x9e3779b97f4a7c15) ^ index as u64;
+        let photons: Vec<Photon> = thread::scope(|s| {
+            let threads: Vec<_> = (0..par)
+                .map(|t| {
+                    s.spawn(move || {
+                        let mut photons = vec![];
+                        for i in t * chunk..((t + 1) * chunk).min(traced) {
+                            let mut rng = Pcg32::new(i as u64, seed);
+                            if let Some(emitted) = emitters.emit(scene, &mut rng) {
+                                trace_photon(scene, config, emitted, &mut rng, &mut photons);
+                            }
+                        }
+                        photons
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|t| t.join().unwrap())
+                .collect()
+        });
+        let positions: Vec<Vector3> = photons.iter().map(|p| p.pos).collect();
+        // Each pass keeps (i + alpha) / (i + 1) of the area of the one before
+        let area = (1..=index).fold(1.0, |a, i| a * (i as f64 + ALPHA) / (i as f64 + 1.0));
+        PhotonMap {
+            tree: KdTree::new(&positions),
+            photons,
+            radius: config.photon_radius * area.sqrt(),
+            traced,
+        }
+    }
+
+    /// Radiance scattered along -dir at pos, on a surface with the given material and normal,
+    /// estimated from the density of the photons around it.
+    fn gather(&self, pos: Vector3, normal: Vector3, dir: Vector3, material: &Material) -> Vector3 {
+        let mut sum = Vector3::new(0.0, 0.0, 0.0);
+        self.tree.within(pos, self.radius, |i| {
+            let p = &self.photons[i];
+            // The photon's power already went through the surface at an angle, so the BSDF
+            // is wanted without its cosine
+            let cos = (p.dir * normal).abs();
+            if cos > 0.0 {
+                let f = material.eval(dir, normal, -p.dir);
+                sum = sum + (1.0 / cos) * f.star(p.power);
+            }
+        });
+        (1.0 / (PI * self.radius * self.radius * self.traced as f64)) * sum
+    }
+}
+
+/// What photons are traced from: the lights, and the background. Light from infinity (the sun
+/// and the background) comes in over a disk as wide as the objects it can land on, which leaves
+/// out emissive ones: a big light far away would make the disk so wide that hardly any photons
+/// get anywhere near the rest. Area lights away from the rest aim their photons at it. Lights
+/// are black in all the scenes, so it's no loss.
+pub struct Emitters {
+    /// Over the lights, followed by the background
+    table: AliasTable,
+    center: Vector3,
+    radius: f64,
+    /// How far back photons from infinity start from the disk, to be clear of the whole scene
+    back: f64,
+}
+
+impl Emitters {
+    /// Emitters get picked in proportion to how much power their photons leave on surfaces, as
+    /// found by a few photons from each of them. Going by their power alone, a big light far
+    /// off would get nearly all of the photons, and send most of them into the void. Half of
+    /// them are shared out evenly though, in case the pilot photons all missed.
+    pub fn new(scene: &Scene, config: &Config) -> Emitters {
+        let black = Vector3::new(0.0, 0.0, 0.0);
+        let lit: Vec<Aabb> = scene
+            .objects
+            .iter()
+            .filter(|g| g.material.emissive == black)
+            .map(|g| g.geometry.bounds())
+            .collect();
+        let (all_center, all_radius) = scene.bounds().bounding_sphere();
+        let (center, radius) = match lit.iter().copied().reduce(|a, b| a.union(&b)) {
+            Some(lit) => lit.bounding_sphere(),
+            None => (all_center, all_radius),
+        };
+        let mut emitters = Emitters {
+            table: AliasTable::new(&[]),
+            center,
+            radius,
+            back: (center - all_center).norm() + radius + all_radius,
+        };
+        // For every emitter, whether it gives off any light, and how much of it gets stored
+        let pilot: Vec<(bool, f64)> = (0..scene.lights.len() + 1)
+            .map(|i| {
+                let (mut emits, mut photons) = (false, vec![]);
+                for j in 0..PILOT_PHOTONS {
+                    let mut rng = Pcg32::new(j as u64, i as u64);
+                    if let Some(emitted) = emitters.emit_from(scene, i, &mut rng) {
+                        emits |= emitted.2.max_component() > 0.0;
+                        trace_photon(scene, config, emitted, &mut rng, &mut photons);
+                    }
+                }
+                let power = photons.iter().map(|p| p.power.x + p.power.y + p.power.z);
+                (emits, power.sum::<f64>() / (3 * PILOT_PHOTONS) as f64)
+            })
+            .collect();
+        let total: f64 = pilot.iter().map(|(_, stored)| stored).sum();
+        let emitting = pilot.iter().filter(|(emits, _)| *emits).count();
+        let weights: Vec<f64> = pilot
+            .iter()
+            .map(|&(emits, stored)| match (emits, total > 0.0) {
+                (false, _) => 0.0,
+                (true, true) => 0.5 * stored / total + 0.5 / emitting as f64,
+                (true, false) => 1.0,
+            })
+            .collect();
+        emitters.table = AliasTable::new(&weights);
+        emitters
+    }
+
+    /// Where a photon from infinity going in direction dir starts: on the disk, moved back out
+    /// of the scene.
+    fn disk_point(&self, dir: Vector3, u: [f64; 2]) -> Vector3 {
+        let (x, y) = concentric_disk(u);
+        let on_disk = self.center + self.radius * dir.local_to_world(Vector3::new(x, y, 0.0));
+        on_disk - self.back * dir
+    }
+
+    /// From a point on a light outside the disk's sphere, only the directions towards the
+    /// sphere lead anywhere lit, so photons from there go in the cone around it. Returns the
+    /// direction and its pdf, or None if the point is inside.
+    fn aim(&self, pos: Vector3, u: [f64; 2]) -> Option<(Vector3, f64)> {
+        let to_center = self.center - pos;
+        let dist = to_center.norm();
+        if dist <= self.radius {
+            return None;
+        }
+        let sin_max = self.radius / dist;
+        let cos_max = (1.0 - sin_max * sin_max).sqrt();
+        let dir = (1.0 / dist) * to_center;
+        Some((
+            dir.local_to_world(uniform_cone(u, cos_max)),
+            1.0 / (2.0 * PI * (1.0 - cos_max)),
+        ))
+    }
+
+    /// Samples a photon: where it starts, which way it goes, and how much power it carries.
+    fn emit(&self, scene: &Scene, rng: &mut Pcg32) -> Option<(Vector3, Vector3, Vector3)> {
+        let (index, pmf) = self.table.sample(rng.uniform())?;
+        let (origin, dir, power) = self.emit_from(scene, index, rng)?;
+        Some((origin, dir, (1.0 / pmf) * power))
+    }
+
+    /// Samples a photon from the emitter with the given index, the background coming after
+    /// the lights.
+    fn emit_from(
+        &self,
+        scene: &Scene,
+        index: usize,
+        rng: &mut Pcg32,
+    ) -> Option<(Vector3, Vector3, Vector3)> {
+        let u_pos = [rng.uniform(), rng.uniform()];
+        let u_dir = [rng.uniform(), rng.uniform()];
+        let disk = PI * self.radius * self.radius;
+        match scene.lights.get(index) {
+            Some(Light::Directional {
+                dir,
+                irradiance,
+                cos_max,
+            }) => {
+                let w = if *cos_max >= 1.0 {
+                    *dir
+                } else {
+                    dir.local_to_world(uniform_cone(u_dir, *cos_max))
+                };
+                Some((self.disk_point(w, u_pos), w, disk * *irradiance))
+            }
+            Some(light) => {
+                let mut es = light.sample_le(u_pos, u_dir)?;
+                if let (Light::Area { .. }, Some((dir, pdf))) = (light, self.aim(es.pos, u_dir)) {
+                    es.le = light.emitted(-dir, es.normal);
+                    (es.dir, es.pdf_dir) = (dir, pdf);
+                }
+                let cos = if es.normal == Vector3::new(0.0, 0.0, 0.0) {
+                    1.0
+                } else {
+                    (es.normal * es.dir).abs()
+                };
+                let power = (cos / (es.pdf_pos * es.pdf_dir)) * es.le;
+                Some((es.pos + 0.001 * es.dir, es.dir, power))
+            }
+            None => {
+                // Towards the part of the sky the light comes from
+                let w = uniform_cone(u_dir, -1.0);
+                let power = (4.0 * PI * disk) * scene.background(w);
+                Some((self.disk_point(-w, u_pos), -w, power))
+            }
+        }
+    }
+}
+
+/// Traces a photon around the scene, as it left an emitter (origin, direction and power), and
+/// stores it wherever it lands on a surface that isn't perfectly smooth, except for the first
+/// one: light straight from the emitters is left to the camera paths.
+fn trace_photon(
+    scene: &Scene,
+    config: &Config,
+    emitted: (Vector3, Vector3, Vector3),
+    rng: &mut Pcg32,
+    photons: &mut Vec<Photon>,
+) {
+    let (mut origin, mut dir, power) = emitted;
+    let time = config.shutter * rng.uniform();
+    let mut beta = Vector3::new(1.0, 1.0, 1.0);
+    for bounces in 0..MAX_BOUNCES {
+        let hit = match scene.intersect(origin, dir, time) {
+            Some(hit) => hit,
+            None => return,
+        };
+        let g = match hit.surface {
+            Surface::Object(g) => g,
+            Surface::Light(_) => return,
+        };
+        let pos = origin + hit.dist * dir;
+        if bounces > 0 && !g.material.is_specular() {
+            photons.push(Photon {
+                pos,
+                dir,
+                power: power.star(beta),
+            });
+        }
+        let u = [rng.uniform(), rng.uniform(), rng.uniform()];
+        let s = match g.material.sample(dir, hit.normal, u) {
+            Some(s) => s,
+            None => return,
+        };
+        beta = beta.star(s.weight);
+        if bounces + 1 > config.bounces.min_depth {
+            let survival = beta.max_component().min(0.95);
+            if rng.uniform() >= survival {
+                return;
+            }
+            beta = (1.0 / survival) * beta;
+        }
+        origin = pos + 0.001 * s.wi;
+        dir = s.wi;
+    }
+}
+
+/// Traces a camera path through glass and mirrors, to the first surface that isn't perfectly
+/// smooth. Light comes to that surface directly from the lights, sampled the way the path
+/// tracer does it, and from everywhere else by way of the photons around it. Returns the
+/// radiance the path carries back, and records the first hit in features.
+pub fn sample_scene(
+    origin: Vector3,
+    dir: Vector3,
+    time: f64,
+    scene: &Scene,
+    photons: &PhotonMap,
+    sampler: &mut dyn Sampler,
+    features: &mut Features,
+) -> PathRadiance {
+    let mut col = PathRadiance::default();
+    let mut beta = Vector3::new(1.0, 1.0, 1.0);
+    let (mut orig, mut dir) = (origin, dir);
+    for depth in 0..MAX_BOUNCES {
+        let (u_pick, u_light, u_shadow) = (sampler.get_1d(), sampler.get_2d(), sampler.get_1d());
+        let (u_lobe, u_dir) = (sampler.get_1d(), sampler.get_2d());
+        // Only smooth bounces got the path here, which lights can't be sampled through, so
+        // whatever it runs into counts in full
+        let hit = match scene.intersect(orig, dir, time) {
+            Some(hit) => hit,
+            None => {
+                col.add(depth, escaped(scene, dir, None).star(beta));
+                break;
+            }
+        };
+        let pos = orig + hit.dist * dir;
+        if depth == 0 {
+            *features = hit_features(scene, &hit, pos, origin, dir);
+        }
+        col.add(depth, emitted(scene, &hit, dir).star(beta));
+        let g = match hit.surface {
+            Surface::Object(g) => g,
+            Surface::Light(_) => break,
+        };
+        let mat = &g.material;
+        let rough = !mat.is_specular();
+        if rough {
+            let surface = Scatterer::Surface(g, mat, hit.normal);
+            let u = (u_pick, u_light, u_shadow);
+            let direct = sample_light(
+                scene,
+                pos,
+                hit.normal,
+                dir,
+                &surface,
+                None,
+                time,
+                u,
+                &Channels::Rgb,
+            );
+            col.add(depth + 1, direct.star(beta));
+            let indirect = photons.gather(pos, hit.normal, dir, mat);
+            col.add(depth + 2, indirect.star(beta));
+        }
+        let s = match mat.sample(dir, hit.normal, [u_lobe, u_dir[0], u_dir[1]]) {
+            Some(s) => s,
+            None => break,
+        };
+        if rough && !s.is_specular() {
+            // The other half of direct lighting: the lights the bounce runs into. Anything
+            // further is in the photons.
+            let light = bounce_light(scene, pos, hit.normal, s.wi, s.pdf, time);
+            col.add(depth + 1, light.star(beta.star(s.weight)));
+            break;
+        }
+        beta = beta.star(s.weight);
+        if beta.max_component() <= 0.0 {
+            break;
+        }
+        orig = pos + 0.001 * s.wi;
+        dir = s.wi;
+    }
+    col
+}
+
+/// Light from the background and the sun, along a ray with direction dir that escaped. If the
+/// ray came from a bounce that light sampling competes with, bounce has its position, normal and
+/// pdf, to weight the sun with.
+fn escaped(scene: &Scene, dir: Vector3, bounce: Option<(Vector3, Vector3, f64)>) -> Vector3 {
+    let mut col = scene.background(dir);
+    for (i, light) in scene.lights.iter().enumerate() {
+        let le = light.escaped(dir);
+        if le.max_component() > 0.0 {
+            let weight = match bounce {
+                Some((p, n, pdf)) => mis_weight(Some(pdf), scene, p, n, i, dir),
+                None => 1.0,
+            };
+            col = col + weight * le;
+        }
+    }
+    col
+}
+
+/// Light a bounce from pos (with normal n) in direction wi, with the given pdf, runs into
+/// straight away, weighted against sampling the lights.
+fn bounce_light(
+    scene: &Scene,
+    pos: Vector3,
+    n: Vector3,
+    wi: Vector3,
+    pdf: f64,
+    time: f64,
+) -> Vector3 {
+    let origin = pos + 0.001 * wi;
+    let hit = match scene.intersect(origin, wi, time) {
+        Some(hit) => hit,
+        None => return escaped(scene, wi, Some((pos, n, pdf))),
+    };
+    let le = emitted(scene, &hit, wi);
+    if le.max_component() <= 0.0 {
+        return le;
+    }
+    match hit.light {
+        Some(i) => mis_weight(Some(pdf), scene, pos, n, i, wi) * le,
+        None => le,
+    }
+}
@@ -0,0 +1,55 @@
+/// The PCG32 random number generator (O'Neill 2014), with 64 bits of state and a choice of
+/// 2^63 streams. Deterministic, unlike the thread local generator of the rand crate, and cheap
+/// to skip ahead in.
+#[derive(Debug, Clone)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+
+const PCG32_MULT: u64 = 0x5851f42d4c957f2d;
+
+impl Pcg32 {
+    /// Generator for the given stream, starting from a state derived from the seed.
+    pub fn new(stream: u64, seed: u64) -> Pcg32 {
+        let mut rng = Pcg32 {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(PCG32_MULT).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Uniformly distributed in [0, 1), with the full 53 bits of precision.
+    pub fn uniform(&mut self) -> f64 {
+        let bits = ((self.next_u32() as u64) << 32) | self.next_u32() as u64;
+        (bits >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+
+    /// Skips ahead by delta numbers, in O(log delta) time.
+    pub fn advance(&mut self, delta: u64) {
+        let (mut cur_mult, mut cur_plus) = (PCG32_MULT, self.inc);
+        let (mut acc_mult, mut acc_plus) = (1u64, 0u64);
+        let mut delta = delta;
+        while delta > 0 {
+            if delta & 1 != 0 {
+                acc_mult = acc_mult.wrapping_mul(cur_mult);
+                acc_plus = acc_plus.wrapping_mul(cur_mult).wrapping_add(cur_plus);
+            }
+            cur_plus = cur_mult.wrapping_add(1).wrapping_mul(cur_plus);
+            cur_mult = cur_mult.wrapping_mul(cur_mult);
+            delta /= 2;
+        }
+        self.state = acc_mult.wrapping_mul(self.state).wrapping_add(acc_plus);
+    }
+}
@@ -0,0 +1,336 @@
+use std::sync::OnceLock;
+
+use crate::rng::Pcg32;
+
+/// Source of the random numbers of a render. Each pixel sample asks for a sequence of
+/// "dimensions": first the position within the pixel, then numbers for the light and BSDF
+/// sampling at each bounce. Samplers other than the independent one make sure that, for a given
+/// dimension, the samples of a pixel are well spread out over [0, 1).
+///
+/// The numbers only depend on the seed, the pixel, the sample index and the dimension. That
+/// makes renders reproducible, no matter how the pixels are split between threads.
+pub trait Sampler {
+    /// Starts sample number `index` of pixel (x, y), from the first dimension.
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> [f64; 2];
+    /// The position within the pixel. Asked for first, at every pixel sample.
+    fn get_pixel_2d(&mut self) -> [f64; 2] {
+        self.get_2d()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    /// Plain uniform random numbers
+    Independent,
+    /// Jittered strata, shuffled between dimensions
+    Stratified,
+    /// Owen scrambled Halton sequence
+    Halton,
+    /// Owen scrambled Sobol (0,2)-sequence, shuffled between dimensions
+    Sobol,
+}
+impl SamplerKind {
+    pub fn by_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "independent" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None,
+        }
+    }
+
+    /// A sampler for renders with spp samples per pixel.
+    pub fn create(&self, spp: usize, seed: u64) -> Box<dyn Sampler> {
+        let pixel = PixelSample {
+            seed,
+            ..Default::default()
+        };
+        let rng = Pcg32::new(0, seed);
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler { pixel, rng }),
+            SamplerKind::Stratified => {
+                // The grid for 2D strata is as square as spp allows, with no strata left over
+                let nx = (1..=spp)
+                    .take_while(|n| n * n <= spp)
+                    .filter(|n| spp.is_multiple_of(*n))
+                    .last()
+                    .unwrap_or(1);
+                Box::new(StratifiedSampler {
+                    spp,
+                    grid: (nx, spp / nx),
+                    pixel,
+                    rng,
+                })
+            }
+            SamplerKind::Halton => Box::new(HaltonSampler { pixel }),
+            SamplerKind::Sobol => Box::new(SobolSampler { pixel }),
+        }
+    }
+}
+
+/// Where in the sequence of samples a sampler is.
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelSample {
+    seed: u64,
+    x: usize,
+    y: usize,
+    index: usize,
+    dimension: usize,
+}
+impl PixelSample {
+    fn start(&mut self, x: usize, y: usize, index: usize) {
+        *self = PixelSample {
+            seed: self.seed,
+            x,
+            y,
+            index,
+            dimension: 0,
+        };
+    }
+    /// Hash of the pixel and the current dimension, then moves on to the next dimension.
+    fn next_dimension(&mut self) -> u64 {
+        self.dimension += 1;
+        hash(&[
+            self.x as u64,
+            self.y as u64,
+            self.dimension as u64 - 1,
+            self.seed,
+        ])
+    }
+    /// Points the generator to the numbers reserved for this pixel sample: every pixel has
+    /// its own stream, in which every sample gets 2^16 numbers.
+    fn seek(&self, rng: &mut Pcg32) {
+        *rng = Pcg32::new(hash(&[self.x as u64, self.y as u64]), self.seed);
+        rng.advance(self.index as u64 * 65536);
+    }
+}
+
+pub struct IndependentSampler {
+    pixel: PixelSample,
+    rng: Pcg32,
+}
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel.start(x, y, index);
+        self.pixel.seek(&mut self.rng);
+    }
+    fn get_1d(&mut self) -> f64 {
+        self.rng.uniform()
+    }
+    fn get_2d(&mut self) -> [f64; 2] {
+        [self.rng.uniform(), self.rng.uniform()]
+    }
+}
+
+/// Splits every dimension into spp strata (2D ones into a grid), and puts one jittered sample
+/// in each. Which sample gets which stratum is shuffled per pixel and dimension, so the
+/// strata of different dimensions don't line up.
+pub struct StratifiedSampler {
+    spp: usize,
+    /// Columns and rows of the 2D strata, which multiply to spp
+    grid: (usize, usize),
+    pixel: PixelSample,
+    /// For the jitter
+    rng: Pcg32,
+}
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel.start(x, y, index);
+        self.pixel.seek(&mut self.rng);
+    }
+    fn get_1d(&mut self) -> f64 {
+        let h = self.pixel.next_dimension();
+        let stratum = permutation_element(self.pixel.index % self.spp, self.spp, h);
+        ((stratum as f64 + self.rng.uniform()) / self.spp as f64).min(ONE_MINUS_EPSILON)
+    }
+    fn get_2d(&mut self) -> [f64; 2] {
+        let h = self.pixel.next_dimension();
+        let (nx, ny) = self.grid;
+        let stratum = permutation_element(self.pixel.index % self.spp, self.spp, h);
+        [
+            ((stratum % nx) as f64 + self.rng.uniform()) / nx as f64,
+            ((stratum / nx) as f64 + self.rng.uniform()) / ny as f64,
+        ]
+    }
+}
+
+/// The Halton sequence, with a different prime base for every dimension. Each pixel gets its
+/// own Owen scrambling of the digits.
+pub struct HaltonSampler {
+    pixel: PixelSample,
+}
+impl HaltonSampler {
+    fn sample(&mut self) -> f64 {
+        let primes = primes();
+        // Dimensions past the last prime start over, with a different scramble
+        let base = primes[self.pixel.dimension % primes.len()];
+        let h = self.pixel.next_dimension();
+        owen_scrambled_radical_inverse(base, self.pixel.index as u64, h)
+    }
+}
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel.start(x, y, index);
+    }
+    fn get_1d(&mut self) -> f64 {
+        self.sample()
+    }
+    fn get_2d(&mut self) -> [f64; 2] {
+        [self.sample(), self.sample()]
+    }
+}
+
+/// The first two dimensions of the Sobol sequence, Owen scrambled. Higher dimensions are
+/// "padded": every 1D or 2D request gets its own scramble and its own shuffle of the sample
+/// order. After "Practical Hash-based Owen Scrambling" (Burley 2020).
+pub struct SobolSampler {
+    pixel: PixelSample,
+}
+impl SobolSampler {
+    fn sample(&mut self) -> [f64; 2] {
+        let h = self.pixel.next_dimension();
+        let index = nested_uniform_scramble(self.pixel.index as u32, h as u32);
+        let x = nested_uniform_scramble(sobol(index, 0), hash(&[h, 0]) as u32);
+        let y = nested_uniform_scramble(sobol(index, 1), hash(&[h, 1]) as u32);
+        [to_unit(x), to_unit(y)]
+    }
+}
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel.start(x, y, index);
+    }
+    fn get_1d(&mut self) -> f64 {
+        self.sample()[0]
+    }
+    fn get_2d(&mut self) -> [f64; 2] {
+        self.sample()
+    }
+}
+
+pub const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+fn to_unit(x: u32) -> f64 {
+    (x as f64 / 4294967296.0).min(ONE_MINUS_EPSILON)
+}
+
+/// The bits of a 64 bit value, mixed up. From pbrt-v4 (which credits David Stafford).
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+pub fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(0x9e3779b97f4a7c15, |h, v| mix_bits(h ^ mix_bits(*v)))
+}
+
+/// Element i of a random permutation of 0..n, picked by the seed. After Kensler's
+/// "Correlated Multi-Jittered Sampling".
+fn permutation_element(i: usize, n: usize, seed: u64) -> usize {
+    let (mut i, n, p) = (i as u32, n as u32, seed as u32);
+    let mut w = n.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(p) % n) as usize
+}
+
+/// The digits of a in the given base, mirrored around the decimal point. Every digit is
+/// permuted depending on the digits before it, which is Owen scrambling.
+fn owen_scrambled_radical_inverse(base: u64, mut a: u64, hash: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut reversed: u64 = 0;
+    // Digits until they stop making a difference
+    while 1.0 - (base - 1) as f64 * inv_base_m < 1.0 {
+        let next = a / base;
+        let digit = a - next * base;
+        let digit_hash = mix_bits(hash ^ reversed);
+        let digit = permutation_element(digit as usize, base as usize, digit_hash) as u64;
+        reversed = reversed * base + digit;
+        inv_base_m *= inv_base;
+        a = next;
+    }
+    (inv_base_m * reversed as f64).min(ONE_MINUS_EPSILON)
+}
+
+/// The first primes, the bases of the Halton sequence.
+fn primes() -> &'static [u64] {
+    static PRIMES: OnceLock<Vec<u64>> = OnceLock::new();
+    PRIMES.get_or_init(|| {
+        let mut primes: Vec<u64> = vec![];
+        let mut n = 2;
+        while primes.len() < 256 {
+            if primes.iter().all(|p| n % p != 0) {
+                primes.push(n);
+            }
+            n += 1;
+        }
+        primes
+    })
+}
+
+/// Dimension 0 or 1 of the Sobol sequence, as a 32 bit fixed point fraction.
+fn sobol(index: u32, dimension: usize) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+    // The second dimension's direction numbers come from the polynomial x + 1
+    let mut v: u32 = 1 << 31;
+    let mut x = 0;
+    let mut index = index;
+    while index != 0 {
+        if index & 1 != 0 {
+            x ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    x
+}
+
+/// Owen scrambling of all 32 bits of x at once: reversing the bits turns it into a
+/// Laine-Karras style permutation, which only lets higher bits affect lower ones.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    // Hash by Nathan Vegdahl, an improvement on the original Laine-Karras one
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x.reverse_bits()
+}
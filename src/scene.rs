@@ -0,0 +1,955 @@
+use crate::animation::{Animation, ObjectTracks, Track};
+use crate::bvh::Bvh;
+use crate::color::ColorSpace;
+use crate::grid::{DensityGrid, Grid};
+use crate::light::Light;
+use crate::lightsampler::{AliasTable, LightSampler, LightSampling};
+use crate::material::Material;
+use crate::medium::Medium;
+use crate::rng::Pcg32;
+use crate::spectrum::Spectral;
+use crate::texture::Texture;
+use crate::vector3::*;
+
+/// Everything that can be rendered: geometry, as well as the lights illuminating it.
+pub struct Scene {
+    pub camera: Camera,
+    pub objects: Vec<Renderable>,
+    /// The lights of the scene, followed by a light for every primitive of every emissive object.
+    pub lights: Vec<Light>,
+    pub light_sampler: LightSampler,
+    light_sampling: LightSampling,
+    /// Picks lights in proportion to their power, whatever the light sampling. For light paths,
+    /// which don't start out from any point in particular, and can't start at infinity.
+    pub emission_sampler: AliasTable,
+    /// Over the objects, with their bounds over the whole motion
+    bvh: Bvh,
+    /// Medium filling all space outside of objects, like fog
+    pub atmosphere: Option<Medium>,
+    /// Latitude-longitude environment map, seen by rays that escape. None for the clear color.
+    pub background: Option<Texture>,
+    /// Keyframes, for rendering a sequence of frames. Empty for a still scene.
+    pub animation: Animation,
+    /// For rendering spectrally. None for RGB.
+    pub spectral: Option<Spectral>,
+    /// From linear sRGB, which the scene colors are given in, to the working space.
+    to_working: Matrix3,
+    /// For every object, the index of the first object with an equal material.
+    pub material_ids: Vec<usize>,
+    /// How many of the lights are not part of an object.
+    standalone_lights: usize,
+    /// For emissive objects, the index of the light made from the first primitive.
+    object_lights: Vec<Option<usize>>,
+}
+
+/// What a ray ran into.
+#[derive(Clone, Copy)]
+pub enum Surface<'a> {
+    Object(&'a Renderable),
+    Light(&'a Light),
+}
+
+pub struct Hit<'a> {
+    pub dist: f64,
+    /// Geometric normal, not necessarily facing the ray.
+    pub normal: Vector3,
+    pub surface: Surface<'a>,
+    /// Index of the light that was hit, if the surface is emissive.
+    pub light: Option<usize>,
+    /// Index of the object that was hit, if it's not a standalone light.
+    pub object: Option<usize>,
+    /// For triangles, the weights of their vertices at the hit.
+    pub barycentric: Option<Vector3>,
+}
+
+impl Scene {
+    pub fn new(
+        camera: Camera,
+        objects: Vec<Renderable>,
+        lights: Vec<Light>,
+        light_sampling: LightSampling,
+        working: ColorSpace,
+    ) -> Scene {
+        let to_working = ColorSpace::Srgb.conversion(working);
+        let convert = |c: Vector3| {
+            let c = to_working * c;
+            Vector3::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0))
+        };
+        let mut objects = objects;
+        for g in objects.iter_mut() {
+            g.material.base_col = convert(g.material.base_col);
+            g.material.emissive = convert(g.material.emissive);
+            if let Some(m) = g.medium.as_mut() {
+                m.map_color(convert);
+            }
+            // The albedo is in the working space already, the mean free path is just a length
+            if let (Some(mfp), None) = (g.material.subsurface, &g.medium) {
+                g.medium = Some(Medium::subsurface(g.material.base_col, mfp));
+            }
+        }
+        let standalone_lights = lights.len();
+        let mut lights = lights;
+        for l in lights.iter_mut() {
+            l.map_color(convert);
+        }
+        let object_lights = object_lights(&objects, &mut lights);
+        let material_ids = objects
+            .iter()
+            .map(|g| {
+                objects
+                    .iter()
+                    .position(|other| other.material == g.material)
+                    .unwrap()
+            })
+            .collect();
+        let boxes: Vec<Aabb> = objects.iter().map(|g| g.geometry.bounds()).collect();
+        Scene {
+            camera,
+            bvh: Bvh::new(&boxes),
+            light_sampler: LightSampler::new(light_sampling, &lights),
+            light_sampling,
+            emission_sampler: emission_sampler(&lights),
+            objects,
+            lights,
+            atmosphere: None,
+            background: None,
+            animation: Animation::default(),
+            spectral: None,
+            to_working,
+            material_ids,
+            standalone_lights,
+            object_lights,
+        }
+    }
+
+    /// Names of the scenes by_name knows
+    pub const NAMES: [&'static str; 8] = [
+        "example",
+        "lights",
+        "volumes",
+        "cloud",
+        "subsurface",
+        "motion",
+        "animation",
+        "prism",
+    ];
+
+    pub fn by_name(
+        name: &str,
+        light_sampling: LightSampling,
+        working: ColorSpace,
+    ) -> Option<Scene> {
+        match name {
+            "example" => Some(Scene::example(light_sampling, working)),
+            "lights" => Some(Scene::lights(light_sampling, working)),
+            "volumes" => Some(Scene::volumes(light_sampling, working)),
+            "cloud" => Some(Scene::cloud(light_sampling, working)),
+            "subsurface" => Some(Scene::subsurface(light_sampling, working)),
+            "motion" => Some(Scene::motion(light_sampling, working)),
+            "animation" => Some(Scene::animation(light_sampling, working)),
+            "prism" => Some(Scene::prism(light_sampling, working)),
+            _ => None,
+        }
+    }
+
+    /// The scene from the README: a few spheres and a bunny, lit by a huge emissive sphere.
+    pub fn example(light_sampling: LightSampling, working: ColorSpace) -> Scene {
+        let sphere2 = Sphere::new(Vector3::new(-150.0, 100.0, -200.0), 50.0);
+        let geom2 = Renderable {
+            material: Material::white_light(),
+            geometry: Box::new(sphere2),
+            medium: None,
+        };
+        let mut objects = vec![geom2];
+        objects.extend(example_objects());
+
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, 0.0, -1.0),
+            fov: 1.57,
+            motion: None,
+        };
+
+        Scene::new(camera, objects, vec![], light_sampling, working)
+    }
+
+    /// The spheres and bunny of the example, lit by one of each kind of light instead of the
+    /// emissive sphere: a warm spot light on the bunny, a panel above, a blue rim light, a small
+    /// fill light and the sun.
+    pub fn lights(light_sampling: LightSampling, working: ColorSpace) -> Scene {
+        // A warm spot light on the bunny
+        let spot = Light::spot(
+            Vector3::new(1.5, 1.0, -1.0),
+            Vector3::new(0.5, -0.8, -2.5),
+            Vector3::new(3.0, 2.4, 1.6),
+            15.0,
+            25.0,
+        );
+        // Soft panel above the scene, facing down
+        let panel = Light::rect(
+            Vector3::new(-1.0, 2.5, -3.5),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(1.5, 1.5, 1.5),
+        );
+        // Blue rim light from behind the left sphere
+        let rim = Light::disk(
+            Vector3::new(-2.8, 0.2, -4.0),
+            Vector3::new(1.0, -0.2, 1.0),
+            0.3,
+            Vector3::new(1.0, 1.5, 4.0),
+        );
+        // Small fill light next to the tiny blue sphere
+        let fill = Light::point(Vector3::new(1.4, -0.4, -1.4), Vector3::new(0.3, 0.3, 0.3));
+        let sun = Light::sun(
+            Vector3::new(0.4, -1.0, -0.3),
+            Vector3::new(0.5, 0.45, 0.4),
+            0.53,
+        );
+
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, 0.0, -1.0),
+            fov: 1.57,
+            motion: None,
+        };
+
+        Scene::new(
+            camera,
+            example_objects(),
+            vec![spot, panel, rim, fill, sun],
+            light_sampling,
+            working,
+        )
+    }
+
+    /// Two spheres full of stuff: a ball of fog with an invisible boundary on the left, and a
+    /// glass ball of absorbing liquid on the right. A spot light shines down between them.
+    pub fn volumes(light_sampling: LightSampling, working: ColorSpace) -> Scene {
+        let ground = Renderable {
+            material: Material::gray_mat(),
+            geometry: Box::new(Sphere::new(Vector3::new(0.0, -41.0, -3.0), 40.0)),
+            medium: None,
+        };
+        let fog = Renderable {
+            material: Material::medium_boundary(),
+            geometry: Box::new(Sphere::new(Vector3::new(-0.8, -0.4, -3.0), 0.6)),
+            medium: Some(Medium::Homogeneous {
+                sigma_a: Vector3::new(0.1, 0.1, 0.1),
+                sigma_s: Vector3::new(3.0, 3.0, 3.0),
+                g: 0.3,
+            }),
+        };
+        // Absorbs the red and green least, so it goes deeper orange the thicker it is
+        let liquid = Renderable {
+            material: Material::glass(),
+            geometry: Box::new(Sphere::new(Vector3::new(0.8, -0.4, -3.0), 0.6)),
+            medium: Some(Medium::Homogeneous {
+                sigma_a: Vector3::new(0.1, 0.8, 2.5),
+                sigma_s: Vector3::new(0.0, 0.0, 0.0),
+                g: 0.0,
+            }),
+        };
+
+        let spot = Light::spot(
+            Vector3::new(0.0, 2.0, -2.5),
+            Vector3::new(0.0, -1.0, -3.0),
+            Vector3::new(12.0, 11.0, 10.0),
+            20.0,
+            35.0,
+        );
+        let panel = Light::rect(
+            Vector3::new(-1.0, 2.0, -4.5),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(1.0, 1.0, 1.0),
+        );
+
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, 0.0, -1.0),
+            fov: 1.0,
+            motion: None,
+        };
+
+        Scene::new(
+            camera,
+            vec![ground, fog, liquid],
+            vec![spot, panel],
+            light_sampling,
+            working,
+        )
+    }
+
+    /// A cumulus cloud of noise in a box, floating over the ground in the sun.
+    pub fn cloud(light_sampling: LightSampling, working: ColorSpace) -> Scene {
+        let ground = Renderable {
+            material: Material::gray_mat(),
+            geometry: Box::new(Sphere::new(Vector3::new(0.0, -41.0, -3.0), 40.0)),
+            medium: None,
+        };
+        let (min, max) = (Vector3::new(-1.3, -0.6, -4.3), Vector3::new(1.3, 0.9, -1.7));
+        let cloud = Renderable {
+            material: Material::medium_boundary(),
+            geometry: Box::new(Cuboid::new(min, max)),
+            medium: Some(Medium::Grid {
+                density: Box::new(DensityGrid::new(Grid::cloud(64), Aabb { min, max })),
+                sigma_a: Vector3::new(0.05, 0.05, 0.05),
+                sigma_s: Vector3::new(12.0, 12.0, 12.0),
+                g: 0.6,
+            }),
+        };
+
+        let sun = Light::sun(
+            Vector3::new(-0.5, -0.8, -0.4),
+            Vector3::new(3.0, 2.8, 2.5),
+            0.53,
+        );
+
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, 0.0, -1.0),
+            fov: 1.0,
+            motion: None,
+        };
+
+        Scene::new(
+            camera,
+            vec![ground, cloud],
+            vec![sun],
+            light_sampling,
+            working,
+        )
+    }
+
+    /// A wax bunny between a marble ball and a ball of skin, lit from above and behind.
+    pub fn subsurface(light_sampling: LightSampling, working: ColorSpace) -> Scene {
+        let ground = Renderable {
+            material: Material::gray_mat(),
+            geometry: Box::new(Sphere::new(Vector3::new(0.0, -41.0, -3.0), 40.0)),
+            medium: None,
+        };
+        let bunny = Renderable {
+            material: Material::wax(),
+            geometry: Box::new(Mesh::from(
+                "./bunny.obj".to_string(),
+                Vector3::new(0.0, -1.25, -2.5),
+                8.0,
+            )),
+            medium: None,
+        };
+        let marble = Renderable {
+            material: Material::marble(),
+            geometry: Box::new(Sphere::new(Vector3::new(-1.1, -0.6, -2.8), 0.4)),
+            medium: None,
+        };
+        let skin = Renderable {
+            material: Material::skin(),
+            geometry: Box::new(Sphere::new(Vector3::new(1.1, -0.6, -2.8), 0.4)),
+            medium: None,
+        };
+
+        let panel = Light::rect(
+            Vector3::new(-1.0, 2.0, -3.0),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(1.5, 1.5, 1.5),
+        );
+        // Shines through the thin parts, like the ears
+        let back = Light::spot(
+            Vector3::new(0.0, 0.5, -4.5),
+            Vector3::new(0.0, -0.7, -2.5),
+            Vector3::new(8.0, 7.0, 6.0),
+            25.0,
+            40.0,
+        );
+
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, -0.2, -1.0).normalize(),
+            fov: 1.2,
+            motion: None,
+        };
+
+        Scene::new(
+            camera,
+            vec![ground, bunny, marble, skin],
+            vec![panel, back],
+            light_sampling,
+            working,
+        )
+    }
+
+    /// Things on the move, for motion blur: a ball rolling across, one falling, a glowing one
+    /// flying past, and a bunny growing as it slides back. The camera drifts to the left.
+    pub fn motion(light_sampling: LightSampling, working: ColorSpace) -> Scene {
+        let ground = Renderable {
+            material: Material::gray_mat(),
+            geometry: Box::new(Sphere::new(Vector3::new(0.0, -41.0, -3.0), 40.0)),
+            medium: None,
+        };
+        let rolling = Renderable {
+            material: Material::semirough(),
+            geometry: Box::new(Sphere::moving(
+                Vector3::new(-1.6, -0.65, -2.6),
+                Vector3::new(-0.4, -0.65, -2.6),
+                0.35,
+            )),
+            medium: None,
+        };
+        let falling = Renderable {
+            material: Material::bluish(),
+            geometry: Box::new(Sphere::moving(
+                Vector3::new(1.2, 0.8, -3.0),
+                Vector3::new(1.2, -0.75, -3.0),
+                0.25,
+            )),
+            medium: None,
+        };
+        let glowing = Renderable {
+            material: Material::yellowish_light(),
+            geometry: Box::new(Sphere::moving(
+                Vector3::new(1.5, 0.6, -2.0),
+                Vector3::new(-1.5, 0.6, -2.0),
+                0.08,
+            )),
+            medium: None,
+        };
+        let bunny = Renderable {
+            material: Material::gray_mat(),
+            geometry: Box::new(
+                Mesh::from(
+                    "./bunny.obj".to_string(),
+                    Vector3::new(0.3, -1.2, -2.5),
+                    6.0,
+                )
+                .with_motion(Vector3::new(0.4, -1.2, -3.5), 9.0),
+            ),
+            medium: None,
+        };
+
+        let panel = Light::rect(
+            Vector3::new(-1.0, 2.5, -3.5),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(2.0, 2.0, 2.0),
+        );
+
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, 0.0, -1.0),
+            fov: 1.2,
+            motion: Some((Vector3::new(-0.2, 0.0, 0.0), Vector3::new(0.0, 0.0, -1.0))),
+        };
+
+        Scene::new(
+            camera,
+            vec![ground, rolling, falling, glowing, bunny],
+            vec![panel],
+            light_sampling,
+            working,
+        )
+    }
+
+    /// A short animation, 48 frames long (from 1 to 48): a ball bouncing across, a glowing one
+    /// circling the bunny, and the bunny growing, going from red and glossy to teal and rough.
+    /// The camera swings around to the left. Frames it isn't rendered at look like the first.
+    pub fn animation(light_sampling: LightSampling, working: ColorSpace) -> Scene {
+        let ground = Renderable {
+            material: Material::gray_mat(),
+            geometry: Box::new(Sphere::new(Vector3::new(0.0, -41.0, -3.0), 40.0)),
+            medium: None,
+        };
+        let ball = Renderable {
+            material: Material::bluish(),
+            geometry: Box::new(Sphere::new(Vector3::new(-1.6, -0.7, -2.8), 0.3)),
+            medium: None,
+        };
+        let glowing = Renderable {
+            material: Material::yellowish_light(),
+            geometry: Box::new(Sphere::new(Vector3::new(0.4, 0.2, -2.0), 0.08)),
+            medium: None,
+        };
+        let bunny = Renderable {
+            material: Material::semirough(),
+            geometry: Box::new(Mesh::from(
+                "./bunny.obj".to_string(),
+                Vector3::new(0.4, -1.2, -3.0),
+                6.0,
+            )),
+            medium: None,
+        };
+
+        let panel = Light::rect(
+            Vector3::new(-1.0, 2.5, -3.5),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(2.0, 2.0, 2.0),
+        );
+
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, 0.0, -1.0),
+            fov: 1.2,
+            motion: None,
+        };
+
+        // Hops getting lower, one every 12 frames, rolling on for the last one
+        let bounce = Track::new(
+            [
+                (1.0, 0.0),
+                (7.0, 1.0),
+                (13.0, 0.0),
+                (19.0, 0.6),
+                (25.0, 0.0),
+            ]
+            .into_iter()
+            .chain([(31.0, 0.3), (37.0, 0.0)])
+            .map(|(frame, height)| {
+                let x = -1.6 + 3.0 * (frame - 1.0) / 47.0;
+                (frame, (Vector3::new(x, -0.7 + height, -2.8), 0.3))
+            })
+            .chain([(48.0, (Vector3::new(1.4, -0.7, -2.8), 0.3))])
+            .collect(),
+        );
+        // Once around the bunny, with a key every eighth of the way
+        let circle = Track::new(
+            (0..=8)
+                .map(|k| {
+                    let angle = k as f64 * std::f64::consts::PI / 4.0;
+                    let pos = Vector3::new(0.4 + 0.9 * angle.sin(), 0.2, -3.0 + 0.9 * angle.cos());
+                    (1.0 + k as f64 * 47.0 / 8.0, (pos, 0.08))
+                })
+                .collect(),
+        );
+        let target = Vector3::new(0.2, -0.6, -2.9);
+        let camera_at = |pos: Vector3| (pos, (target - pos).normalize());
+        let mut scene = Scene::new(
+            camera,
+            vec![ground, ball, glowing, bunny],
+            vec![panel],
+            light_sampling,
+            working,
+        );
+        scene.animation = Animation {
+            camera: Some(Track::new(vec![
+                (1.0, camera_at(Vector3::new(0.0, 0.0, 0.0))),
+                (24.0, camera_at(Vector3::new(-1.2, 0.2, -0.4))),
+                (48.0, camera_at(Vector3::new(-2.2, 0.5, -1.6))),
+            ])),
+            objects: vec![
+                (
+                    1,
+                    ObjectTracks {
+                        placement: Some(bounce),
+                        ..ObjectTracks::default()
+                    },
+                ),
+                (
+                    2,
+                    ObjectTracks {
+                        placement: Some(circle),
+                        ..ObjectTracks::default()
+                    },
+                ),
+                (
+                    3,
+                    ObjectTracks {
+                        placement: Some(Track::new(vec![
+                            (1.0, (Vector3::new(0.4, -1.2, -3.0), 6.0)),
+                            (48.0, (Vector3::new(0.4, -1.2, -3.0), 9.0)),
+                        ])),
+                        base_col: Some(Track::new(vec![
+                            (1.0, Vector3::new(0.9, 0.3, 0.3)),
+                            (48.0, Vector3::new(0.2, 0.7, 0.65)),
+                        ])),
+                        roughness: Some(Track::new(vec![(1.0, 0.1), (48.0, 0.9)])),
+                    },
+                ),
+            ],
+        };
+        scene.set_frame(1);
+        scene
+    }
+
+    /// A flint glass prism and a crown glass ball in front of thin strips of white light, for
+    /// dispersion: rendered with --spectral, the strips seen through the glass come apart into
+    /// rainbows.
+    pub fn prism(light_sampling: LightSampling, working: ColorSpace) -> Scene {
+        let floor = Renderable {
+            material: Material::gray_mat(),
+            geometry: Box::new(Cuboid::new(
+                Vector3::new(-6.0, -1.1, -9.0),
+                Vector3::new(6.0, -1.0, 1.0),
+            )),
+            medium: None,
+        };
+        // Standing on the floor, with an edge towards the camera
+        let base = [
+            Vector3::new(0.0, 0.0, 0.6),
+            Vector3::new(-0.52, 0.0, -0.3),
+            Vector3::new(0.52, 0.0, -0.3),
+        ];
+        let prism = Renderable {
+            material: Material::flint_glass(),
+            geometry: Box::new(Mesh::new(
+                Vector3::new(-0.5, -1.0, -3.0),
+                1.0,
+                prism_tris(base, 1.6),
+            )),
+            medium: None,
+        };
+        let ball = Renderable {
+            material: Material::crown_glass(),
+            geometry: Box::new(Sphere::new(Vector3::new(1.1, -0.5, -3.0), 0.5)),
+            medium: None,
+        };
+
+        let wall = Renderable {
+            material: Material::gray_mat(),
+            geometry: Box::new(Cuboid::new(
+                Vector3::new(-6.0, -1.0, -6.6),
+                Vector3::new(6.0, 4.0, -6.5),
+            )),
+            medium: None,
+        };
+
+        // Strips of light along the wall, facing the camera. Seen through the prism, which
+        // bends light a long way to the side, they're the ones far off to the left.
+        let strips = (0..16).map(|i| {
+            Light::rect(
+                Vector3::new(-5.0 + 0.6 * i as f64, -1.0, -6.0),
+                Vector3::new(0.04, 0.0, 0.0),
+                Vector3::new(0.0, 2.5, 0.0),
+                Vector3::new(20.0, 20.0, 20.0),
+            )
+        });
+        // Soft panel above, facing down
+        let panel = Light::rect(
+            Vector3::new(-1.0, 2.5, -3.5),
+            Vector3::new(2.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(1.0, 1.0, 1.0),
+        );
+        let mut lights: Vec<Light> = strips.collect();
+        lights.push(panel);
+
+        let camera = Camera {
+            pos: Vector3::new(0.0, 0.0, 0.0),
+            dir: Vector3::new(0.0, -0.15, -1.0).normalize(),
+            fov: 1.0,
+            motion: None,
+        };
+
+        Scene::new(
+            camera,
+            vec![floor, wall, prism, ball],
+            lights,
+            light_sampling,
+            working,
+        )
+    }
+
+    /// Poses the scene for the given frame of its animation. Things move from where they are at
+    /// this frame to where they are at the next, over the time the shutter could be open. Meshes
+    /// keep their triangles in object space, so their BVHs stay as they are, and the BVH over
+    /// the objects just gets refit.
+    pub fn set_frame(&mut self, frame: usize) {
+        let (start, end) = (frame as f64, frame as f64 + 1.0);
+        if let Some(track) = &self.animation.camera {
+            let (pos, dir) = track.at(start);
+            self.camera.pos = pos;
+            self.camera.dir = dir;
+            self.camera.motion = Some(track.at(end));
+        }
+        let to_working = self.to_working;
+        let mut relight = false;
+        for (i, tracks) in &self.animation.objects {
+            let g = &mut self.objects[*i];
+            if let Some(track) = &tracks.placement {
+                g.geometry.place(track.at(start), track.at(end));
+                relight |= g.material.emissive.max_component() > 0.0;
+            }
+            if let Some(track) = &tracks.base_col {
+                let c = to_working * track.at(start);
+                g.material.base_col = Vector3::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0));
+                if let Some(mfp) = g.material.subsurface {
+                    g.medium = Some(Medium::subsurface(g.material.base_col, mfp));
+                }
+            }
+            if let Some(track) = &tracks.roughness {
+                g.material.roughness = track.at(start);
+            }
+        }
+        let boxes: Vec<Aabb> = self.objects.iter().map(|g| g.geometry.bounds()).collect();
+        self.bvh.refit(&boxes);
+        // Emissive objects that moved take their lights along
+        if relight {
+            self.lights.truncate(self.standalone_lights);
+            self.object_lights = object_lights(&self.objects, &mut self.lights);
+            self.light_sampler = LightSampler::new(self.light_sampling, &self.lights);
+            self.emission_sampler = emission_sampler(&self.lights);
+        }
+    }
+
+    /// Swaps out the density of every grid volume in the scene for grid, stretched over the
+    /// same box.
+    pub fn set_density(&mut self, grid: &Grid) {
+        for g in self.objects.iter_mut() {
+            if let Some(Medium::Grid { density, .. }) = g.medium.as_mut() {
+                **density = DensityGrid::new(grid.clone(), density.bounds);
+            }
+        }
+    }
+
+    /// Radiance from the background, in direction dir. Without an environment map, it's a
+    /// gradient going from green below to blue above.
+    pub fn background(&self, dir: Vector3) -> Vector3 {
+        match &self.background {
+            Some(env) => env.lookup_dir(dir),
+            None => self.to_working * Vector3::new(0.2, (-dir.y).max(0.0), dir.y.max(0.0)),
+        }
+    }
+
+    /// Where on the surface of the object the hit at pos is, from 0 to 1: longitude and latitude
+    /// on spheres, across the face on boxes, and the barycentric coordinates on triangles. Zero
+    /// on standalone lights. Only worked out when asked for, as it's of no use to the light.
+    pub fn uv(&self, hit: &Hit, pos: Vector3) -> [f64; 2] {
+        match hit.surface {
+            Surface::Object(g) => g.geometry.uv(pos, hit.normal, hit.barycentric),
+            Surface::Light(_) => [0.0, 0.0],
+        }
+    }
+
+    /// Closest intersection of a ray, at the given time, with either an object or a light.
+    pub fn intersect(&self, origin: Vector3, dir: Vector3, time: f64) -> Option<Hit<'_>> {
+        let mut best = None;
+        let mut z0 = f64::MAX;
+        self.bvh.traverse(origin, dir, z0, |i| {
+            let g = &self.objects[i];
+            let res = g.geometry.intersect(origin, dir, time)?;
+            if res.dist < z0 {
+                z0 = res.dist;
+                best = Some(Hit {
+                    dist: res.dist,
+                    normal: res.normal,
+                    surface: Surface::Object(g),
+                    light: self.object_lights[i].map(|l| l + res.prim),
+                    object: Some(i),
+                    barycentric: res.barycentric,
+                });
+            }
+            Some(res.dist)
+        });
+        for (i, l) in self.lights[..self.standalone_lights].iter().enumerate() {
+            if let Light::Area { shape, .. } = l {
+                if let Some(z) = shape.intersect(origin, dir) {
+                    if z < z0 {
+                        z0 = z;
+                        best = Some(Hit {
+                            dist: z,
+                            normal: shape.normal(origin + z * dir),
+                            surface: Surface::Light(l),
+                            light: Some(i),
+                            object: None,
+                            barycentric: None,
+                        });
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// The medium a ray crossing the surface of g, in direction dir, ends up in.
+    /// Media don't nest: leaving an object always means going back into the atmosphere.
+    pub fn medium_across<'a>(
+        &'a self,
+        g: &'a Renderable,
+        dir: Vector3,
+        normal: Vector3,
+    ) -> Option<&'a Medium> {
+        if dir * normal < 0.0 {
+            g.medium.as_ref()
+        } else {
+            self.atmosphere.as_ref()
+        }
+    }
+
+    /// Fraction of light that makes it along a ray at the given time, up to max_dist. The ray
+    /// starts out in medium. Solid surfaces block it, but the boundaries of media let it
+    /// through. Grid media need random numbers, which get seeded from u.
+    pub fn transmittance(
+        &self,
+        origin: Vector3,
+        dir: Vector3,
+        max_dist: f64,
+        medium: Option<&Medium>,
+        time: f64,
+        u: f64,
+    ) -> Vector3 {
+        let (black, mut tr) = (Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0));
+        // Without media, there's nothing to let through
+        if medium.is_none() && !self.has_media() {
+            return if self.occluded(origin, dir, max_dist, time) {
+                black
+            } else {
+                tr
+            };
+        }
+        let mut rng = Pcg32::new(0, u.to_bits());
+        let (mut origin, mut left, mut medium) = (origin, max_dist, medium);
+        loop {
+            let hit = self.intersect(origin, dir, time).filter(|h| h.dist < left);
+            if let Some(m) = medium {
+                let dist = hit.as_ref().map_or(left, |h| h.dist);
+                tr = tr.star(m.transmittance(origin, dir, dist, &mut rng));
+            }
+            let hit = match hit {
+                Some(hit) => hit,
+                None => return tr,
+            };
+            match hit.surface {
+                Surface::Object(g) if g.material.is_passthrough() => {
+                    tr = tr.star(g.material.base_col);
+                    medium = self.medium_across(g, dir, hit.normal);
+                    origin = origin + (hit.dist + 0.001) * dir;
+                    left -= hit.dist + 0.001;
+                }
+                _ => return black,
+            }
+            if tr.max_component() <= 0.0 {
+                return black;
+            }
+        }
+    }
+
+    /// Box around the objects and lights of the scene, over their whole motion. Lights at
+    /// infinity are left out.
+    pub fn bounds(&self) -> Aabb {
+        let objects = self
+            .objects
+            .iter()
+            .fold(Aabb::empty(), |b, g| b.union(&g.geometry.bounds()));
+        self.lights[..self.standalone_lights]
+            .iter()
+            .fold(objects, |b, l| match l {
+                Light::Point { pos, .. } | Light::Spot { pos, .. } => b.union(&Aabb::point(*pos)),
+                Light::Area { shape, .. } => b.union(&shape.bounds()),
+                Light::Directional { .. } => b,
+            })
+    }
+
+    pub fn has_media(&self) -> bool {
+        self.atmosphere.is_some() || self.objects.iter().any(|g| g.medium.is_some())
+    }
+
+    /// Is there anything in the way of a ray at the given time, closer than max_dist?
+    pub fn occluded(&self, origin: Vector3, dir: Vector3, max_dist: f64, time: f64) -> bool {
+        let mut occluded = false;
+        self.bvh.traverse(origin, dir, max_dist, |i| {
+            let res = self.objects[i].geometry.intersect(origin, dir, time)?;
+            // Once anything is in the way, the rest of the ray doesn't matter
+            occluded |= res.dist < max_dist;
+            Some(if occluded { 0.0 } else { res.dist })
+        });
+        occluded
+            || self.lights[..self.standalone_lights]
+                .iter()
+                .any(|l| l.intersect(origin, dir).is_some_and(|z| z < max_dist))
+    }
+
+    /// Pdf of light sampling at p (with surface normal n) picking the light, then direction dir.
+    pub fn light_pdf(&self, p: Vector3, n: Vector3, light: usize, dir: Vector3) -> f64 {
+        self.light_sampler.pmf(p, n, light) * self.lights[light].pdf_li(p, dir)
+    }
+}
+
+/// The spheres and the bunny of the example scene, without its light.
+fn example_objects() -> Vec<Renderable> {
+    let sphere3 = Sphere::new(Vector3::new(1.8, -1.0, -2.0), 0.22);
+    let sphere4 = Sphere::new(Vector3::new(2.0, 0.0, -3.0), 1.0);
+    let sphere5 = Sphere::new(Vector3::new(0.0, -41.0, -3.0), 40.0);
+    let sphere6 = Sphere::new(Vector3::new(-1.5, -0.3, -2.5), 0.8);
+    let geom3 = Renderable {
+        material: Material::bluish(),
+        geometry: Box::new(sphere3),
+        medium: None,
+    };
+    let geom4 = Renderable {
+        material: Material::gray_mat(),
+        geometry: Box::new(sphere4),
+        medium: None,
+    };
+    let geom5 = Renderable {
+        material: Material::gray_mat(),
+        geometry: Box::new(sphere5),
+        medium: None,
+    };
+    let geom6 = Renderable {
+        material: Material::semirough(),
+        geometry: Box::new(sphere6),
+        medium: None,
+    };
+    let geom_bunny = Renderable {
+        material: Material::semirough(),
+        geometry: Box::new(Mesh::from(
+            "./bunny.obj".to_string(),
+            Vector3::new(0.5, -1.2, -2.5),
+            8.0,
+        )),
+        medium: None,
+    };
+    vec![geom3, geom4, geom5, geom6, geom_bunny]
+}
+
+/// Alias table over the power of the lights, leaving out the lights at infinity.
+fn emission_sampler(lights: &[Light]) -> AliasTable {
+    let powers: Vec<f64> = lights
+        .iter()
+        .map(|l| if l.is_infinite() { 0.0 } else { l.power(0.0) })
+        .collect();
+    AliasTable::new(&powers)
+}
+
+/// Turns the emissive objects into lights, one per primitive, added after the lights there
+/// are. Returns the index of the first light of every emissive object.
+fn object_lights(objects: &[Renderable], lights: &mut Vec<Light>) -> Vec<Option<usize>> {
+    let mut object_lights = vec![];
+    for g in objects {
+        if g.material.emissive.max_component() > 0.0 && !g.geometry.moves() {
+            object_lights.push(Some(lights.len()));
+            lights.extend(g.geometry.emitters().into_iter().map(|shape| Light::Area {
+                shape,
+                radiance: g.material.emissive,
+            }));
+        } else {
+            object_lights.push(None);
+        }
+    }
+    object_lights
+}
+
+/// The triangles of a prism: the triangle base, and the same one height above it, joined up.
+/// They get wound so that their normals face out.
+fn prism_tris(base: [Vector3; 3], height: f64) -> Vec<Triangle> {
+    let up = Vector3::new(0.0, height, 0.0);
+    let top = base.map(|p| p + up);
+    let center = (1.0 / 3.0) * (base[0] + base[1] + base[2]) + 0.5 * up;
+    let mut faces = vec![base, top];
+    for i in 0..3 {
+        let j = (i + 1) % 3;
+        faces.push([base[i], base[j], top[j]]);
+        faces.push([base[i], top[j], top[i]]);
+    }
+    faces
+        .into_iter()
+        .map(|[a, b, c]| {
+            if (b - a).cross(c - a) * (a - center) > 0.0 {
+                Triangle::new(a, b, c)
+            } else {
+                Triangle::new(a, c, b)
+            }
+        })
+        .collect()
+}
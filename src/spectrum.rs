@@ -0,0 +1,432 @@
+use std::borrow::Cow;
+use std::thread;
+
+use crate::color::ColorSpace;
+use crate::film::PathRadiance;
+use crate::material::Material;
+use crate::vector3::*;
+
+/// The visible range, in nm, which the wavelengths of paths are picked from
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 780.0;
+/// Resolution of the table of spectra, along each of its axes
+const TABLE_RES: usize = 32;
+/// Wavelength step, in nm, of the integrals over the visible range that fitting the table takes
+const STEP: f64 = 5.0;
+
+/// CIE standard illuminant D65, from 380nm to 780nm in steps of 10nm
+const D65: [f64; 41] = [
+    49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008, 117.812, 114.861,
+    115.923, 108.811, 109.354, 107.802, 104.790, 107.689, 104.405, 104.046, 100.000, 96.3342,
+    95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268, 80.2146, 82.2778,
+    78.2842, 69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927, 46.4182, 66.8054, 63.3828,
+];
+
+/// The CIE 1931 color matching functions at lambda (in nm), from the multi-lobe gaussian fit of
+/// Wyman, Sloan and Shirley.
+pub fn cie_xyz(lambda: f64) -> Vector3 {
+    let g = |mu: f64, s1: f64, s2: f64| {
+        let t = (lambda - mu) / if lambda < mu { s1 } else { s2 };
+        (-0.5 * t * t).exp()
+    };
+    Vector3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/// D65 at lambda, interpolated linearly between the entries of the table.
+fn d65(lambda: f64) -> f64 {
+    let x = ((lambda - LAMBDA_MIN) / 10.0).clamp(0.0, 40.0);
+    let i = (x as usize).min(39);
+    let t = x - i as f64;
+    (1.0 - t) * D65[i] + t * D65[i + 1]
+}
+
+/// A smooth spectrum between 0 and 1: a quadratic polynomial in the wavelength, squashed by a
+/// sigmoid. The wavelength goes from 0 to 1 over the visible range, which keeps the
+/// coefficients in a sane range.
+#[derive(Debug, Clone, Copy)]
+struct Sigmoid([f64; 3]);
+impl Sigmoid {
+    fn at(&self, lambda: f64) -> f64 {
+        let t = (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+        let [a, b, c] = self.0;
+        let x = (a * t + b) * t + c;
+        // Flat black and flat white are out at infinity
+        if x.is_infinite() {
+            return if x > 0.0 { 1.0 } else { 0.0 };
+        }
+        0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+    }
+}
+
+/// Integrals over the visible range, of a spectrum lit by D65, as a sum over every STEP nm.
+/// The weights are the color matching functions times D65, scaled so that white has Y = 1.
+struct Quadrature {
+    points: Vec<(f64, Vector3)>,
+    /// What D65 gets scaled by, for Y = 1
+    d65_scale: f64,
+    /// XYZ of white: a flat spectrum of 1
+    white: Vector3,
+    /// Linear sRGB to XYZ, with white matched to the white of these integrals rather than to
+    /// the D65 chromaticity, so that gray stays gray exactly
+    srgb_to_xyz: Matrix3,
+}
+impl Quadrature {
+    fn new() -> Quadrature {
+        let n = ((LAMBDA_MAX - LAMBDA_MIN) / STEP) as usize;
+        let mut points: Vec<(f64, Vector3)> = (0..=n)
+            .map(|i| {
+                let lambda = LAMBDA_MIN + i as f64 * STEP;
+                // Trapezoidal rule
+                let w = if i == 0 || i == n { 0.5 * STEP } else { STEP };
+                (lambda, (w * d65(lambda)) * cie_xyz(lambda))
+            })
+            .collect();
+        let d65_scale = 1.0 / points.iter().map(|(_, w)| w.y).sum::<f64>();
+        for (_, w) in points.iter_mut() {
+            *w = d65_scale * *w;
+        }
+        let white = points
+            .iter()
+            .fold(Vector3::new(0.0, 0.0, 0.0), |s, (_, w)| s + *w);
+        let m = ColorSpace::Srgb.rgb_to_xyz();
+        let m_white = m * Vector3::new(1.0, 1.0, 1.0);
+        let balance = Vector3::new(
+            white.x / m_white.x,
+            white.y / m_white.y,
+            white.z / m_white.z,
+        );
+        Quadrature {
+            points,
+            d65_scale,
+            white,
+            srgb_to_xyz: Matrix3::diag(balance) * m,
+        }
+    }
+
+    fn xyz(&self, s: Sigmoid) -> Vector3 {
+        self.points
+            .iter()
+            .fold(Vector3::new(0.0, 0.0, 0.0), |sum, (lambda, w)| {
+                sum + s.at(*lambda) * *w
+            })
+    }
+
+    /// CIELAB, which the fit measures its error in, so that it's about as bad everywhere as
+    /// the eye sees it.
+    fn lab(&self, xyz: Vector3) -> Vector3 {
+        let f = |t: f64| {
+            let d: f64 = 6.0 / 29.0;
+            if t > d * d * d {
+                t.cbrt()
+            } else {
+                t / (3.0 * d * d) + 4.0 / 29.0
+            }
+        };
+        let (fx, fy, fz) = (
+            f(xyz.x / self.white.x),
+            f(xyz.y / self.white.y),
+            f(xyz.z / self.white.z),
+        );
+        Vector3::new(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+    }
+
+    /// The sigmoid spectrum that reflects the sRGB color rgb under D65, by Gauss-Newton
+    /// iteration from the coefficients start.
+    fn fit(&self, rgb: Vector3, start: [f64; 3]) -> [f64; 3] {
+        let target = self.lab(self.srgb_to_xyz * rgb);
+        let residual = |c: [f64; 3]| self.lab(self.xyz(Sigmoid(c))) - target;
+        let mut c = start;
+        let mut r = residual(c);
+        for _ in 0..15 {
+            if r.norm() < 1e-6 {
+                break;
+            }
+            let h = 1e-5;
+            let [d0, d1, d2] = [0, 1, 2].map(|i| {
+                let mut d = c;
+                d[i] += h;
+                (1.0 / h) * (residual(d) - r)
+            });
+            let step = Matrix3::from_cols(d0, d1, d2).inverse() * r;
+            if !(step.x.is_finite() && step.y.is_finite() && step.z.is_finite()) {
+                break;
+            }
+            // Full steps overshoot out at the edge of the gamut, so steps get halved until
+            // they make things better
+            let mut t = 1.0;
+            loop {
+                let mut next = [c[0] - t * step.x, c[1] - t * step.y, c[2] - t * step.z];
+                // Colors on the edge of the gamut need infinitely steep spectra, which the
+                // coefficients are kept from running off towards
+                let largest = next.iter().fold(0.0f64, |m, v| m.max(v.abs()));
+                if largest > 200.0 {
+                    next = next.map(|v| v * 200.0 / largest);
+                }
+                let next_r = residual(next);
+                if next_r.norm() < r.norm() || t < 1e-3 {
+                    (c, r) = (next, next_r);
+                    break;
+                }
+                t *= 0.5;
+            }
+        }
+        c
+    }
+}
+
+/// Spectra for the colors of linear sRGB, after Jakob and Hanika, "A Low-Dimensional Function
+/// Space for Efficient Spectral Upsampling" (2019). The table has the sigmoid spectra of a grid
+/// of colors, and colors in between get theirs interpolated.
+struct Table {
+    /// The values of the largest component the grid has, closer together towards black
+    scale: Vec<f64>,
+    /// By which component is the largest, then by its value, then by the other two components
+    /// relative to it
+    coeffs: Vec<[f64; 3]>,
+}
+impl Table {
+    /// Fits the spectra of the grid over par threads. Each thread takes lines of colors that
+    /// only differ in brightness, where every fit starts from the one before.
+    fn new(par: usize) -> Table {
+        let res = TABLE_RES;
+        let smoothstep = |x: f64| x * x * (3.0 - 2.0 * x);
+        let scale: Vec<f64> = (0..res)
+            .map(|i| smoothstep(smoothstep(i as f64 / (res - 1) as f64)))
+            .collect();
+        let quadrature = Quadrature::new();
+        let lines = 3 * res * res;
+        let chunk = lines.div_ceil(par);
+        let fitted: Vec<Vec<[f64; 3]>> = thread::scope(|s| {
+            let threads: Vec<_> = (0..par)
+                .map(|t| {
+                    let (scale, quadrature) = (&scale, &quadrature);
+                    s.spawn(move || {
+                        (t * chunk..((t + 1) * chunk).min(lines))
+                            .map(|line| fit_line(quadrature, scale, line))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|t| t.join().unwrap())
+                .collect()
+        });
+        let mut coeffs = vec![[0.0; 3]; 3 * res * res * res];
+        for (line, c) in fitted.into_iter().enumerate() {
+            let (l, rest) = (line / (res * res), line % (res * res));
+            for (zi, c) in c.into_iter().enumerate() {
+                coeffs[(l * res + zi) * res * res + rest] = c;
+            }
+        }
+        Table { scale, coeffs }
+    }
+
+    /// The spectrum of an sRGB color, clamped to [0, 1].
+    fn get(&self, rgb: Vector3) -> Sigmoid {
+        let c = [rgb.x, rgb.y, rgb.z].map(|v| v.clamp(0.0, 1.0));
+        if c[0] == c[1] && c[1] == c[2] {
+            // Grays are flat, and the sigmoid can hit them exactly
+            return Sigmoid([0.0, 0.0, (c[0] - 0.5) / (c[0] * (1.0 - c[0])).sqrt()]);
+        }
+        let res = TABLE_RES;
+        let l = if c[0] >= c[1] && c[0] >= c[2] {
+            0
+        } else if c[1] >= c[2] {
+            1
+        } else {
+            2
+        };
+        let z = c[l];
+        let x = c[(l + 1) % 3] / z * (res - 1) as f64;
+        let y = c[(l + 2) % 3] / z * (res - 1) as f64;
+        let (xi, yi) = ((x as usize).min(res - 2), (y as usize).min(res - 2));
+        let zi = self.scale.partition_point(|&s| s <= z).clamp(1, res - 1) - 1;
+        let (dx, dy) = (x - xi as f64, y - yi as f64);
+        let dz = (z - self.scale[zi]) / (self.scale[zi + 1] - self.scale[zi]);
+        let mut out = [0.0; 3];
+        for corner in 0..8 {
+            let (ox, oy, oz) = (corner & 1, (corner >> 1) & 1, corner >> 2);
+            let w = if ox == 1 { dx } else { 1.0 - dx }
+                * if oy == 1 { dy } else { 1.0 - dy }
+                * if oz == 1 { dz } else { 1.0 - dz };
+            let i = ((l * res + zi + oz) * res + yi + oy) * res + xi + ox;
+            for (o, v) in out.iter_mut().zip(self.coeffs[i]) {
+                *o += w * v;
+            }
+        }
+        Sigmoid(out)
+    }
+}
+
+/// Fits one line of the table: the colors with the largest component l, and the other two at
+/// fixed fractions of it. line numbers the lines by l, then the fraction of the third
+/// component, then of the second. Returns the coefficients by brightness.
+fn fit_line(quadrature: &Quadrature, scale: &[f64], line: usize) -> Vec<[f64; 3]> {
+    let res = TABLE_RES;
+    let (l, y, x) = (line / (res * res), line / res % res, line % res);
+    let (x, y) = (x as f64 / (res - 1) as f64, y as f64 / (res - 1) as f64);
+    let color = |z: f64| {
+        let mut c = [0.0; 3];
+        c[l] = z;
+        c[(l + 1) % 3] = x * z;
+        c[(l + 2) % 3] = y * z;
+        Vector3::new(c[0], c[1], c[2])
+    };
+    let mut coeffs = vec![[0.0; 3]; res];
+    // Starting from a middling brightness, where flat gray is a good first guess, up to the
+    // brightest, then back down to black
+    let start = res / 5;
+    let mut c = [0.0; 3];
+    for zi in start..res {
+        c = quadrature.fit(color(scale[zi]), c);
+        coeffs[zi] = c;
+    }
+    c = coeffs[start];
+    for zi in (0..start).rev() {
+        c = quadrature.fit(color(scale[zi]), c);
+        coeffs[zi] = c;
+    }
+    coeffs
+}
+
+/// What spectral rendering needs, made once for the render: the table of spectra, and the
+/// conversions between the working space and the sRGB the table is for.
+pub struct Spectral {
+    table: Table,
+    /// Scales D65 to Y = 1, the same as white has in RGB
+    d65_scale: f64,
+    to_srgb: Matrix3,
+    from_xyz: Matrix3,
+}
+impl Spectral {
+    pub fn new(working: ColorSpace, par: usize) -> Spectral {
+        let quadrature = Quadrature::new();
+        Spectral {
+            table: Table::new(par),
+            d65_scale: quadrature.d65_scale,
+            to_srgb: working.conversion(ColorSpace::Srgb),
+            from_xyz: ColorSpace::Srgb.conversion(working) * quadrature.srgb_to_xyz.inverse(),
+        }
+    }
+
+    /// Picks the wavelengths of a path: the hero wavelength, uniformly over the visible range,
+    /// and two more spaced evenly after it, wrapping around at the end.
+    pub fn sample(&self, u: f64) -> Channels<'_> {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let lambda = [0.0, 1.0, 2.0].map(|k| {
+            let offset = range * u + k * range / 3.0;
+            LAMBDA_MIN + offset % range
+        });
+        Channels::Spectral {
+            spectral: self,
+            lambda,
+            hero_only: false,
+        }
+    }
+}
+
+/// What the three channels of a path's colors are: red, green and blue, or the values of the
+/// spectrum at three wavelengths. Scene colors are RGB, and get turned into the path's channels
+/// where the path picks them up.
+#[derive(Clone, Copy)]
+pub enum Channels<'a> {
+    Rgb,
+    Spectral {
+        spectral: &'a Spectral,
+        /// In nm. The first is the hero wavelength.
+        lambda: [f64; 3],
+        /// Set once the path went where only the hero wavelength would have, through a prism
+        /// say. The other two are dropped from then on.
+        hero_only: bool,
+    },
+}
+impl Channels<'_> {
+    /// A color that reflects or lets through a fraction of light, which stays between 0 and 1
+    /// at every wavelength.
+    pub fn albedo(&self, col: Vector3) -> Vector3 {
+        match self {
+            Channels::Rgb => col,
+            Channels::Spectral {
+                spectral, lambda, ..
+            } => {
+                let s = spectral.table.get(spectral.to_srgb * col);
+                Vector3::new(s.at(lambda[0]), s.at(lambda[1]), s.at(lambda[2]))
+            }
+        }
+    }
+
+    /// A color of light, which can be as bright as it likes. It gets the spectrum of the color
+    /// scaled down to half its largest component, scaled back up, and shone through D65, so
+    /// that white light has the spectrum of daylight.
+    pub fn illuminant(&self, col: Vector3) -> Vector3 {
+        match self {
+            Channels::Rgb => col,
+            Channels::Spectral {
+                spectral, lambda, ..
+            } => {
+                let c = spectral.to_srgb * col;
+                let c = Vector3::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0));
+                let m = c.max_component();
+                if m <= 0.0 {
+                    return Vector3::new(0.0, 0.0, 0.0);
+                }
+                let s = spectral.table.get((0.5 / m) * c);
+                let at = |lambda: f64| 2.0 * m * spectral.d65_scale * d65(lambda) * s.at(lambda);
+                Vector3::new(at(lambda[0]), at(lambda[1]), at(lambda[2]))
+            }
+        }
+    }
+
+    /// The material, as the path sees it: with spectra for its colors, and dispersive glass
+    /// with its index of refraction at the hero wavelength.
+    pub fn material<'m>(&self, m: &'m Material) -> Cow<'m, Material> {
+        match self {
+            Channels::Rgb => Cow::Borrowed(m),
+            Channels::Spectral { lambda, .. } => Cow::Owned(Material {
+                base_col: self.albedo(m.base_col),
+                emissive: self.illuminant(m.emissive),
+                ior: m.dispersion.map_or(m.ior, |d| d.ior(lambda[0])),
+                ..m.clone()
+            }),
+        }
+    }
+
+    /// The throughput col, after a bounce that only the hero wavelength could have taken. The
+    /// other wavelengths are dropped, and the hero makes up for them.
+    pub fn hero_only(&mut self, col: Vector3) -> Vector3 {
+        if let Channels::Spectral { hero_only, .. } = self {
+            if !*hero_only {
+                *hero_only = true;
+                return Vector3::new(3.0 * col.x, 0.0, 0.0);
+            }
+        }
+        col
+    }
+
+    /// The radiance a path brought back, in the working space. For spectral paths, each
+    /// wavelength is an estimate of XYZ on its own, and they get averaged.
+    pub fn in_rgb(&self, l: PathRadiance) -> PathRadiance {
+        match self {
+            Channels::Rgb => l,
+            Channels::Spectral {
+                spectral, lambda, ..
+            } => {
+                // Divided by the pdf of the wavelengths
+                let w = (LAMBDA_MAX - LAMBDA_MIN) / 3.0;
+                let cmf = lambda.map(|lambda| w * cie_xyz(lambda));
+                let convert =
+                    |v: Vector3| spectral.from_xyz * (v.x * cmf[0] + v.y * cmf[1] + v.z * cmf[2]);
+                PathRadiance {
+                    emission: convert(l.emission),
+                    direct: convert(l.direct),
+                    indirect: convert(l.indirect),
+                }
+            }
+        }
+    }
+}
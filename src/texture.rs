@@ -0,0 +1,100 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use crate::color::{srgb_to_linear, ColorSpace};
+use crate::vector3::*;
+
+/// An image, linearized and converted to the working space on load.
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Vector3>,
+}
+
+impl Texture {
+    /// Loads an 8-bit binary .ppm (P6), the same format the renders are written in. The values
+    /// are taken to be sRGB encoded.
+    pub fn load_ppm(path: &str, working: ColorSpace) -> std::io::Result<Texture> {
+        let bytes = fs::read(path)?;
+        let bad = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{}: {}", path, msg));
+        // Header: magic, width, height and maxval, separated by whitespace, with # comments
+        let mut fields = vec![];
+        let mut pos = 0;
+        while fields.len() < 4 {
+            while pos < bytes.len() && bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if bytes.get(pos) == Some(&b'#') {
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    pos += 1;
+                }
+                continue;
+            }
+            let start = pos;
+            while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                return Err(bad("truncated header"));
+            }
+            fields.push(String::from_utf8_lossy(&bytes[start..pos]).to_string());
+        }
+        // A single whitespace character separates the header from the data
+        pos += 1;
+        if fields[0] != "P6" {
+            return Err(bad("only binary ppm (P6) is supported"));
+        }
+        let num = |s: &str| s.parse::<usize>().map_err(|_| bad("invalid header"));
+        let (width, height, maxval) = (num(&fields[1])?, num(&fields[2])?, num(&fields[3])?);
+        if maxval == 0 || maxval > 255 {
+            return Err(bad("only 8-bit ppm is supported"));
+        }
+        if width == 0 || height == 0 {
+            return Err(bad("empty image"));
+        }
+        // The size comes from the header, so it can be anything
+        let end = width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(3))
+            .and_then(|n| n.checked_add(pos))
+            .ok_or_else(|| bad("invalid header"))?;
+        let data = bytes
+            .get(pos..end)
+            .ok_or_else(|| bad("truncated pixel data"))?;
+
+        let to_working = ColorSpace::Srgb.conversion(working);
+        let decode = |b: u8| srgb_to_linear(b as f64 / maxval as f64);
+        let pixels = data
+            .chunks_exact(3)
+            .map(|p| to_working * Vector3::new(decode(p[0]), decode(p[1]), decode(p[2])))
+            .collect();
+        Ok(Texture {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// Bilinearly filtered lookup, with u wrapping around and v clamped.
+    pub fn lookup(&self, u: f64, v: f64) -> Vector3 {
+        let x = u * self.width as f64 - 0.5;
+        let y = clamp(v * self.height as f64 - 0.5, 0.0, (self.height - 1) as f64);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |x: f64, y: f64| {
+            let x = (x as i64).rem_euclid(self.width as i64) as usize;
+            let y = (y as usize).min(self.height - 1);
+            self.pixels[y * self.width + x]
+        };
+        (1.0 - fy) * ((1.0 - fx) * texel(x0, y0) + fx * texel(x0 + 1.0, y0))
+            + fy * ((1.0 - fx) * texel(x0, y0 + 1.0) + fx * texel(x0 + 1.0, y0 + 1.0))
+    }
+
+    /// Lookup of a latitude-longitude environment map, with +y up.
+    pub fn lookup_dir(&self, dir: Vector3) -> Vector3 {
+        let u = 0.5 + dir.x.atan2(-dir.z) / (2.0 * PI);
+        let v = clamp(dir.y, -1.0, 1.0).acos() / PI;
+        self.lookup(u, v)
+    }
+}
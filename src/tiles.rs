@@ -0,0 +1,149 @@
+use std::io::{IsTerminal, Write};
+use std::ops::Range;
+use std::time::Instant;
+
+/// A rectangle of the image, rendered by a single thread.
+#[derive(Debug, Clone)]
+pub struct Tile {
+    pub cols: Range<usize>,
+    pub rows: Range<usize>,
+}
+
+/// The order tiles are handed out in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    /// Row by row, like a book
+    Scanline,
+    /// Along a Hilbert curve, so that tiles rendered around the same time are close together
+    Hilbert,
+    /// Outwards from the center, where the subject usually is
+    Spiral,
+}
+
+impl TileOrder {
+    pub fn by_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "scanline" => Some(TileOrder::Scanline),
+            "hilbert" => Some(TileOrder::Hilbert),
+            "spiral" => Some(TileOrder::Spiral),
+            _ => None,
+        }
+    }
+}
+
+/// Splits the image into square tiles of the given size (smaller along the right and bottom
+/// edges), in the given order.
+pub fn tiles(width: usize, height: usize, size: usize, order: TileOrder) -> Vec<Tile> {
+    let (nx, ny) = (width.div_ceil(size), height.div_ceil(size));
+    let mut coords: Vec<(usize, usize)> =
+        (0..ny).flat_map(|y| (0..nx).map(move |x| (x, y))).collect();
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Hilbert => {
+            let n = nx.max(ny).next_power_of_two();
+            coords.sort_by_key(|&(x, y)| hilbert_index(n, x, y));
+        }
+        TileOrder::Spiral => {
+            // By the ring of tiles around the center, then by angle within the ring
+            let (cx, cy) = ((nx as f64 - 1.0) / 2.0, (ny as f64 - 1.0) / 2.0);
+            let key = |&(x, y): &(usize, usize)| {
+                let (dx, dy) = (x as f64 - cx, y as f64 - cy);
+                (dx.abs().max(dy.abs()), dy.atan2(dx))
+            };
+            coords.sort_by(|a, b| {
+                let (ka, kb) = (key(a), key(b));
+                ka.0.total_cmp(&kb.0).then(ka.1.total_cmp(&kb.1))
+            });
+        }
+    }
+    coords
+        .into_iter()
+        .map(|(x, y)| Tile {
+            cols: x * size..((x + 1) * size).min(width),
+            rows: y * size..((y + 1) * size).min(height),
+        })
+        .collect()
+}
+
+/// Position of (x, y) along the Hilbert curve filling an n by n grid, n a power of two.
+/// From https://en.wikipedia.org/wiki/Hilbert_curve
+fn hilbert_index(n: usize, x: usize, y: usize) -> usize {
+    let (mut x, mut y) = (x, y);
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as usize;
+        let ry = (y & s > 0) as usize;
+        d += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant, so the curve inside it lines up
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+/// A progress bar with an estimate of the time left, drawn on stderr if it's a terminal.
+pub struct Progress {
+    start: Instant,
+    /// Fraction of the render done at the start, by a run that got resumed
+    start_fraction: f64,
+    show: bool,
+}
+
+impl Progress {
+    pub fn new(start_fraction: f64) -> Progress {
+        Progress {
+            start: Instant::now(),
+            start_fraction,
+            show: std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Redraws the bar, with fraction of the whole render done.
+    pub fn update(&self, fraction: f64, status: &str) {
+        if !self.show {
+            return;
+        }
+        const WIDTH: usize = 30;
+        let fraction = fraction.clamp(0.0, 1.0);
+        let filled = (fraction * WIDTH as f64) as usize;
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let eta = if fraction > self.start_fraction {
+            format_secs(elapsed * (1.0 - fraction) / (fraction - self.start_fraction))
+        } else {
+            "?".to_string()
+        };
+        eprint!(
+            "\r[{}{}] {:5.1}% {}, {} elapsed, ETA {}\x1b[K",
+            "#".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            100.0 * fraction,
+            status,
+            format_secs(elapsed),
+            eta
+        );
+        let _ = std::io::stderr().flush();
+    }
+
+    /// Wipes the bar, so that it doesn't get mixed up with other output.
+    pub fn clear(&self) {
+        if self.show {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+fn format_secs(secs: f64) -> String {
+    let secs = secs as u64;
+    if secs >= 3600 {
+        format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
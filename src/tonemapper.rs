@@ -1,23 +1,134 @@
-use rand_distr::num_traits::clamp;
-
 use crate::vector3::*;
 
-// TODO: A filmic tonemapper, ACES perhaps
+/// Tonemapping operators, which squeeze the unbounded radiance of the render into the [0, 1]
+/// range of the output image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tonemap {
+    /// Just clips everything above 1
+    Clamp,
+    /// L / (1 + L) on the luminance
+    Reinhard,
+    /// Reinhard, with luminance `white` mapped to 1. None picks the luminance at a percentile of
+    /// the image, set with --white-percentile (99 by default).
+    ReinhardExtended { white: Option<f64> },
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+    /// Sobotka's AgX, in its minimal polynomial form
+    Agx,
+    /// Hable's filmic curve from Uncharted 2
+    Hable,
+}
+
+impl Tonemap {
+    pub fn by_name(name: &str) -> Option<Tonemap> {
+        match name {
+            "clamp" => Some(Tonemap::Clamp),
+            "reinhard" => Some(Tonemap::Reinhard),
+            "reinhard-extended" => Some(Tonemap::ReinhardExtended { white: None }),
+            "aces" => Some(Tonemap::Aces),
+            "agx" => Some(Tonemap::Agx),
+            "hable" => Some(Tonemap::Hable),
+            _ => None,
+        }
+    }
+
+    /// Scales the frame by 2^exposure, then tonemaps it in place. The automatic white point is
+    /// the given percentile of luminance, after exposure.
+    pub fn apply(&self, frame: &mut [Vector3], exposure: f64, white_percentile: f64) {
+        let scale = exposure.exp2();
+        for c in frame.iter_mut() {
+            *c = scale * *c;
+        }
+        let white = match self {
+            Tonemap::ReinhardExtended { white: Some(w) } => *w,
+            Tonemap::ReinhardExtended { white: None } => percentile(frame, white_percentile),
+            _ => 1.0,
+        };
+        for c in frame.iter_mut() {
+            *c = self.map(*c, white);
+        }
+    }
+
+    fn map(&self, col: Vector3, white: f64) -> Vector3 {
+        match self {
+            Tonemap::Clamp => col,
+            Tonemap::Reinhard => {
+                let l = luminance(col);
+                change_luminance(col, l / (1.0 + l))
+            }
+            Tonemap::ReinhardExtended { .. } => reinhard(col, white.max(f64::EPSILON)),
+            Tonemap::Aces => aces_fit(col),
+            Tonemap::Agx => agx(col),
+            Tonemap::Hable => hable(col),
+        }
+    }
+}
+
+/// Luminance statistics of a frame.
+#[derive(Debug, Clone, Copy)]
+pub struct LuminanceStats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// Geometric mean, which is less swayed by a few very bright pixels than the mean.
+    pub log_average: f64,
+}
+
+impl LuminanceStats {
+    pub fn of(frame: &[Vector3]) -> LuminanceStats {
+        // Keeps black pixels from sending the log average to zero
+        const DELTA: f64 = 1e-4;
+        let n = frame.len().max(1) as f64;
+        let (mut min, mut max, mut sum, mut log_sum) = (f64::MAX, 0.0_f64, 0.0, 0.0);
+        for c in frame {
+            let l = luminance(*c).max(0.0);
+            min = min.min(l);
+            max = max.max(l);
+            sum += l;
+            log_sum += (DELTA + l).ln();
+        }
+        LuminanceStats {
+            min: min.min(max),
+            max,
+            mean: sum / n,
+            log_average: (log_sum / n).exp(),
+        }
+    }
+
+    /// Exposure in stops that brings the log average luminance to middle gray, as in Reinhard's
+    /// "Photographic Tone Reproduction".
+    pub fn auto_exposure(&self) -> f64 {
+        const MIDDLE_GRAY: f64 = 0.18;
+        (MIDDLE_GRAY / self.log_average).log2()
+    }
+}
+
+/// The luminance that p percent of the pixels are at or below.
+pub fn percentile(frame: &[Vector3], p: f64) -> f64 {
+    let mut lums: Vec<f64> = frame.iter().map(|c| luminance(*c)).collect();
+    if lums.is_empty() {
+        return 0.0;
+    }
+    lums.sort_by(f64::total_cmp);
+    let i = (clamp(p, 0.0, 100.0) / 100.0 * (lums.len() - 1) as f64).round() as usize;
+    lums[i]
+}
 
 // An approximate ACES tonemapping.
 // From https://64.github.io/tonemapping/#aces
-/*
 pub fn aces_fit(col: Vector3) -> Vector3 {
-    let mut v = col.clone();
-    v = 0.6 * v;
-    let a = 2.51;
-    let b = 0.03;
-    let c = 2.43;
-    let d = 0.59;
-    let e = 0.14;
-    // The result will be clamped when converted into col.
-    (v.star(a * v + b)) / (v.star(c * v + d) + e)
-}*/
+    let f = |v: f64| {
+        let v = 0.6 * v;
+        let a = 2.51;
+        let b = 0.03;
+        let c = 2.43;
+        let d = 0.59;
+        let e = 0.14;
+        // The result will be clamped when converted into col.
+        (v * (a * v + b)) / (v * (c * v + d) + e)
+    };
+    Vector3::new(f(col.x), f(col.y), f(col.z))
+}
 
 pub fn reinhard(col: Vector3, max_luminance: f64) -> Vector3 {
     let l_old = luminance(col);
@@ -26,11 +137,55 @@ pub fn reinhard(col: Vector3, max_luminance: f64) -> Vector3 {
     change_luminance(col, l_new)
 }
 
+// Also from https://64.github.io/tonemapping/
+pub fn hable(col: Vector3) -> Vector3 {
+    fn partial(x: f64) -> f64 {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+    }
+    let exposure_bias = 2.0;
+    let white_scale = 1.0 / partial(11.2);
+    let f = |v: f64| partial(exposure_bias * v) * white_scale;
+    Vector3::new(f(col.x), f(col.y), f(col.z))
+}
+
+// Minimal AgX, after https://iolite-engine.com/blog_posts/minimal_agx_implementation
+// The curve produces display encoded values, which get linearized again at the end, since the
+// output stage does its own encoding.
+pub fn agx(col: Vector3) -> Vector3 {
+    let inset = Matrix3::new([
+        [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+        [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+        [0.0423756549057051, 0.0784336, 0.879142973793104],
+    ]);
+    let outset = Matrix3::new([
+        [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+        [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+        [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+    ]);
+    let (min_ev, max_ev) = (-12.47393, 4.026069);
+    let curve = |v: f64| {
+        // Position in the log encoded range, then the sigmoid
+        let x = (clamp(v.max(1e-10).log2(), min_ev, max_ev) - min_ev) / (max_ev - min_ev);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    };
+    let v = inset * col;
+    let v = outset * Vector3::new(curve(v.x), curve(v.y), curve(v.z));
+    let linearize = |v: f64| v.max(0.0).powf(2.2);
+    Vector3::new(linearize(v.x), linearize(v.y), linearize(v.z))
+}
+
 // From https://64.github.io/tonemapping/
-fn luminance(col: Vector3) -> f64 {
+pub fn luminance(col: Vector3) -> f64 {
     0.2126 * col.x + 0.7152 * col.y + 0.0722 * col.z
 }
 fn change_luminance(c_in: Vector3, l_out: f64) -> Vector3 {
     let l_in = luminance(c_in);
-    return (l_out / l_in) * c_in;
+    if l_in <= 0.0 {
+        return Vector3::new(0.0, 0.0, 0.0);
+    }
+    (l_out / l_in) * c_in
 }
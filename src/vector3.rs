@@ -1,11 +1,13 @@
 use core::f64;
-use rand::Rng;
-use rand_distr::{num_traits::Float, StandardNormal};
-use std::ops::{Add, Mul, Sub};
+use std::ops::{Add, Mul, Neg, Sub};
 
+use crate::bvh::Bvh;
+use crate::light::AreaShape;
+use crate::material::Material;
+use crate::medium::Medium;
 use crate::objloader::load_obj_file;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Vector3 {
     pub x: f64,
     pub y: f64,
@@ -15,17 +17,10 @@ impl Vector3 {
     pub fn new(x: f64, y: f64, z: f64) -> Self {
         Vector3 { x, y, z }
     }
-    pub fn on_unit_sphere() -> Vector3 {
-        let mut rng = rand::thread_rng();
-        let x: f64 = rng.sample(StandardNormal);
-        let y: f64 = rng.sample(StandardNormal);
-        let z: f64 = rng.sample(StandardNormal);
-        Vector3::new(x, y, z).normalize()
-    }
+    /// Quantizes a color that's already display encoded.
     pub fn as_col(&self) -> Col {
-        // Apply gamma correction with sqrt
         fn col_cast(f: f64) -> u8 {
-            (clamp(f, 0.0, 1.0).sqrt() * 255.0) as u8
+            (clamp(f, 0.0, 1.0) * 255.0).round() as u8
         }
         Col {
             r: col_cast(self.x),
@@ -54,19 +49,67 @@ impl Vector3 {
             self.x * other.y - self.y * other.x,
         )
     }
+    /// Two unit vectors that, together with self (assumed normalized), form an orthonormal basis.
+    /// From "Building an Orthonormal Basis, Revisited" (Duff et al.)
+    pub fn orthonormal_basis(&self) -> (Vector3, Vector3) {
+        let sign = 1.0_f64.copysign(self.z);
+        let a = -1.0 / (sign + self.z);
+        let b = self.x * self.y * a;
+        (
+            Vector3::new(1.0 + sign * self.x * self.x * a, sign * b, -sign * self.x),
+            Vector3::new(b, sign + self.y * self.y * a, -self.y),
+        )
+    }
+    /// Expresses a vector given in the local frame around self (z axis) in world space.
+    pub fn local_to_world(&self, local: Vector3) -> Vector3 {
+        let (t, b) = self.orthonormal_basis();
+        local.x * t + local.y * b + local.z * *self
+    }
+    pub fn max_component(&self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
 }
-fn clamp(value: f64, min: f64, max: f64) -> f64 {
+pub fn clamp(value: f64, min: f64, max: f64) -> f64 {
     value.max(min).min(max)
 }
-fn lerp(a: f64, b: f64, value: f64) -> f64 {
-    let f = clamp(value, 0.0, 1.0);
-    (1.0 - f) * a + f * b
+
+/// Cosine-weighted direction on the hemisphere around +z, from two uniform numbers.
+pub fn cosine_hemisphere(u: [f64; 2]) -> Vector3 {
+    let r = u[0].sqrt();
+    let phi = 2.0 * f64::consts::PI * u[1];
+    Vector3::new(r * phi.cos(), r * phi.sin(), (1.0 - u[0]).max(0.0).sqrt())
+}
+
+/// Uniformly distributed direction inside a cone of directions around +z.
+pub fn uniform_cone(u: [f64; 2], cos_max: f64) -> Vector3 {
+    let cos_theta = (1.0 - u[0]) + u[0] * cos_max;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * f64::consts::PI * u[1];
+    Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+/// Uniformly distributed point on the unit disk, using Shirley's concentric mapping.
+pub fn concentric_disk(u: [f64; 2]) -> (f64, f64) {
+    let (a, b) = (2.0 * u[0] - 1.0, 2.0 * u[1] - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, f64::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, f64::consts::FRAC_PI_2 - f64::consts::FRAC_PI_4 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
 }
 
-// A normally-distributed random variable
-// Global functions may be the answer, in this case.
-fn rand_normal() -> f64 {
-    rand::thread_rng().sample(StandardNormal)
+/// The power heuristic (beta = 2) for multiple importance sampling.
+pub fn power_heuristic(pdf_a: f64, pdf_b: f64) -> f64 {
+    let (a, b) = (pdf_a * pdf_a, pdf_b * pdf_b);
+    if a + b == 0.0 {
+        0.0
+    } else {
+        a / (a + b)
+    }
 }
 
 impl Add for Vector3 {
@@ -99,6 +142,13 @@ impl Mul for Vector3 {
         self.x * other.x + self.y * other.y + self.z * other.z
     }
 }
+impl Neg for Vector3 {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Vector3::new(-self.x, -self.y, -self.z)
+    }
+}
 // Implement the Mul trait for i32 and Vector3
 impl Mul<Vector3> for f64 {
     type Output = Vector3;
@@ -113,6 +163,59 @@ impl Mul<Vector3> for f64 {
     }
 }
 
+/// 3x3 matrix, stored by rows.
+#[derive(Debug, Clone, Copy)]
+pub struct Matrix3 {
+    pub m: [[f64; 3]; 3],
+}
+impl Matrix3 {
+    pub fn new(m: [[f64; 3]; 3]) -> Self {
+        Matrix3 { m }
+    }
+    pub fn from_cols(a: Vector3, b: Vector3, c: Vector3) -> Self {
+        Matrix3::new([[a.x, b.x, c.x], [a.y, b.y, c.y], [a.z, b.z, c.z]])
+    }
+    pub fn diag(d: Vector3) -> Self {
+        Matrix3::new([[d.x, 0.0, 0.0], [0.0, d.y, 0.0], [0.0, 0.0, d.z]])
+    }
+    /// Inverse by cofactors. The matrix is assumed not to be singular.
+    pub fn inverse(&self) -> Matrix3 {
+        let m = &self.m;
+        let cof = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+        };
+        let adj = [
+            [cof(1, 2, 1, 2), -cof(0, 2, 1, 2), cof(0, 1, 1, 2)],
+            [-cof(1, 2, 0, 2), cof(0, 2, 0, 2), -cof(0, 1, 0, 2)],
+            [cof(1, 2, 0, 1), -cof(0, 2, 0, 1), cof(0, 1, 0, 1)],
+        ];
+        let det = m[0][0] * adj[0][0] + m[0][1] * adj[1][0] + m[0][2] * adj[2][0];
+        Matrix3::new(adj.map(|row| row.map(|v| v / det)))
+    }
+}
+impl Mul<Matrix3> for Matrix3 {
+    type Output = Matrix3;
+
+    fn mul(self, other: Matrix3) -> Matrix3 {
+        let mut m = [[0.0; 3]; 3];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = (0..3).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Matrix3::new(m)
+    }
+}
+// Matrix-vector product
+impl Mul<Vector3> for Matrix3 {
+    type Output = Vector3;
+
+    fn mul(self, v: Vector3) -> Vector3 {
+        let row = |r: [f64; 3]| r[0] * v.x + r[1] * v.y + r[2] * v.z;
+        Vector3::new(row(self.m[0]), row(self.m[1]), row(self.m[2]))
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Col {
     pub r: u8,
@@ -124,59 +227,296 @@ pub struct Col {
 pub struct Camera {
     pub pos: Vector3,
     pub dir: Vector3,
-    // Horizontal fov IN RADIANS
+    // Vertical fov IN RADIANS
     pub fov: f64,
+    /// Position and direction at the end of the motion, if the camera moves
+    pub motion: Option<(Vector3, Vector3)>,
+}
+impl Camera {
+    /// Ray through the continuous image position (x, y), on an image of the given size, at the
+    /// given time. Returns (origin, direction).
+    pub fn ray(
+        &self,
+        x: f64,
+        y: f64,
+        width: usize,
+        height: usize,
+        time: f64,
+    ) -> (Vector3, Vector3) {
+        let (pos, dir, right, up) = self.frame(time);
+        let scale = (self.fov / 2.0).tan();
+        // x and y offsets of the camera direction
+        let xoff = (2.0 * x / (width as f64) - 1.0) * scale * (width as f64) / (height as f64);
+        let yoff = -(2.0 * y / (height as f64) - 1.0) * scale;
+        (pos, (dir + xoff * right + yoff * up).normalize())
+    }
+
+    /// Where the camera is at the given time, and its forward, right and up directions.
+    fn frame(&self, time: f64) -> (Vector3, Vector3, Vector3, Vector3) {
+        let (pos, dir) = match self.motion {
+            Some((pos, dir)) => (
+                (1.0 - time) * self.pos + time * pos,
+                ((1.0 - time) * self.dir + time * dir).normalize(),
+            ),
+            None => (self.pos, self.dir),
+        };
+        let right = dir.cross(Vector3::new(0.0, 1.0, 0.0)).normalize();
+        let up = right.cross(dir);
+        (pos, dir, right, up)
+    }
+
+    /// Where the camera is at the given time
+    pub fn position(&self, time: f64) -> Vector3 {
+        self.frame(time).0
+    }
+
+    /// Which way the camera looks at the given time
+    pub fn forward(&self, time: f64) -> Vector3 {
+        self.frame(time).1
+    }
+
+    /// The continuous image position the point p shows up at, at the given time. None if it's
+    /// behind the camera or outside of the image. The inverse of ray.
+    pub fn project(
+        &self,
+        p: Vector3,
+        width: usize,
+        height: usize,
+        time: f64,
+    ) -> Option<(f64, f64)> {
+        let (pos, dir, right, up) = self.frame(time);
+        let d = p - pos;
+        let z = d * dir;
+        if z <= 0.0 {
+            return None;
+        }
+        let scale = (self.fov / 2.0).tan();
+        let aspect = width as f64 / height as f64;
+        let x = (1.0 + (d * right) / (z * scale * aspect)) * 0.5 * width as f64;
+        let y = (1.0 - (d * up) / (z * scale)) * 0.5 * height as f64;
+        if (0.0..width as f64).contains(&x) && (0.0..height as f64).contains(&y) {
+            Some((x, y))
+        } else {
+            None
+        }
+    }
+
+    /// Importance the camera gives a ray leaving it in direction dir, for light traced towards
+    /// it, and the solid angle pdf of ray picking dir. Both are relative to the whole image:
+    /// rays through the image plane at distance 1 (of area A) are spread evenly over it, so the
+    /// pdf is 1 / (A cos^3), and the importance 1 / (A cos^4).
+    pub fn importance(&self, dir: Vector3, width: usize, height: usize, time: f64) -> (f64, f64) {
+        let (_, forward, right, up) = self.frame(time);
+        let cos = dir * forward;
+        let scale = (self.fov / 2.0).tan();
+        let aspect = width as f64 / height as f64;
+        // Where dir goes through the image plane, which has to be within the image
+        if cos <= 0.0
+            || (dir * right).abs() > cos * scale * aspect
+            || (dir * up).abs() > cos * scale
+        {
+            return (0.0, 0.0);
+        }
+        let area = 4.0 * scale * scale * aspect;
+        let pdf = 1.0 / (area * cos * cos * cos);
+        (pdf / cos, pdf)
+    }
 }
 
 pub struct Mesh {
-    pos: Vector3,
-    scale: f64,
+    /// Where the mesh is placed, and how much it's scaled up, at the start and the end of its
+    /// motion
+    keys: [(Vector3, f64); 2],
+    /// In object space
     tris: Vec<Triangle>,
+    bvh: Bvh,
 }
 impl Mesh {
     pub fn new(pos: Vector3, scale: f64, tris: Vec<Triangle>) -> Mesh {
-        Mesh { pos, scale, tris }
+        let boxes: Vec<Aabb> = tris.iter().map(|t| t.bounds()).collect();
+        Mesh {
+            keys: [(pos, scale); 2],
+            tris,
+            bvh: Bvh::new(&boxes),
+        }
     }
     pub fn from(path: String, pos: Vector3, scale: f64) -> Mesh {
         Mesh::new(pos, scale, load_obj_file(path))
     }
+    /// The same mesh, moving to pos and scaling to scale by the end of the motion.
+    pub fn with_motion(self, pos: Vector3, scale: f64) -> Mesh {
+        Mesh {
+            keys: [self.keys[0], (pos, scale)],
+            ..self
+        }
+    }
+}
+impl Mesh {
+    /// Placement of the mesh at the given time
+    fn at(&self, time: f64) -> (Vector3, f64) {
+        let [(p0, s0), (p1, s1)] = self.keys;
+        ((1.0 - time) * p0 + time * p1, (1.0 - time) * s0 + time * s1)
+    }
+    /// The triangles of the mesh, placed in the world at the start of the motion.
+    fn world_tris(&self) -> impl Iterator<Item = Triangle> + '_ {
+        let (pos, scale) = self.at(0.0);
+        self.tris
+            .iter()
+            .map(move |t| Triangle::new(scale * t.a + pos, scale * t.b + pos, scale * t.c + pos))
+    }
 }
 impl Geometry for Mesh {
-    /// Ray-mesh intersection. Simply executes intersection for all triangles in mesh.
-    fn intersect(&self, origin: Vector3, dir: Vector3) -> Option<(f64, Vector3)> {
-        // Find best intersection
-        let mut best = None;
-        let mut best_depth = f64::MAX;
-        for t in &self
+    /// Ray-mesh intersection. The ray is moved into object space, where the BVH of the triangles
+    /// is. The primitive index of a hit is the index of the triangle.
+    fn intersect(&self, origin: Vector3, dir: Vector3, time: f64) -> Option<Intersection> {
+        let (pos, scale) = self.at(time);
+        // Distances along the ray shrink by the scale too, as dir stays the same
+        let local = (1.0 / scale) * (origin - pos);
+        let mut best: Option<Intersection> = None;
+        self.bvh.traverse(local, dir, f64::MAX, |i| {
+            let res = self.tris[i].intersect(local, dir, time)?;
+            if best.as_ref().is_none_or(|b| res.dist < b.dist) {
+                best = Some(Intersection { prim: i, ..res });
+            }
+            Some(res.dist)
+        });
+        best.map(|b| Intersection {
+            dist: scale * b.dist,
+            ..b
+        })
+    }
+    fn bounds(&self) -> Aabb {
+        // Every point moves in a straight line, so it's enough to look at the ends of the motion
+        let object = self
             .tris
             .iter()
-            .map(|t| {
-                Triangle::new(
-                    self.scale * t.a + self.pos,
-                    self.scale * t.b + self.pos,
-                    self.scale * t.c + self.pos,
-                )
-            })
-            .collect::<Vec<Triangle>>()
-        {
-            let res = t.intersect(origin, dir);
-            match res {
-                Some((d, _)) if d < best_depth => {
-                    best = res;
-                    best_depth = d;
-                }
-                _ => (),
-            }
-        }
-        best
+            .fold(Aabb::empty(), |b, t| b.union(&t.bounds()));
+        let at = |(pos, scale): (Vector3, f64)| Aabb {
+            min: scale * object.min + pos,
+            max: scale * object.max + pos,
+        };
+        at(self.keys[0]).union(&at(self.keys[1]))
+    }
+    fn emitters(&self) -> Vec<AreaShape> {
+        self.world_tris().flat_map(|t| t.emitters()).collect()
+    }
+    fn moves(&self) -> bool {
+        self.keys[0] != self.keys[1]
+    }
+    /// Only the placement changes, so the BVH of the triangles stays good.
+    fn place(&mut self, start: (Vector3, f64), end: (Vector3, f64)) {
+        self.keys = [start, end];
     }
 }
 
-pub trait Geometry {
+/// Where a ray hit a piece of geometry.
+#[derive(Debug, Clone, Copy)]
+pub struct Intersection {
+    /// Depth of the intersection along the ray
+    pub dist: f64,
+    /// Normal at the point of intersection
+    pub normal: Vector3,
+    /// Which primitive of the geometry was hit, e.g. the triangle index of a mesh.
+    pub prim: usize,
+    /// On triangles, the weights of the three vertices at the hit
+    pub barycentric: Option<Vector3>,
+}
+
+pub trait Geometry: Send + Sync {
     /// add code here
-    /// Defines the intersection behaviour for a ray.
-    /// Returns: option, (intersection depth, normal at intersection)
-    fn intersect(&self, origin: Vector3, dir: Vector3) -> Option<(f64, Vector3)>;
+    /// Defines the intersection behaviour for a ray, at the given time within the motion (0 at
+    /// the start, 1 at the end).
+    fn intersect(&self, origin: Vector3, dir: Vector3, time: f64) -> Option<Intersection>;
+    /// World space bounding box, over the whole motion
+    fn bounds(&self) -> Aabb;
+    /// The surfaces of the geometry as light shapes, one per primitive, in primitive order.
+    /// Used to turn emissive geometry into lights.
+    fn emitters(&self) -> Vec<AreaShape>;
+    /// Moving geometry can't be sampled as a light, as its emitters are where it starts out.
+    fn moves(&self) -> bool {
+        false
+    }
+    /// Places the geometry at start (position, scale) when the motion starts, and at end when
+    /// it ends, for animations. Geometry that can't be moved around stays where it is.
+    fn place(&mut self, _start: (Vector3, f64), _end: (Vector3, f64)) {}
+    /// Where on the surface the hit at pos, with the given normal and vertex weights, is, from 0
+    /// to 1. Triangles have nothing better than their barycentric coordinates.
+    fn uv(&self, _pos: Vector3, _normal: Vector3, barycentric: Option<Vector3>) -> [f64; 2] {
+        barycentric.map_or([0.0, 0.0], |b| [b.y, b.z])
+    }
+}
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Vector3,
+    pub max: Vector3,
+}
+impl Aabb {
+    /// A box containing nothing. Union with anything gives the other thing.
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vector3::new(f64::MAX, f64::MAX, f64::MAX),
+            max: Vector3::new(f64::MIN, f64::MIN, f64::MIN),
+        }
+    }
+    pub fn point(p: Vector3) -> Aabb {
+        Aabb { min: p, max: p }
+    }
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vector3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Vector3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+    pub fn center(&self) -> Vector3 {
+        0.5 * (self.min + self.max)
+    }
+    pub fn diagonal(&self) -> Vector3 {
+        self.max - self.min
+    }
+    pub fn contains(&self, p: Vector3) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x)
+            && (self.min.y..=self.max.y).contains(&p.y)
+            && (self.min.z..=self.max.z).contains(&p.z)
+    }
+    /// Center and radius of a sphere around the box.
+    pub fn bounding_sphere(&self) -> (Vector3, f64) {
+        (self.center(), 0.5 * self.diagonal().norm())
+    }
+    /// Slab test. Returns the distances along the ray where it enters and leaves the box, which
+    /// can be negative if the box is (partly) behind the origin.
+    pub fn intersect(&self, origin: Vector3, dir: Vector3) -> Option<(f64, f64)> {
+        let (mut t0, mut t1) = (f64::MIN, f64::MAX);
+        for (o, d, lo, hi) in [
+            (origin.x, dir.x, self.min.x, self.max.x),
+            (origin.y, dir.y, self.min.y, self.max.y),
+            (origin.z, dir.z, self.min.z, self.max.z),
+        ] {
+            if d == 0.0 {
+                if o < lo || o > hi {
+                    return None;
+                }
+                continue;
+            }
+            let (a, b) = ((lo - o) / d, (hi - o) / d);
+            t0 = t0.max(a.min(b));
+            t1 = t1.min(a.max(b));
+        }
+        if t0 <= t1 && t1 > 0.0 {
+            Some((t0, t1))
+        } else {
+            None
+        }
+    }
 }
 
 /*
@@ -207,15 +547,119 @@ impl Geometry for Plane {
     }
 }*/
 
+/// Axis aligned box
+#[derive(Debug)]
+pub struct Cuboid {
+    bounds: Aabb,
+}
+
+impl Cuboid {
+    pub fn new(min: Vector3, max: Vector3) -> Self {
+        Cuboid {
+            bounds: Aabb { min, max },
+        }
+    }
+}
+
+impl Geometry for Cuboid {
+    fn intersect(&self, origin: Vector3, dir: Vector3, _time: f64) -> Option<Intersection> {
+        let (t0, t1) = self.bounds.intersect(origin, dir)?;
+        // From inside, the ray hits the far side
+        let dist = if t0 > 0.0 { t0 } else { t1 };
+        // The face that was hit is the one the point is relatively furthest out towards
+        let p = origin + dist * dir - self.bounds.center();
+        let half = 0.5 * self.bounds.diagonal();
+        let (x, y, z) = (
+            (p.x / half.x).abs(),
+            (p.y / half.y).abs(),
+            (p.z / half.z).abs(),
+        );
+        let (axis, normal) = if x >= y && x >= z {
+            (0, Vector3::new(p.x.signum(), 0.0, 0.0))
+        } else if y >= z {
+            (1, Vector3::new(0.0, p.y.signum(), 0.0))
+        } else {
+            (2, Vector3::new(0.0, 0.0, p.z.signum()))
+        };
+        let positive = normal.x + normal.y + normal.z > 0.0;
+        Some(Intersection {
+            dist,
+            normal,
+            // Same order as the emitters
+            prim: 2 * axis + positive as usize,
+            barycentric: None,
+        })
+    }
+    /// Across the face that was hit
+    fn uv(&self, pos: Vector3, normal: Vector3, _barycentric: Option<Vector3>) -> [f64; 2] {
+        let p = pos - self.bounds.center();
+        let half = 0.5 * self.bounds.diagonal();
+        let axis = if normal.x != 0.0 {
+            0
+        } else if normal.y != 0.0 {
+            1
+        } else {
+            2
+        };
+        // The other two axes
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let (p, half) = ([p.x, p.y, p.z], [half.x, half.y, half.z]);
+        [0.5 + 0.5 * p[a] / half[a], 0.5 + 0.5 * p[b] / half[b]]
+    }
+    fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+    /// The six faces, facing out: -x, +x, -y, +y, -z, +z
+    fn emitters(&self) -> Vec<AreaShape> {
+        let (min, d) = (self.bounds.min, self.bounds.diagonal());
+        let (dx, dy, dz) = (
+            Vector3::new(d.x, 0.0, 0.0),
+            Vector3::new(0.0, d.y, 0.0),
+            Vector3::new(0.0, 0.0, d.z),
+        );
+        let rect = |corner, u, v| AreaShape::Rect { corner, u, v };
+        vec![
+            rect(min, dz, dy),
+            rect(min + dx, dy, dz),
+            rect(min, dx, dz),
+            rect(min + dy, dz, dx),
+            rect(min, dy, dx),
+            rect(min + dz, dx, dy),
+        ]
+    }
+}
+
 #[derive(Debug)]
 pub struct Sphere {
     center: Vector3,
     radius: f64,
+    /// Where the center is, and the radius, at the end of the motion
+    end: (Vector3, f64),
 }
 
 impl Sphere {
     pub fn new(center: Vector3, radius: f64) -> Self {
-        Sphere { center, radius }
+        Sphere {
+            center,
+            radius,
+            end: (center, radius),
+        }
+    }
+    /// A sphere moving in a straight line from start to end.
+    pub fn moving(start: Vector3, end: Vector3, radius: f64) -> Self {
+        Sphere {
+            center: start,
+            radius,
+            end: (end, radius),
+        }
+    }
+    /// Center and radius at the given time
+    fn at(&self, time: f64) -> (Vector3, f64) {
+        let (end, end_radius) = self.end;
+        (
+            (1.0 - time) * self.center + time * end,
+            (1.0 - time) * self.radius + time * end_radius,
+        )
     }
 }
 impl Geometry for Sphere {
@@ -223,13 +667,14 @@ impl Geometry for Sphere {
     /// References a depth value, used for depth culling.
     /// Assumes dir is normalized
     /// returns [intersection found?, intersection depth, normal at point of intersection.]
-    fn intersect(&self, origin: Vector3, dir: Vector3) -> Option<(f64, Vector3)> {
-        let l = self.center - origin; // origin to sphere center
+    fn intersect(&self, origin: Vector3, dir: Vector3, time: f64) -> Option<Intersection> {
+        let (center, radius) = self.at(time);
+        let l = center - origin; // origin to sphere center
         let tca = l * dir; // dot of ray dir, and that of origin to circle center
                            //println!("{}", tca);
         let d2 = l * l - tca * tca; // square of distance from center of sphere to closest
                                     // approach
-        let r2 = self.radius * self.radius; // radius squared
+        let r2 = radius * radius; // radius squared
         if d2 > r2 {
             //println!("{}, {}, {}", d2 - r2, d2, r2);
             // distance grater than radius, no intersection
@@ -239,19 +684,57 @@ impl Geometry for Sphere {
         let z = tca - thc; // Difference between radius and closest ray approach
         let z1 = tca + thc; // The new depth value
         if z > 0.0 {
-            let normal = (origin + (z * dir) - self.center).normalize();
-            return Some((z, normal));
+            let normal = (origin + (z * dir) - center).normalize();
+            return Some(Intersection {
+                dist: z,
+                normal,
+                prim: 0,
+                barycentric: None,
+            });
         }
         if z1 > 0.0 {
-            let normal = (origin + (z1 * dir) - self.center).normalize();
-            return Some((z1, normal));
+            let normal = (origin + (z1 * dir) - center).normalize();
+            return Some(Intersection {
+                dist: z1,
+                normal,
+                prim: 0,
+                barycentric: None,
+            });
         }
         /*if *z0 < 0.0 {
             // depth negative, cull (lies behind camera)
             return None;
         };*/
         // Our vectors are in world space
-        return None;
+        None
+    }
+    fn bounds(&self) -> Aabb {
+        let at = |(c, r): (Vector3, f64)| Aabb {
+            min: c - Vector3::new(r, r, r),
+            max: c + Vector3::new(r, r, r),
+        };
+        at((self.center, self.radius)).union(&at(self.end))
+    }
+    fn emitters(&self) -> Vec<AreaShape> {
+        vec![AreaShape::Sphere {
+            center: self.center,
+            radius: self.radius,
+        }]
+    }
+    fn moves(&self) -> bool {
+        self.end != (self.center, self.radius)
+    }
+    /// The scale is the radius
+    fn place(&mut self, start: (Vector3, f64), end: (Vector3, f64)) {
+        (self.center, self.radius) = start;
+        self.end = end;
+    }
+    /// Longitude and latitude, from the normal
+    fn uv(&self, _pos: Vector3, normal: Vector3, _barycentric: Option<Vector3>) -> [f64; 2] {
+        [
+            0.5 + normal.z.atan2(normal.x) / (2.0 * f64::consts::PI),
+            normal.y.clamp(-1.0, 1.0).acos() / f64::consts::PI,
+        ]
     }
 }
 
@@ -273,13 +756,12 @@ impl Geometry for Triangle {
     /// References a depth value, used for depth culling.
     /// Assumes dir is normalized
     /// returns [intersection found?, intersection depth, normal at point of intersection.]
-    fn intersect(&self, origin: Vector3, dir: Vector3) -> Option<(f64, Vector3)> {
+    fn intersect(&self, origin: Vector3, dir: Vector3, _time: f64) -> Option<Intersection> {
         let ab = self.b - self.a;
         let ac = self.c - self.a;
+        // No backface culling: paths inside a mesh, like random walks, have to find their way
+        // out through the back of the triangles
         let n: Vector3 = ab.cross(ac).normalize();
-        if -1.0 * n * dir < 0.0 {
-            return None;
-        }
 
         let d_cross_ac = dir.cross(ac);
         let det = ab * d_cross_ac;
@@ -291,7 +773,7 @@ impl Geometry for Triangle {
         let inv_det = 1.0 / det;
         let s = origin - self.a;
         let u = inv_det * s * d_cross_ac;
-        if u < 0.0 || u > 1.0 {
+        if !(0.0..=1.0).contains(&u) {
             return None;
         }
 
@@ -304,70 +786,27 @@ impl Geometry for Triangle {
         let t = inv_det * (ac * s_cross_ab);
 
         if t > f64::EPSILON {
-            Some((t, n))
+            Some(Intersection {
+                dist: t,
+                normal: n,
+                prim: 0,
+                barycentric: Some(Vector3::new(1.0 - u - v, u, v)),
+            })
         } else {
             None
         }
     }
-}
-
-#[derive(Debug, Clone)]
-pub struct Material {
-    pub base_col: Vector3,
-    pub emissive: Vector3,
-    pub roughness: f64,
-    pub metallic: f64,
-    // Normals will be more convoluted
-}
-impl Material {
-    fn new(base_col: Vector3, emissive: Vector3, roughness: f64, metallic: f64) -> Material {
-        Material {
-            base_col,
-            emissive,
-            roughness,
-            metallic,
-        }
+    fn bounds(&self) -> Aabb {
+        Aabb::point(self.a)
+            .union(&Aabb::point(self.b))
+            .union(&Aabb::point(self.c))
     }
-
-    pub fn gray_mat() -> Material {
-        Material::new(
-            Vector3::new(0.5, 0.5, 0.5),
-            Vector3::new(0.0, 0.0, 0.0),
-            1.0,
-            0.0,
-        )
-    }
-    pub fn semirough() -> Material {
-        Material::new(
-            Vector3::new(0.9, 0.3, 0.3),
-            Vector3::new(0.0, 0.0, 0.0),
-            0.5,
-            0.0,
-        )
-    }
-    pub fn bluish() -> Material {
-        Material::new(
-            Vector3::new(0.2, 0.2, 0.5),
-            Vector3::new(0.0, 0.0, 0.0),
-            0.0,
-            0.0,
-        )
-    }
-    pub fn white_light() -> Material {
-        Material::new(
-            Vector3::new(0.0, 0.0, 0.0),
-            Vector3::new(10.0, 10.0, 10.0),
-            0.0,
-            0.0,
-        )
-    }
-    pub fn yellowish_light() -> Material {
-        Material::new(
-            Vector3::new(0.0, 0.0, 0.0),
-            Vector3::new(12.0, 12.0, 8.0),
-            0.0,
-            0.0,
-        )
+    fn emitters(&self) -> Vec<AreaShape> {
+        vec![AreaShape::Triangle {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+        }]
     }
 }
 
@@ -376,4 +815,6 @@ impl Material {
 pub struct Renderable {
     pub material: Material,
     pub geometry: Box<dyn Geometry>,
+    /// What fills the inside of the object, for closed geometry
+    pub medium: Option<Medium>,
 }